@@ -69,36 +69,100 @@ void applyConfig() {
     client.subscribe(GUARD_COMMAND_TOPIC);
 }
 
+String getJsonValue(String &json, const char *key) {
+    /* Minimal lookup of number or string value by key, returns empty string if key is missing */
+    i32 from = json.indexOf(String("\"") + key + "\"");
+    if (from < 0)
+        return String();
+
+    from += strlen(key) + 2;
+    while (from < json.length() && json[from] == ' ')
+        from++;
+    if (from >= json.length() || json[from] != ':')
+        return String();
+
+    from++;
+    while (from < json.length() && json[from] == ' ')
+        from++;
+    if (from >= json.length())
+        return String();
+
+    if (json[from] == '"') {
+        i32 to = json.indexOf('"', from + 1);
+        return to < 0 ? String() : json.substring(from + 1, to);
+    }
+
+    i32 to = from;
+    while (to < json.length() && isDigit(json[to]))
+        to++;
+    return json.substring(from, to);
+}
+
+void rejectConfig(const char *reason) {
+    Serial.printf("ERROR: Config rejected: %s\n", reason);
+    Serial.flush();
+    client.publish(GUARD_ERROR_LOG_TOPIC, String("Config rejected: ") + reason);
+}
+
 void parseConfig(String &payload) {
     /* 
     Assume that message format is:
-    "miners_count miner0_name miner0_pinset miner1_name miner1_pinset ... "
+    {"version": 1, "miners": [{"id": "Miner00", "pinset": 0}, ...], "timing": {"hard_stop_s": 5, "start_timeout_s": 5}}
     */
 
-    u32 from = 0;
-    u32 to = 0;
-    u32 length = payload.length();
+    String ids[MAX_MINERS];
+    u8 pinsets[MAX_MINERS];
+    i32 count = 0;
 
-    u8 pinset;
-    String name;
+    String version = getJsonValue(payload, "version");
+    if (version.length() == 0 || version.toInt() != PROTOCOL_VERSION) {
+        rejectConfig("unsupported version");
+        return;
+    }
 
-    while (payload[to] != ' ')
-      to++;
-    minersCount = payload.substring(from, to).toInt();
-    
-    from = to = to + 1;
-    
-    for (i32 i = 0; i < minersCount; ++i) {
-        while (payload[to] != ' ') 
-            to++;
-        name = payload.substring(from, to);
+    i32 from = payload.indexOf("\"miners\"");
+    i32 end = from < 0 ? -1 : payload.indexOf(']', from);
+    if (end < 0) {
+        rejectConfig("missing miners");
+        return;
+    }
+
+    while (true) {
+        i32 minerFrom = payload.indexOf('{', from);
+        if (minerFrom < 0 || minerFrom > end)
+            break;
+
+        i32 minerTo = payload.indexOf('}', minerFrom);
+        if (minerTo < 0 || minerTo > end || count >= MAX_MINERS) {
+            rejectConfig("improper miners");
+            return;
+        }
+
+        String miner = payload.substring(minerFrom, minerTo + 1);
+        String pinset = getJsonValue(miner, "pinset");
+        ids[count] = getJsonValue(miner, "id");
+        if (ids[count].length() == 0 || pinset.length() == 0 || pinset.toInt() >= MAX_MINERS) {
+            rejectConfig("improper miner");
+            return;
+        }
+        pinsets[count] = pinset.toInt();
+
+        from = minerTo + 1;
+        count++;
+    }
 
-        from = to = to + 1;
-        while (to < length && payload[to] != ' ')
-            to++;
-        pinset = payload.substring(from, to).toInt();
+    /* Timing is optional, defaults are kept for missing values */
+    String hardStop = getJsonValue(payload, "hard_stop_s");
+    if (hardStop.length() > 0)
+        Miner::hardStopContactorMiliseconds = hardStop.toInt() * 1000;
 
-        miners[i].setConfiguration(pinset, name);
+    String startTimeout = getJsonValue(payload, "start_timeout_s");
+    if (startTimeout.length() > 0)
+        Miner::startingMiliseconds = startTimeout.toInt() * 1000;
+
+    minersCount = count;
+    for (i32 i = 0; i < minersCount; ++i) {
+        miners[i].setConfiguration(pinsets[i], ids[i]);
     }
 
     isConfigSet = true;
@@ -208,7 +272,7 @@ void runGuardRestart() {
 }
 
 void runGuardAnnounce() {
-    char buffer[256];
+    char buffer[320];
     char miner[128];
     String minersBuffer;
 
@@ -223,8 +287,8 @@ void runGuardAnnounce() {
     minersBuffer += "]";
 
     sprintf(buffer, 
-        "{\"id\": \"%s\", \"type\": \"%s\", \"miners\": %s}", 
-        DEV_ID, DEV_TYPE, minersBuffer.c_str()
+        "{\"id\": \"%s\", \"type\": \"%s\", \"version\": %d, \"miners\": %s}", 
+        DEV_ID, DEV_TYPE, PROTOCOL_VERSION, minersBuffer.c_str()
     );
 
     Serial.println("Guard publishs announce message!");
@@ -273,8 +337,8 @@ void setup() {
     /* Change message handler */
     client.onMessage(controlMessageReceiver);
 
-    /* Publish guard is configured */
-    client.publish(GUARD_CONFIGURED_TOPIC, DEV_ID);
+    /* Publish guard is configured, acknowledging version of applied config */
+    client.publish(GUARD_CONFIGURED_TOPIC, String("{\"version\": ") + PROTOCOL_VERSION + "}");
 
     printConfigSummary();
     timestamp = pingTimestamp = timer.getTimestamp();
@@ -13,6 +13,9 @@
 
 static const u8 MAX_MINERS = 4;
 
+/* Version of config message guard understands */
+static const u32 PROTOCOL_VERSION = 1;
+
 static const u8 PINOUTS_SET [MAX_MINERS][3] = {
     {25, 26, 36},
     {16, 17, 39},
@@ -23,6 +26,7 @@ static const u8 PINOUTS_SET [MAX_MINERS][3] = {
 static const u64 POWER_ON_CONTACTOR_MILISECONDS = 1500;
 static const u64 POWER_OFF_CONTACTOR_MILISECONDS = 1500;
 static const u64 RESET_CONTACTOR_MILISECONDS = 1500;
+/* Defaults, server overrides them by timing of config message */
 static const u64 HARD_STOP_CONTACTOR_MILISECONDS = 5500;
 static const u64 STARTING_MILISECONDS = 5000;
 static const u64 STOPPING_MILISECONDS = 120000;
@@ -43,6 +43,8 @@ Command getCommandFromName(String& state) {
 
 TimerWrapper& Miner::timer = getTimerInstance();
 MQTTClient *Miner::client = 0;
+u64 Miner::hardStopContactorMiliseconds = HARD_STOP_CONTACTOR_MILISECONDS;
+u64 Miner::startingMiliseconds = STARTING_MILISECONDS;
 
 void Miner::setConfiguration(u8 pinSet_, String & id_) {
     pinSet = pinSet_;
@@ -165,7 +167,7 @@ void Miner::watchCommandExecution() {
                     timestamp = timer.getTimestamp();
                     ++commandStage;
                 }
-            } else if (timer.isTimeElapsed(timestamp, STARTING_MILISECONDS)) {
+            } else if (timer.isTimeElapsed(timestamp, startingMiliseconds)) {
                 if (digitalRead(pinLed) == HIGH) {
                     /* Send command execution DONE */
                     client->publish(commandTopic, "command=DONE, state=Running");
@@ -208,7 +210,7 @@ void Miner::watchCommandExecution() {
 
         case Command::HardStop: {
             if (commandStage == 0) {
-                if (timer.isTimeElapsed(timestamp, HARD_STOP_CONTACTOR_MILISECONDS)) {
+                if (timer.isTimeElapsed(timestamp, hardStopContactorMiliseconds)) {
                     digitalWrite(pinPower, HIGH);
                     timestamp = timer.getTimestamp();
                     ++commandStage;
@@ -254,7 +256,7 @@ void Miner::watchCommandExecution() {
 
         case Command::HardReset: {
             if (commandStage == 0) {
-                if (timer.isTimeElapsed(timestamp, HARD_STOP_CONTACTOR_MILISECONDS)) {
+                if (timer.isTimeElapsed(timestamp, hardStopContactorMiliseconds)) {
                     digitalWrite(pinPower, HIGH);
                     timestamp = timer.getTimestamp();
                     ++commandStage;
@@ -280,7 +282,7 @@ void Miner::watchCommandExecution() {
                     ++commandStage;
                 }
             } else if (commandStage == 3) {
-                if (timer.isTimeElapsed(timestamp, STARTING_MILISECONDS)) {
+                if (timer.isTimeElapsed(timestamp, startingMiliseconds)) {
                     if (digitalRead(pinLed) == HIGH) {
                         /* Send command execution DONE */
                         client->publish(commandTopic, "command=FAILED, state=Running");
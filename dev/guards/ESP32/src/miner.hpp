@@ -50,6 +50,9 @@ public:
 
     static MQTTClient * client;
 
+    static u64 hardStopContactorMiliseconds;
+    static u64 startingMiliseconds;
+
     u8 pinSet;
     u8 pinPower;
     u8 pinReset;
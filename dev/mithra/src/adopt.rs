@@ -0,0 +1,431 @@
+use rumqttc::{Client, Event, MqttOptions, Outgoing, Packet, QoS};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader, yaml::Hash};
+
+use crate::system::{
+    MqttConfig,
+    adapters::{mapping::DeviceMapping, shelly},
+    discovery::{self, AnnouncedDevice},
+    structs::{Miner, Plug, PlugType},
+};
+
+/* Announced devices which are not in devices config are adopted by operator answering questions,
+every miner is checked as it is entered and new config is written only when it passes the same
+checks as config loaded by mithra */
+pub fn adopt(mqtt_config: &MqttConfig, config_file: &str, listen: Duration) {
+    let content = fs::read_to_string(config_file).unwrap_or_else(|error| {
+        eprintln!("Cannot read yaml configuration file: {}", error);
+        std::process::exit(1);
+    });
+
+    let doc = match YamlLoader::load_from_str(&content) {
+        Ok(mut docs) if !docs.is_empty() => docs.remove(0),
+        _ => {
+            eprintln!("Parsing yaml error!");
+            std::process::exit(1);
+        },
+    };
+
+    let (switchboard, guards, mut known_miners, mut known_plugs, mappings, _, guard_types) = crate::parse_yaml(&doc).unwrap_or_else(|| {
+        eprintln!("Not valid yaml configuration!");
+        std::process::exit(1);
+    });
+    let phases = switchboard.board_type.get_phase_count();
+
+    let devices: Vec<AnnouncedDevice> = listen_announces(mqtt_config, listen).into_iter()
+        .filter(|device| {
+            let id = device.get_id();
+            id != switchboard.id && !guards.contains_key(id) && !known_plugs.contains_key(id)
+        })
+        .collect();
+
+    if devices.is_empty() {
+        println!("No new devices announced.");
+        return;
+    }
+
+    /* Announced Shelly plugs by their models */
+    let announced_plugs: HashMap<String, String> = devices.iter()
+        .filter_map(|device| match device {
+            AnnouncedDevice::Shelly{id, model} if PlugType::from_str(model).is_ok() => Some((id.clone(), model.clone())),
+            _ => None,
+        })
+        .collect();
+
+    let mut new_guards = vec![];
+    let mut new_miners = vec![];
+
+    for device in devices.iter() {
+        if let AnnouncedDevice::Guard{id, board_type, miners, ..} = device {
+            println!("\nGuard {} of type {} announces miners {:?}", id, board_type, miners);
+            if !guard_types.contains_key(board_type) {
+                println!("Guard type {} is not described in devices config, guard is skipped.", board_type);
+                continue;
+            }
+            if !ask_yes(&format!("Adopt guard {}", id)) { continue; }
+
+            let mut guard_miners = vec![];
+            for (announced_id, pinset) in miners {
+                println!("Miner on pinset {}:", pinset);
+                loop {
+                    let miner_id = ask_miner_id(&format!("Miner id [{}]", announced_id), Some(announced_id), &known_miners);
+                    let phase = ask_number::<i64>(&format!("Phase 0 - {}", phases - 1), |&phase| phase >= 0 && (phase as usize) < phases);
+                    let consumption = ask_number::<i64>("Estimated consumption W", |&consumption| consumption > 0);
+                    let plug = ask("Plug id, empty for miner without plug");
+
+                    let mut fields = vec![
+                        ("id", Yaml::String(miner_id.clone())),
+                        ("pinset", Yaml::Integer(*pinset as i64)),
+                    ];
+                    if !plug.is_empty() {
+                        if let Some(model) = announced_plugs.get(&plug) {
+                            if model != "SHPLG-S" {
+                                fields.push(("plug_type", Yaml::String(model.clone())));
+                            }
+                        }
+                        fields.push(("plug", Yaml::String(plug)));
+                    }
+                    fields.push(("phase", Yaml::Integer(phase)));
+                    fields.push(("consumption", Yaml::Integer(consumption)));
+
+                    let miner = yaml_hash(fields);
+                    if add_miner(&miner, Some(id), *pinset, phases, &mappings, &mut known_miners, &mut known_plugs) {
+                        guard_miners.push(miner);
+                        break;
+                    }
+                    println!("{}", MINER_REJECTED);
+                }
+            }
+
+            new_guards.push(yaml_hash(vec![
+                ("id", Yaml::String(id.clone())),
+                ("type", Yaml::String(board_type.clone())),
+                ("miners", Yaml::Array(guard_miners)),
+            ]));
+        }
+    }
+
+    for device in devices.iter() {
+        if let AnnouncedDevice::Shelly{id, model} = device {
+            if known_plugs.contains_key(id) { continue; }
+            if !announced_plugs.contains_key(id) {
+                println!("\nShelly {} of model {} is not a plug, it is skipped.", id, model);
+                continue;
+            }
+
+            println!("\nPlug {} of model {} can drive miner without guard.", id, model);
+            loop {
+                let miner_id = ask_miner_id("Miner id, empty to skip plug", None, &known_miners);
+                if miner_id.is_empty() { break; }
+                let phase = ask_number::<i64>(&format!("Phase 0 - {}", phases - 1), |&phase| phase >= 0 && (phase as usize) < phases);
+                let consumption = ask_number::<i64>("Estimated consumption W", |&consumption| consumption > 0);
+
+                let mut fields = vec![
+                    ("id", Yaml::String(miner_id)),
+                    ("plug", Yaml::String(id.clone())),
+                ];
+                if model != "SHPLG-S" {
+                    fields.push(("plug_type", Yaml::String(model.clone())));
+                }
+                fields.push(("phase", Yaml::Integer(phase)));
+                fields.push(("consumption", Yaml::Integer(consumption)));
+
+                let miner = yaml_hash(fields);
+                if add_miner(&miner, None, 0, phases, &mappings, &mut known_miners, &mut known_plugs) {
+                    new_miners.push(miner);
+                    break;
+                }
+                println!("{}", MINER_REJECTED);
+            }
+        }
+    }
+
+    if new_guards.is_empty() && new_miners.is_empty() {
+        println!("No device adopted, devices config is left unchanged.");
+        return;
+    }
+
+    /* Answers are not lost when whole config is still rejected */
+    let output = to_yaml_string(&add_devices(&doc, new_guards, new_miners));
+    if let Err(error_msg) = validate(&output) {
+        let adopted_file = format!("{}.adopted", config_file);
+        match fs::write(&adopted_file, output) {
+            Ok(_) => eprintln!("{}, devices config is left unchanged and adopted one is saved as {}.", error_msg, adopted_file),
+            Err(error) => eprintln!("{}, devices config is left unchanged, saving adopted one error: {}", error_msg, error),
+        }
+        std::process::exit(1);
+    }
+
+    /* Emitted yaml has no comments, so original file is kept aside */
+    let backup_file = format!("{}.bak", config_file);
+    if let Err(error) = fs::copy(config_file, &backup_file).and_then(|_| fs::write(config_file, output)) {
+        eprintln!("Writing devices config error: {}", error);
+        std::process::exit(1);
+    }
+    println!("Devices config {} is updated, previous one is saved as {}.", config_file, backup_file);
+}
+
+const MINER_REJECTED: &str = "Miner does not fit devices config, shared plug needs miners with guard on the same phase \
+and plug of the same type, try again.";
+
+/* Miner is checked against miners entered so far like by config loading, accepted miner is added to them */
+fn add_miner(
+    miner: &Yaml,
+    guard_id: Option<&str>,
+    pinset: u32,
+    phases: usize,
+    mappings: &HashMap<String, DeviceMapping>,
+    miners: &mut HashMap<String, Miner>,
+    plugs: &mut HashMap<String, Plug>
+) -> bool {
+    let (miner, plug) = match crate::parse_miner(miner, guard_id, pinset, phases, mappings, miners, plugs) {
+        Some((miner, plug)) if guard_id.is_some() || plug.is_some() => (miner, plug),
+        _ => return false,
+    };
+
+    match (plug, &miner.plug_id) {
+        (Some(plug), _) => { plugs.insert(plug.id.clone(), plug); },
+        (None, Some(plug_id)) => plugs.get_mut(plug_id).unwrap().miners.push(miner.id.clone()),
+        (None, None) => {},
+    }
+    miners.insert(miner.id.clone(), miner);
+    true
+}
+
+/* Gen2 devices do not answer announce command, they are found by their retained online status and asked for device info */
+fn listen_announces(mqtt_config: &MqttConfig, listen: Duration) -> Vec<AnnouncedDevice> {
+    let mut mqtt_options = MqttOptions::new("Adopt_loop", mqtt_config.host.clone(), mqtt_config.port);
+    mqtt_options.set_credentials(mqtt_config.user.clone(), mqtt_config.password.clone());
+    mqtt_options.set_keep_alive(5);
+
+    let (mut client, mut connection) = Client::new(mqtt_options, 1024);
+    client.subscribe(discovery::SHELLIES_ANNOUNCE_TOPIC, QoS::ExactlyOnce).unwrap();
+    client.subscribe(discovery::GUARDS_ANNOUNCE_TOPIC, QoS::ExactlyOnce).unwrap();
+    client.subscribe(shelly::get_response_topic(shelly::ANNOUNCE_SRC), QoS::ExactlyOnce).unwrap();
+    client.subscribe(discovery::ONLINE_TOPIC, QoS::ExactlyOnce).unwrap();
+
+    for topic in ["shellies/command", "guards/command"] {
+        if let Err(error_msg) = client.publish(topic, QoS::ExactlyOnce, false, "announce".as_bytes()) {
+            eprintln!("Annouce command error: {}", error_msg);
+            std::process::exit(1);
+        }
+    }
+
+    let mut devices: Vec<AnnouncedDevice> = vec![];
+    let timer = Instant::now();
+    println!("Listening to announces for {} s.", listen.as_secs());
+    for msg in connection.iter() {
+        if timer.elapsed() > listen {
+            client.disconnect().unwrap();
+        }
+
+        match msg {
+            Ok(Event::Incoming(Packet::Publish(data))) => {
+                let payload = std::str::from_utf8(&data.payload).unwrap();
+                if let Some(device_id) = discovery::parse_online(&data.topic, payload) {
+                    if let Err(error_msg) = discovery::request_device_info(&mut client, device_id) {
+                        eprintln!("Shelly {} device info request error: {}", device_id, error_msg);
+                    }
+                    continue;
+                } else if data.topic.ends_with("/online") {
+                    continue;
+                }
+
+                match discovery::parse_announce(&data.topic, payload) {
+                    Ok(device) => {
+                        devices.retain(|announced| announced.get_id() != device.get_id());
+                        devices.push(device);
+                    },
+                    Err(error_msg) => eprintln!("{}", error_msg),
+                }
+            },
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                break;
+            },
+            Ok(_) => (),
+            Err(_) => (),
+        }
+    }
+
+    devices
+}
+
+/* Appends adopted guards and plug-only miners to devices config */
+fn add_devices(doc: &Yaml, guards: Vec<Yaml>, miners: Vec<Yaml>) -> Yaml {
+    let mut conf = doc.as_hash().cloned().unwrap_or_default();
+
+    for (key, entries) in [("guards", guards), ("miners", miners)] {
+        if entries.is_empty() { continue; }
+
+        let key = Yaml::String(String::from(key));
+        match conf.get_mut(&key) {
+            Some(Yaml::Array(array)) => array.extend(entries),
+            _ => { conf.insert(key, Yaml::Array(entries)); },
+        }
+    }
+
+    Yaml::Hash(conf)
+}
+
+fn validate(content: &str) -> Result<(), String> {
+    let docs = YamlLoader::load_from_str(content).map_err(|error| format!("Parsing adopted yaml error: {}", error))?;
+
+    match docs.first().and_then(|doc| crate::parse_yaml(doc)) {
+        Some(_) => Ok(()),
+        None => Err(String::from("Adopted devices do not make valid configuration")),
+    }
+}
+
+fn to_yaml_string(conf: &Yaml) -> String {
+    let mut output = String::new();
+    YamlEmitter::new(&mut output).dump(conf).unwrap();
+    output.push('\n');
+    output
+}
+
+fn yaml_hash(fields: Vec<(&str, Yaml)>) -> Yaml {
+    let mut hash = Hash::new();
+    for (key, value) in fields {
+        hash.insert(Yaml::String(String::from(key)), value);
+    }
+    Yaml::Hash(hash)
+}
+
+/* Adoption is aborted when input is closed */
+fn ask(question: &str) -> String {
+    print!("{}: ", question);
+    io::stdout().flush().unwrap();
+
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(0) | Err(_) => {
+            eprintln!("\nInput closed, devices config is left unchanged.");
+            std::process::exit(1);
+        },
+        Ok(_) => String::from(answer.trim()),
+    }
+}
+
+fn ask_yes(question: &str) -> bool {
+    matches!(ask(&format!("{} [y/N]", question)).to_lowercase().as_str(), "y" | "yes")
+}
+
+fn ask_number<T: FromStr>(question: &str, is_valid: impl Fn(&T) -> bool) -> T {
+    loop {
+        match ask(question).parse::<T>() {
+            Ok(value) if is_valid(&value) => return value,
+            _ => println!("Improper value, try again."),
+        }
+    }
+}
+
+/* Miner ids are unique, empty answer takes default id or skips miner without default */
+fn ask_miner_id(question: &str, default_id: Option<&String>, miners: &HashMap<String, Miner>) -> String {
+    loop {
+        let answer = ask(question);
+        let miner_id = match (answer.is_empty(), default_id) {
+            (true, Some(default_id)) => default_id.clone(),
+            (true, None) => return answer,
+            (false, _) => answer,
+        };
+
+        if !miners.contains_key(&miner_id) {
+            return miner_id;
+        }
+        println!("Miner {} is already defined, try again.", miner_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+switchboard:
+  id: shellyem3-0
+  type: SHEM-3
+guards:
+  - id: Guard00
+    type: ESP32
+    miners:
+      - id: Miner00
+        pinset: 0
+        plug: shellyplug-s-0
+        phase: 0
+        consumption: 200
+";
+
+    #[test]
+    fn adopted_devices_extend_config() {
+        let doc = &YamlLoader::load_from_str(CONFIG).unwrap()[0];
+        let guard = yaml_hash(vec![
+            ("id", Yaml::String(String::from("Guard01"))),
+            ("type", Yaml::String(String::from("ESP32"))),
+            ("miners", Yaml::Array(vec![yaml_hash(vec![
+                ("id", Yaml::String(String::from("Miner01"))),
+                ("pinset", Yaml::Integer(1)),
+                ("phase", Yaml::Integer(2)),
+                ("consumption", Yaml::Integer(300)),
+            ])])),
+        ]);
+        let miner = yaml_hash(vec![
+            ("id", Yaml::String(String::from("Miner02"))),
+            ("plug", Yaml::String(String::from("shellyplug-s-2"))),
+            ("phase", Yaml::Integer(1)),
+            ("consumption", Yaml::Integer(150)),
+        ]);
+
+        let output = to_yaml_string(&add_devices(doc, vec![guard], vec![miner.clone()]));
+        assert_eq!(validate(&output), Ok(()));
+
+        let (_, guards, miners, plugs, _, _, _) = crate::parse_yaml(&YamlLoader::load_from_str(&output).unwrap()[0]).unwrap();
+        assert_eq!(guards.len(), 2);
+        assert_eq!(miners.len(), 3);
+        assert_eq!(plugs.len(), 2);
+        assert!(miners.get("Miner01").unwrap().plug_id.is_none());
+        assert!(miners.get("Miner02").unwrap().guard.is_none());
+
+        /* Miner adopted twice breaks unique ids */
+        let output = to_yaml_string(&add_devices(doc, vec![], vec![miner.clone(), miner]));
+        assert!(validate(&output).is_err());
+    }
+
+    #[test]
+    fn entered_miners_are_checked_against_config() {
+        let doc = &YamlLoader::load_from_str(CONFIG).unwrap()[0];
+        let (_, _, mut miners, mut plugs, mappings, _, _) = crate::parse_yaml(doc).unwrap();
+        let get_miner = |id: &str, plug: &str, plug_type: Option<&str>, phase: i64| {
+            let mut fields = vec![("id", Yaml::String(String::from(id))), ("plug", Yaml::String(String::from(plug)))];
+            if let Some(plug_type) = plug_type {
+                fields.push(("plug_type", Yaml::String(String::from(plug_type))));
+            }
+            fields.push(("phase", Yaml::Integer(phase)));
+            fields.push(("consumption", Yaml::Integer(100)));
+            yaml_hash(fields)
+        };
+
+        /* Shared plug needs guarded miners on the same phase with the same plug type */
+        assert!(!add_miner(&get_miner("Miner01", "shellyplug-s-0", None, 1), Some("Guard01"), 0, 3, &mappings, &mut miners, &mut plugs));
+        assert!(!add_miner(&get_miner("Miner01", "shellyplug-s-0", Some("PlusPlugS"), 0), Some("Guard01"), 0, 3, &mappings, &mut miners, &mut plugs));
+        assert!(!add_miner(&get_miner("Miner01", "shellyplug-s-0", None, 0), None, 0, 3, &mappings, &mut miners, &mut plugs));
+        assert!(add_miner(&get_miner("Miner01", "shellyplug-s-0", None, 0), Some("Guard01"), 0, 3, &mappings, &mut miners, &mut plugs));
+        assert_eq!(plugs.get("shellyplug-s-0").unwrap().miners, vec![String::from("Miner00"), String::from("Miner01")]);
+
+        /* Miner without guard needs its own plug */
+        let plugless = yaml_hash(vec![
+            ("id", Yaml::String(String::from("Miner02"))),
+            ("phase", Yaml::Integer(0)),
+            ("consumption", Yaml::Integer(100)),
+        ]);
+        assert!(!add_miner(&plugless, None, 0, 3, &mappings, &mut miners, &mut plugs));
+        assert!(add_miner(&get_miner("Miner02", "shellyplusplugs-a8", Some("PlusPlugS"), 2), None, 0, 3, &mappings, &mut miners, &mut plugs));
+        assert!(!add_miner(&get_miner("Miner02", "shellyplug-s-9", None, 2), None, 0, 3, &mappings, &mut miners, &mut plugs));
+        assert_eq!(miners.len(), 3);
+    }
+}
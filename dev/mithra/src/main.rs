@@ -1,8 +1,9 @@
 #![feature(deadline_api)]
 #![feature(thread_is_running)]
+#![cfg_attr(test, feature(test))]
 
 use chrono::naive::MIN_DATETIME;
-use clap::{Arg, ArgMatches, App, Error};
+use clap::{Arg, ArgMatches, App, Error, SubCommand};
 use configparser::ini::Ini;
 use hostname_validator;
 use postgres::Config;
@@ -11,14 +12,18 @@ use std::{
     fs::File,
     io::Read,
     path::Path,
-    str::FromStr
+    str::FromStr,
+    time::Duration
 };
 use yaml_rust::{Yaml, YamlLoader};
 
+mod adopt;
 mod system;
 use system::{
     MqttConfig,
     System,
+    adapters::{self, cgminer, mapping::{self, DeviceMapping}, sma},
+    solar::Site,
     structs::*
 
 };
@@ -32,6 +37,24 @@ fn main() {
     let servers_file = params.value_of("servers").unwrap();
     let config_file = params.value_of("config").unwrap();
 
+    /* Adoption needs MQTT server only, it ends after devices config is written */
+    if let Some(adopt_params) = params.subcommand_matches("adopt") {
+        let mut servers_config = Ini::new();
+        servers_config.load(servers_file).unwrap_or_else(|error_msg| {
+            eprintln!("{}", error_msg);
+            std::process::exit(1);
+        });
+
+        let mqtt_config = get_mqtt_config(&servers_config).unwrap_or_else(|error_msg| {
+            eprintln!("{}", error_msg);
+            std::process::exit(1);
+        });
+
+        let listen = adopt_params.value_of("listen").unwrap().parse().unwrap();
+        adopt::adopt(&mqtt_config, config_file, Duration::from_secs(listen));
+        return;
+    }
+
     loop {
         let mut servers_config = Ini::new();
         servers_config.load(servers_file).unwrap_or_else(|error_msg| {
@@ -44,6 +67,21 @@ fn main() {
             std::process::exit(1);
         });
     
+        let balancing_mode = get_balancing_mode(&servers_config).unwrap_or_else(|error_msg| {
+            eprintln!("{}", error_msg);
+            std::process::exit(1);
+        });
+
+        let (final_stretch_days, safety_margin_wh) = get_final_stretch_config(&servers_config).unwrap_or_else(|error_msg| {
+            eprintln!("{}", error_msg);
+            std::process::exit(1);
+        });
+
+        let site = get_site_config(&servers_config).unwrap_or_else(|error_msg| {
+            eprintln!("{}", error_msg);
+            std::process::exit(1);
+        });
+
         let db_config = get_db_config(&servers_config).unwrap_or_else(|error_msg| {
             eprintln!("{}", error_msg);
             std::process::exit(1);
@@ -54,7 +92,7 @@ fn main() {
             std::process::exit(1);
         });
     
-        let (switchboard, guards, miners, plugs) = match load_yaml_config(config_file) {
+        let (switchboard, guards, miners, plugs, device_mappings, inverter, guard_types) = match load_yaml_config(config_file) {
             Ok(devices) => devices,
             Err(error_msg) => {
                 eprintln!("{}", error_msg);
@@ -62,17 +100,29 @@ fn main() {
             }
         };
     
+        for miner in miners.values().filter(|miner| miner.plug_id.is_none()) {
+            eprintln!("Miner {} has no plug, its emergency cut-off is disabled!", miner.id);
+        }
+
         let mut system = System {
             start_year,
             start_month,
             billing_period,
             recovery_ratio,
+            balancing_mode,
+            final_stretch_days,
+            safety_margin_wh,
+            site,
             db_config,
             mqtt_config,
             switchboard,
             guards,
             miners,
             plugs, 
+            device_mappings,
+            inverter,
+            guard_types,
+            discovered_devices: HashMap::new(),
         };
     
         /* Try to connect with database */
@@ -117,6 +167,18 @@ fn get_cli_parameters() -> Result<ArgMatches<'static>, Error> {
             .required(true)
             .validator(validate_file)
         )
+        .subcommand(SubCommand::with_name("adopt")
+            .about("Adds announced devices missing in devices config, Gen2 Shelly devices do not announce themselves")
+            .arg(Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .value_name("SECONDS")
+                .help("Sets how long announces are collected")
+                .takes_value(true)
+                .default_value("10")
+                .validator(|value| value.parse::<u64>().map(|_| ()).map_err(|_| String::from("Listen time must be number of seconds")))
+            )
+        )
     .get_matches_safe()
     
 }
@@ -165,6 +227,77 @@ fn get_contract_data(params: &Ini) -> Result<(u32, u32, u32, f64), &str> {
     return Ok((start_year, start_month, billing_period_months, recovery_ratio));
 }
 
+/* Phases are billed separately when balancing mode is not configured */
+fn get_balancing_mode(params: &Ini) -> Result<BalancingMode, &str> {
+    if let Some(value) = params.get("Contract", "BalancingMode") {
+        if let Ok(mode) = BalancingMode::from_str(&value) {
+            Ok(mode)
+        } else {
+            Err("Balancing mode improper value!")
+        }
+    } else {
+        Ok(BalancingMode::Phase)
+    }
+}
+
+/* Final stretch is disabled and no margin is kept when not configured */
+fn get_final_stretch_config(params: &Ini) -> Result<(u32, f64), &str> {
+    let final_stretch_days = if params.get("Contract", "FinalStretchDays").is_none() {
+        0
+    } else if let Ok(Some(value)) = params.getint("Contract", "FinalStretchDays") {
+        if let Ok(value) = u32::try_from(value) {
+            value
+        } else {
+            return Err("Final stretch days improper value!");
+        }
+    } else {
+        return Err("Final stretch days configuration invalid!");
+    };
+
+    let safety_margin_wh = if params.get("Contract", "SafetyMargin").is_none() {
+        0.0
+    } else if let Ok(Some(value)) = params.getfloat("Contract", "SafetyMargin") {
+        if 0.0 <= value {
+            value
+        } else {
+            return Err("Safety margin improper value!");
+        }
+    } else {
+        return Err("Safety margin configuration invalid!");
+    };
+
+    Ok((final_stretch_days, safety_margin_wh))
+}
+
+fn get_site_config(params: &Ini) -> Result<Option<Site>, &str> {
+    /* Site section is optional */
+    if let (None, None) = (params.get("Site", "Latitude"), params.get("Site", "Longitude")) {
+        return Ok(None);
+    }
+
+    let latitude = if let Ok(Some(value)) = params.getfloat("Site", "Latitude") {
+        if -90.0 <= value && value <= 90.0 {
+            value
+        } else {
+            return Err("Site latitude improper value!");
+        }
+    } else {
+        return Err("Site latitude configuration invalid!");
+    };
+
+    let longitude = if let Ok(Some(value)) = params.getfloat("Site", "Longitude") {
+        if -180.0 <= value && value <= 180.0 {
+            value
+        } else {
+            return Err("Site longitude improper value!");
+        }
+    } else {
+        return Err("Site longitude configuration invalid!");
+    };
+
+    return Ok(Some(Site { latitude, longitude }));
+}
+
 fn get_db_config(params: &Ini) -> Result<Config, &str> {
     let host = if let Some(value) = params.get("Database", "Host") {
         if hostname_validator::is_valid(&value) {
@@ -262,7 +395,10 @@ Result<
         Switchboard,
         HashMap<String, Guard>,
         HashMap<String, Miner>,
-        HashMap<String, Plug>
+        HashMap<String, Plug>,
+        HashMap<String, DeviceMapping>,
+        Option<Inverter>,
+        HashMap<String, GuardType>
     ),
     &str
 > {
@@ -297,8 +433,17 @@ fn parse_yaml(conf: &Yaml)
     Switchboard,
     HashMap<String, Guard>,
     HashMap<String, Miner>,
-    HashMap<String, Plug>
+    HashMap<String, Plug>,
+    HashMap<String, DeviceMapping>,
+    Option<Inverter>,
+    HashMap<String, GuardType>
 )> {
+    /* Checking device mappings, they are optional */
+    let mappings = mapping::parse_mappings(conf)?;
+
+    /* Checking guard types, ESP32 is built in */
+    let guard_types = parse_guard_types(conf)?;
+
     /* Checking switchboard */
     if let Yaml::BadValue = conf["switchboard"] {
         return None;
@@ -309,20 +454,49 @@ fn parse_yaml(conf: &Yaml)
     }
     let switchboard_id = conf["switchboard"]["id"].as_str().unwrap();
 
-    /* Checking each guard */
-    if let Yaml::BadValue = conf["guards"] {
+    /* Check switchboard type is an energy meter, it determines number of phases, SHEM-3 when type is missing */
+    let switchboard_type = if let Yaml::BadValue = conf["switchboard"]["type"] {
+        MeterType::Shelly(ShellyType::SHEM_3)
+    } else if let Some(board_id) = conf["switchboard"]["type"].as_str() {
+        if let Some(phases) = mapping::get_mapped_meter_phases(board_id, &mappings) {
+            MeterType::Mapped(String::from(board_id), phases)
+        } else if let Ok(model) = ModbusModel::from_str(board_id) {
+            MeterType::Modbus(parse_modbus_config(&conf["switchboard"], model)?)
+        } else if board_id == "P1" {
+            MeterType::P1(parse_p1_config(&conf["switchboard"])?)
+        } else if board_id == "SMA" {
+            MeterType::Sma(parse_sma_config(&conf["switchboard"])?)
+        } else {
+            match ShellyType::from_str(board_id) {
+                Ok(board_type) if board_type.get_phase_count() > 0 => MeterType::Shelly(board_type),
+                _ => {
+                    eprintln!("Unknown switchboard type '{}'!", board_id);
+                    return None;
+                }
+            }
+        }
+    } else {
         return None;
-    } 
+    };
 
-    /* Checking guards */
-    let guards_array = if let Some(array) = conf["guards"].as_vec() {
-        array
-    } else {
-        return None
+    let phases = switchboard_type.get_phase_count();
+
+    /* Checking inverter, it is optional */
+    let inverter = match conf["inverter"] {
+        Yaml::BadValue => None,
+        ref inverter => Some(parse_inverter(inverter, phases)?),
+    };
+
+    /* Checking guards, they may be missing when all miners are plug-only */
+    let guards_array = match &conf["guards"] {
+        Yaml::BadValue => vec![],
+        Yaml::Array(array) => array.clone(),
+        _ => return None,
     };
 
     let switchboard = Switchboard {
         id: String::from(switchboard_id),
+        board_type: switchboard_type,
         state: DeviceState::Inaccessible,
         last_seen: MIN_DATETIME,
     };
@@ -331,7 +505,7 @@ fn parse_yaml(conf: &Yaml)
     let mut miners = HashMap::new();
     let mut plugs = HashMap::new();
 
-    for guard in guards_array {
+    for guard in guards_array.iter() {
         let guard_id = if let Some(guard_id) = guard["id"].as_str() {
             /* Guards id are uniqe */
             if guards.contains_key(guard_id) {
@@ -342,16 +516,8 @@ fn parse_yaml(conf: &Yaml)
             return None;
         };
 
-        /* Check guard type is supported */
-        let guard_type = if let Some(board_id) = guard["type"].as_str() {
-            if let Ok(guard_type) = GuardType::from_str(board_id) {
-                guard_type
-            } else {
-                return None;
-            }
-        } else {
-            return None;
-        };
+        /* Check guard type is described */
+        let guard_type = guard_types.get(guard["type"].as_str()?)?.clone();
 
         let pinset_limit = guard_type.get_pinset_limit();
 
@@ -369,19 +535,8 @@ fn parse_yaml(conf: &Yaml)
             last_seen: MIN_DATETIME,
         };
         /* Check all miners under this guard */
+        let mut pinsets = HashSet::new();
         for miner in miners_array {
-            let mut pinsets = HashSet::new();
-            
-            let miner_id = if let Some(miner_id) = miner["id"].as_str() {
-                /* Miner ids are uniqe */
-                if miners.contains_key(miner_id) {
-                    return None;
-                }
-                miner_id
-            } else {
-                return None;
-            };
-
             let miner_pinset = if let Some(pinset) = miner["pinset"].as_i64() {
                 if pinsets.contains(&pinset) || pinset < 0 || pinset as u32 >= pinset_limit {
                     return None;
@@ -392,64 +547,303 @@ fn parse_yaml(conf: &Yaml)
                 return None;
             };
 
-            let plug_id = if let Some(plug_id) = miner["plug"].as_str() {
-                if plugs.contains_key(plug_id) {
-                    return None;
-                }
-                plug_id
-            } else {
-                return None;
+            let (miner, plug) = parse_miner(miner, Some(guard_id), miner_pinset as u32, phases, &mappings, &miners, &plugs)?;
+            local_guard.miners.push(miner.id.clone());
+            match (plug, &miner.plug_id) {
+                (Some(plug), _) => { plugs.insert(plug.id.clone(), plug); },
+                (None, Some(plug_id)) => plugs.get_mut(plug_id).unwrap().miners.push(miner.id.clone()),
+                (None, None) => {},
+            }
+            miners.insert(miner.id.clone(), miner);
+        }
+
+        guards.insert(String::from(guard_id), local_guard);
+    }
+
+    /* Plug-only miners boot when plug is switched on */
+    let plug_miners_array = match &conf["miners"] {
+        Yaml::BadValue => vec![],
+        Yaml::Array(array) => array.clone(),
+        _ => return None,
+    };
+
+    for miner in plug_miners_array.iter() {
+        let (miner, plug) = parse_miner(miner, None, 0, phases, &mappings, &miners, &plugs)?;
+        plugs.insert(plug.as_ref()?.id.clone(), plug?);
+        miners.insert(miner.id.clone(), miner);
+    }
+
+    if miners.is_empty() {
+        return None;
+    }
+
+    Some((switchboard, guards, miners, plugs, mappings, inverter, guard_types))
+}
+
+/* Miner with its plug, pinset is used only by miner with guard, miner with guard may have no plug.
+Plug already defined is not returned, it may be shared only by miners with guard on the same phase. */
+fn parse_miner(
+    miner: &Yaml,
+    guard_id: Option<&str>,
+    pinset: u32,
+    phases: usize,
+    mappings: &HashMap<String, DeviceMapping>,
+    miners: &HashMap<String, Miner>,
+    plugs: &HashMap<String, Plug>
+) -> Option<(Miner, Option<Plug>)> {
+    let miner_id = if let Some(miner_id) = miner["id"].as_str() {
+        /* Miner ids are uniqe */
+        if miners.contains_key(miner_id) {
+            return None;
+        }
+        miner_id
+    } else {
+        return None;
+    };
+
+    /* Plug is optional only for miner with guard, see caller */
+    let plug_id = match &miner["plug"] {
+        Yaml::BadValue => None,
+        Yaml::String(plug_id) => Some(plug_id.as_str()),
+        _ => return None,
+    };
+
+    /* Plug type is optional, Gen1 plug is assumed */
+    let plug_type = if let Some(plug_type) = miner["plug_type"].as_str() {
+        mapping::parse_plug_type(plug_type, mappings)?
+    } else {
+        PlugType::Shelly(ShellyType::SHPLG_S)
+    };
+
+    let phase = if let Some(phase) = miner["phase"].as_i64() {
+        if phase < 0 || phase as usize >= phases {
+            return None;
+        }
+        phase as u8
+    } else {
+        return None;
+    };
+
+    if let Some(plug) = plug_id.and_then(|plug_id| plugs.get(plug_id)) {
+        let first_miner = miners.get(&plug.miners[0]).unwrap();
+        let is_other_type = miner["plug_type"].as_str().is_some() && plug_type != plug.plug_type;
+
+        if guard_id.is_none() || first_miner.guard.is_none() || first_miner.phase != phase || is_other_type {
+            return None;
+        }
+    }
+
+    let consumption = if let Some(consumption) = miner["consumption"].as_i64() {
+        if consumption < 0 {
+            return None;
+        }
+        consumption as u32
+    } else {
+        return None;
+    };
+
+    /* cgminer API is optional, default port is assumed */
+    let api = match &miner["api"] {
+        Yaml::BadValue => None,
+        Yaml::String(address) if address.contains(':') => Some(address.clone()),
+        Yaml::String(address) => Some(format!("{}:{}", address, cgminer::DEFAULT_PORT)),
+        _ => return None,
+    };
+
+    /* Soft power management needs API, only LuxOS sets power target */
+    let soft_power = match (&miner["api_control"], &api) {
+        (Yaml::BadValue, _) => None,
+        (Yaml::String(control), Some(_)) => {
+            let control = ApiControl::from_str(control).ok()?;
+            let power_target = match &miner["power_target"] {
+                Yaml::BadValue => None,
+                Yaml::Integer(watts) if *watts > 0 && control == ApiControl::LuxOS => Some(*watts as u32),
+                _ => return None,
             };
+            Some(SoftPower { control, power_target })
+        },
+        _ => return None,
+    };
 
-            let phase = if let Some(phase) = miner["phase"].as_i64() {
-                if phase < 0 || 2 < phase {
-                    return None;
-                }
-                phase as u8
-            } else {
+    let miner = Miner {
+        id: String::from(miner_id),
+        guard: guard_id.map(String::from),
+        plug_id: plug_id.map(String::from),
+        pinset,
+        phase: phase,
+        estimated_consumption: consumption as f32,
+        power_consumption: None,
+        state: MinerState::Undefined,
+        target_state: None,
+        command_ts: None,
+        included: true,
+        api,
+        is_stalled: false,
+        soft_power,
+        is_paused: false,
+    };
+    let plug = plug_id.filter(|plug_id| !plugs.contains_key(*plug_id)).map(|plug_id| Plug {
+        id: String::from(plug_id),
+        driver: adapters::get_plug_driver(&plug_type, plug_id, mappings),
+        plug_type,
+        state: DeviceState::Inaccessible,
+        miners: vec![String::from(miner_id)],
+        is_enabled: true,
+        last_seen: MIN_DATETIME,
+    });
+
+    Some((miner, plug))
+}
+
+/* Guard types are described by number of pinsets and supported commands, like:
+
+guard_types:
+  ESP8266:
+    pinsets: 2
+    commands: [PowerOn, PowerOff, HardStop, StateReport]
+*/
+fn parse_guard_types(conf: &Yaml) -> Option<HashMap<String, GuardType>> {
+    let mut guard_types = HashMap::new();
+    let esp32 = GuardType::esp32();
+    guard_types.insert(esp32.name.clone(), esp32);
+
+    let types_hash = match &conf["guard_types"] {
+        Yaml::BadValue => return Some(guard_types),
+        Yaml::Hash(hash) => hash,
+        _ => return None,
+    };
+
+    for (name, guard_type) in types_hash.iter() {
+        let name = name.as_str()?;
+
+        /* Type must not shadow built in board */
+        if guard_types.contains_key(name) {
+            return None;
+        }
+
+        let pinsets = match &guard_type["pinsets"] {
+            Yaml::Integer(pinsets) if *pinsets > 0 => *pinsets as u32,
+            _ => return None,
+        };
+
+        let mut commands = vec![];
+        for command in guard_type["commands"].as_vec()? {
+            let command = command.as_str()?;
+            if !GUARD_COMMANDS.contains(&command) || commands.iter().any(|c| c == command) {
                 return None;
-            };
+            }
+            commands.push(String::from(command));
+        }
+        if REQUIRED_GUARD_COMMANDS.iter().any(|&required| !commands.iter().any(|c| c == required)) {
+            return None;
+        }
 
-            let consumption = if let Some(consumption) = miner["consumption"].as_i64() {
-                if consumption < 0 {
-                    return None;
+        guard_types.insert(String::from(name), GuardType { name: String::from(name), pinsets, commands });
+    }
+
+    Some(guard_types)
+}
+
+/* Fronius inverter feeds all phases of switchboard unless its phases are listed */
+fn parse_inverter(conf: &Yaml, phases: usize) -> Option<Inverter> {
+    let id = String::from(conf["id"].as_str()?);
+    if conf["type"].as_str()? != "Fronius" {
+        return None;
+    }
+    let address = String::from(conf["address"].as_str()?);
+
+    let inverter_phases = match &conf["phases"] {
+        Yaml::BadValue => (0..phases as u8).collect(),
+        Yaml::Array(array) => {
+            let mut inverter_phases = vec![];
+            for phase in array {
+                match phase.as_i64() {
+                    Some(phase) if phase >= 0 && (phase as usize) < phases && !inverter_phases.contains(&(phase as u8)) => {
+                        inverter_phases.push(phase as u8);
+                    },
+                    _ => return None,
                 }
-                consumption as u32
-            } else {
+            }
+            if inverter_phases.is_empty() {
                 return None;
-            };
+            }
+            inverter_phases
+        },
+        _ => return None,
+    };
 
-            local_guard.miners.push(String::from(miner_id));
-            miners.insert(
-                String::from(miner_id),
-                Miner {
-                    id: String::from(miner_id),
-                    guard: String::from(guard_id),
-                    plug_id: String::from(plug_id),
-                    pinset: miner_pinset as u32,
-                    phase: phase,
-                    estimated_consumption: consumption as f32,
-                    power_consumption: None,
-                    state: MinerState::Undefined,
-                    target_state: None,
-                    command_ts: None,
-                    included: true,
-                }    
-            );
-            plugs.insert(
-                String::from(plug_id),
-                Plug {
-                    id: String::from(plug_id),
-                    state: DeviceState::Inaccessible,
-                    miner_id: String::from(miner_id),
-                    is_enabled: true,
-                    last_seen: MIN_DATETIME,
-                }
-            );
-        }
+    /* Power is integrated, so inverter is polled more often than meters */
+    let interval = match &conf["interval"] {
+        Yaml::BadValue => 10,
+        Yaml::Integer(interval) if *interval > 0 => *interval as u64,
+        _ => return None,
+    };
 
-        guards.insert(String::from(guard_id), local_guard);
+    Some(Inverter { id, address, phases: inverter_phases, interval })
+}
+
+/* Modbus meter needs address, unit id, number of phases and polling interval are optional */
+fn parse_modbus_config(conf: &Yaml, model: ModbusModel) -> Option<ModbusConfig> {
+    let address = conf["address"].as_str()?;
+    let address = if address.contains(':') {
+        String::from(address)
+    } else {
+        format!("{}:502", address)
+    };
+
+    let unit = match &conf["unit"] {
+        Yaml::BadValue => 1,
+        Yaml::Integer(unit) if (0..=255).contains(unit) => *unit as u8,
+        _ => return None,
+    };
+
+    let phases = parse_phase_count(conf)?;
+    let interval = parse_polling_interval(conf)?;
+
+    Some(ModbusConfig { model, address, unit, phases, interval })
+}
+
+/* SMA meter multicasts by default, serial number is needed when there are more meters */
+fn parse_sma_config(conf: &Yaml) -> Option<SmaConfig> {
+    let address = String::from(conf["address"].as_str().unwrap_or(sma::MULTICAST_ADDRESS));
+
+    let serial = match &conf["serial"] {
+        Yaml::BadValue => None,
+        Yaml::Integer(serial) if (0..=u32::MAX as i64).contains(serial) => Some(*serial as u32),
+        _ => return None,
+    };
+
+    let phases = parse_phase_count(conf)?;
+    let interval = parse_polling_interval(conf)?;
+
+    Some(SmaConfig { address, serial, phases, interval })
+}
+
+/* P1 port is read from serial device path or from TCP bridge address,
+meter is accounted as single phase unless phases are given */
+fn parse_p1_config(conf: &Yaml) -> Option<P1Config> {
+    let source = String::from(conf["source"].as_str()?);
+    let phases = match &conf["phases"] {
+        Yaml::BadValue => 1,
+        _ => parse_phase_count(conf)?,
+    };
+    let interval = parse_polling_interval(conf)?;
+
+    Some(P1Config { source, phases, interval })
+}
+
+fn parse_phase_count(conf: &Yaml) -> Option<usize> {
+    match &conf["phases"] {
+        Yaml::BadValue => Some(3),
+        Yaml::Integer(phases) if (1..=3).contains(phases) => Some(*phases as usize),
+        _ => None,
     }
+}
 
-    Some((switchboard, guards, miners, plugs))
-}
\ No newline at end of file
+fn parse_polling_interval(conf: &Yaml) -> Option<u64> {
+    match &conf["interval"] {
+        Yaml::BadValue => Some(30),
+        Yaml::Integer(interval) if *interval > 0 => Some(*interval as u64),
+        _ => None,
+    }
+}
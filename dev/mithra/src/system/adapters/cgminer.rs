@@ -0,0 +1,262 @@
+use json::JsonValue;
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::system::structs::{ApiControl, MinerApiCommand, MinerTelemetry};
+
+/* cgminer API and its forks (bmminer, bosminer) answer one JSON command per connection on TCP 4028,
+response is terminated by zero byte and starts with STATUS list where status "E" means error.
+Hashrate is reported as "GHS 5s" by bmminer, often as string, or as "MHS 5s" by cgminer.
+Antminer stats hold chip temperatures as temp2_N or as temp_chipN like "62-70-61-69" and fans as fanN,
+unused fan slots report 0. Devices of cgminer report their own temperature.
+Mining is paused by pause and resume of Braiins OS, by curtail sleep and wakeup of LuxOS, which needs
+session id obtained by logon as first parameter, and by disabling every ASC device of plain cgminer.
+Power target can be set only by LuxOS, Braiins OS keeps power target of its own tuner configuration. */
+
+pub const DEFAULT_PORT: u16 = 4028;
+
+/* Miners are polled less often than meters, telemetry is not used for scheduling */
+pub const TELEMETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn send_command(address: &str, command: &str) -> Result<JsonValue, String> {
+    send_request(address, command, None)
+}
+
+fn send_request(address: &str, command: &str, parameter: Option<&str>) -> Result<JsonValue, String> {
+    let socket_address = address.to_socket_addrs()
+        .map_err(|error| error.to_string())?
+        .next()
+        .ok_or(format!("Unresolved address {}", address))?;
+
+    let mut stream = TcpStream::connect_timeout(&socket_address, TIMEOUT).map_err(|error| error.to_string())?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|error| error.to_string())?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(|error| error.to_string())?;
+
+    let mut request = JsonValue::new_object();
+    request["command"] = command.into();
+    if let Some(parameter) = parameter {
+        request["parameter"] = parameter.into();
+    }
+    stream.write_all(request.dump().as_bytes()).map_err(|error| error.to_string())?;
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response).map_err(|error| error.to_string())?;
+    let response = String::from_utf8_lossy(&response);
+
+    /* bmminer omits commas between objects of STATS list */
+    let response = response.trim_end_matches(|c: char| c == '\0' || c.is_whitespace()).replace("}{", "},{");
+    let response = json::parse(&response).map_err(|error| error.to_string())?;
+
+    if response["STATUS"][0]["STATUS"] == "E" || response["STATUS"][0]["STATUS"] == "F" {
+        return Err(format!("Command {} failed: {}", command, response["STATUS"][0]["Msg"]));
+    }
+
+    Ok(response)
+}
+
+/* Numbers are sent as strings by some firmwares */
+fn get_number(value: &JsonValue) -> Option<f64> {
+    value.as_f64().or_else(|| value.as_str().and_then(|value| value.trim().parse().ok()))
+}
+
+/* Returns hashrate in GH/s and accepted shares */
+pub fn parse_summary(summary: &JsonValue) -> Option<(f64, u64)> {
+    let summary = &summary["SUMMARY"][0];
+
+    let hashrate = match get_number(&summary["GHS 5s"]) {
+        Some(hashrate) => hashrate,
+        None => get_number(&summary["MHS 5s"])? / 1000.0,
+    };
+    let accepted = get_number(&summary["Accepted"])? as u64;
+
+    Some((hashrate, accepted))
+}
+
+/* Returns temperature of the hottest chip and speed of the slowest running fan */
+pub fn parse_stats(stats: &JsonValue) -> (Option<f32>, Option<u32>) {
+    let mut temperature: Option<f64> = None;
+    let mut fan_rpm: Option<u32> = None;
+
+    for entry in stats["STATS"].members() {
+        for (key, value) in entry.entries() {
+            if key.starts_with("temp2_") {
+                if let Some(chip_temperature) = get_number(value) {
+                    temperature = Some(temperature.map_or(chip_temperature, |t| t.max(chip_temperature)));
+                }
+            } else if key.starts_with("temp_chip") {
+                /* Inlet and outlet temperature of chip pairs */
+                for chip_temperature in value.as_str().unwrap_or("").split('-').filter_map(|t| t.parse::<f64>().ok()) {
+                    temperature = Some(temperature.map_or(chip_temperature, |t| t.max(chip_temperature)));
+                }
+            } else if key.strip_prefix("fan").map_or(false, |n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())) {
+                match get_number(value) {
+                    Some(rpm) if rpm > 0.0 => fan_rpm = Some(fan_rpm.map_or(rpm as u32, |f| f.min(rpm as u32))),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    (temperature.map(|t| t as f32), fan_rpm)
+}
+
+/* Returns temperature of the hottest device */
+pub fn parse_devs(devs: &JsonValue) -> Option<f32> {
+    devs["DEVS"].members()
+        .filter_map(|dev| get_number(&dev["Temperature"]))
+        .filter(|&temperature| temperature > 0.0)
+        .fold(None, |max: Option<f64>, t| Some(max.map_or(t, |max| max.max(t))))
+        .map(|t| t as f32)
+}
+
+/* Summary is mandatory, stats and devs are not supported by every firmware */
+pub fn get_telemetry(address: &str) -> Result<MinerTelemetry, String> {
+    let summary = send_command(address, "summary")?;
+    let (hashrate_ghs, accepted) = parse_summary(&summary).ok_or(String::from("Malformed summary"))?;
+
+    let (temperature, fan_rpm) = send_command(address, "stats")
+        .map(|stats| parse_stats(&stats))
+        .unwrap_or((None, None));
+    let temperature = match temperature {
+        Some(temperature) => Some(temperature),
+        None => send_command(address, "devs").ok().and_then(|devs| parse_devs(&devs)),
+    };
+
+    Ok(MinerTelemetry { hashrate_ghs, temperature, fan_rpm, accepted })
+}
+
+fn control_luxos(address: &str, command: MinerApiCommand, power_target: Option<u32>) -> Result<(), String> {
+    let session = send_command(address, "logon")?;
+    let session_id = session["SESSION"][0]["SessionID"].as_str().ok_or(String::from("Session id is missing"))?.to_string();
+
+    let mut result = Ok(());
+    if let (MinerApiCommand::Resume, Some(watts)) = (command, power_target) {
+        result = send_request(address, "powertargetset", Some(&format!("{},{}", session_id, watts))).map(|_| ());
+    }
+    if result.is_ok() {
+        let action = if command == MinerApiCommand::Pause { "sleep" } else { "wakeup" };
+        result = send_request(address, "curtail", Some(&format!("{},{}", session_id, action))).map(|_| ());
+    }
+
+    /* Session is released even after failure, LuxOS allows only one session */
+    send_request(address, "logoff", Some(&session_id)).ok();
+    result
+}
+
+fn control_cgminer(address: &str, command: MinerApiCommand) -> Result<(), String> {
+    let devs = send_command(address, "devs")?;
+    let command = if command == MinerApiCommand::Pause { "ascdisable" } else { "ascenable" };
+
+    let mut devices = 0;
+    for dev in devs["DEVS"].members() {
+        if let Some(asc) = dev["ASC"].as_u32() {
+            send_request(address, command, Some(&asc.to_string()))?;
+            devices += 1;
+        }
+    }
+
+    if devices == 0 {
+        return Err(String::from("Miner has no ASC device"));
+    }
+    Ok(())
+}
+
+/* Pauses or resumes mining, power target is used only on resume */
+pub fn control(address: &str, control: ApiControl, command: MinerApiCommand, power_target: Option<u32>) -> Result<(), String> {
+    match control {
+        ApiControl::BraiinsOS => {
+            let command = if command == MinerApiCommand::Pause { "pause" } else { "resume" };
+            send_command(address, command).map(|_| ())
+        },
+        ApiControl::LuxOS => control_luxos(address, command, power_target),
+        ApiControl::Cgminer => control_cgminer(address, command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    static BMMINER_SUMMARY: &str = r#"{"STATUS":[{"STATUS":"S","When":1650000000,"Code":11,"Msg":"Summary","Description":"bmminer 1.0.0"}],"SUMMARY":[{"Elapsed":3600,"GHS 5s":"13512.34","GHS av":13498.10,"Found Blocks":0,"Accepted":1234,"Rejected":5}],"id":1}"#;
+    static BMMINER_STATS: &str = r#"{"STATUS":[{"STATUS":"S","When":1650000000,"Code":70,"Msg":"BMMiner stats","Description":"bmminer 1.0.0"}],"STATS":[{"BMMiner":"1.0.0","Miner":"16.8.1.3","Type":"Antminer S9"}{"STATS":0,"ID":"BC50","Elapsed":3600,"fan_num":2,"fan1":0,"fan2":0,"fan3":5880,"fan6":6120,"temp_num":3,"temp2_6":71,"temp2_7":78,"temp2_8":74,"temp_max":65}],"id":1}"#;
+    static CGMINER_DEVS: &str = r#"{"STATUS":[{"STATUS":"S","When":1650000000,"Code":9,"Msg":"1 ASC(s)","Description":"cgminer 4.11.1"}],"DEVS":[{"ASC":0,"Name":"GSD","Temperature":0.00,"MHS 5s":3500.12},{"ASC":1,"Name":"GSD","Temperature":61.50,"MHS 5s":3499.88}],"id":1}"#;
+
+    #[test]
+    fn bmminer_responses() {
+        let stats = json::parse(&BMMINER_STATS.replace("}{", "},{")).unwrap();
+        assert_eq!(parse_summary(&json::parse(BMMINER_SUMMARY).unwrap()), Some((13512.34, 1234)));
+        assert_eq!(parse_stats(&stats), (Some(78.0), Some(5880)));
+
+        let cgminer_summary = json::parse(r#"{"STATUS":[{"STATUS":"S"}],"SUMMARY":[{"MHS 5s":7000.0,"Accepted":42}]}"#).unwrap();
+        assert_eq!(parse_summary(&cgminer_summary), Some((7.0, 42)));
+        assert_eq!(parse_devs(&json::parse(CGMINER_DEVS).unwrap()), Some(61.5));
+    }
+
+    /* Stand-in answering every request by given closure, requests are returned when it finishes */
+    fn spawn_api(requests: usize, respond: fn(&JsonValue) -> String) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let server = thread::spawn(move || {
+            let mut received = vec![];
+            for _ in 0..requests {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 256];
+                let size = stream.read(&mut request).unwrap();
+                let request = json::parse(std::str::from_utf8(&request[..size]).unwrap()).unwrap();
+
+                stream.write_all(respond(&request).as_bytes()).unwrap();
+                stream.write_all(b"\0").unwrap();
+                received.push(format!("{}|{}", request["command"], request["parameter"]));
+            }
+            received
+        });
+
+        (address, server)
+    }
+
+    #[test]
+    fn luxos_session_wraps_commands() {
+        let (address, server) = spawn_api(4, |request| match request["command"].as_str().unwrap() {
+            "logon" => String::from(r#"{"STATUS":[{"STATUS":"S","Msg":"Session created"}],"SESSION":[{"SessionID":"xyz"}]}"#),
+            _ => String::from(r#"{"STATUS":[{"STATUS":"S","Msg":"Ok"}]}"#),
+        });
+        assert_eq!(control(&address, ApiControl::LuxOS, MinerApiCommand::Resume, Some(1200)), Ok(()));
+        assert_eq!(server.join().unwrap(), vec!["logon|null", "powertargetset|xyz,1200", "curtail|xyz,wakeup", "logoff|xyz"]);
+
+        /* Failed command is reported and session is released */
+        let (address, server) = spawn_api(3, |request| match request["command"].as_str().unwrap() {
+            "logon" => String::from(r#"{"STATUS":[{"STATUS":"S"}],"SESSION":[{"SessionID":"xyz"}]}"#),
+            "curtail" => String::from(r#"{"STATUS":[{"STATUS":"E","Msg":"Invalid session"}]}"#),
+            _ => String::from(r#"{"STATUS":[{"STATUS":"S"}]}"#),
+        });
+        assert_eq!(
+            control(&address, ApiControl::LuxOS, MinerApiCommand::Pause, Some(1200)),
+            Err(String::from("Command curtail failed: Invalid session"))
+        );
+        assert_eq!(server.join().unwrap(), vec!["logon|null", "curtail|xyz,sleep", "logoff|xyz"]);
+    }
+
+    #[test]
+    fn telemetry_from_api_stand_in() {
+        let (address, server) = spawn_api(2, |request| match request["command"].as_str().unwrap() {
+            "summary" => String::from(BMMINER_SUMMARY),
+            "stats" => String::from(BMMINER_STATS),
+            _ => unreachable!(),
+        });
+
+        assert_eq!(get_telemetry(&address), Ok(MinerTelemetry {
+            hashrate_ghs: 13512.34,
+            temperature: Some(78.0),
+            fan_rpm: Some(5880),
+            accepted: 1234,
+        }));
+        assert_eq!(server.join().unwrap(), vec!["summary|null", "stats|null"]);
+    }
+}
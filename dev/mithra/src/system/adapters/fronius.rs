@@ -0,0 +1,172 @@
+use json::JsonValue;
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use crate::system::structs::Inverter;
+
+/* Fronius Solar API v1 of Datamanager or Gen24 inverter is served over plain local HTTP.
+Power flow of site contains actual PV power P_PV in W, which is null while inverter sleeps.
+Energy is integrated from power of consecutive polls, so interval should be short (about 10 s). */
+
+pub const POWER_FLOW_PATH: &str = "/solar_api/v1/GetPowerFlowRealtimeData.fcgi";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/* Readings older than this number of intervals are not integrated */
+const MAX_MISSED_POLLS: u32 = 3;
+
+/* Minimal HTTP/1.0 client, server closes connection after response */
+pub fn http_get(address: &str, path: &str) -> Result<String, String> {
+    let address = if address.contains(':') {
+        String::from(address)
+    } else {
+        format!("{}:80", address)
+    };
+
+    let mut stream = TcpStream::connect(&address).map_err(|error| error.to_string())?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|error| error.to_string())?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(|error| error.to_string())?;
+
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, address);
+    stream.write_all(request.as_bytes()).map_err(|error| error.to_string())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(|error| error.to_string())?;
+
+    let (head, body) = response.split_once("\r\n\r\n").ok_or(String::from("Malformed HTTP response"))?;
+    let status = head.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        return Err(format!("HTTP status {}", status));
+    }
+
+    Ok(String::from(body))
+}
+
+/* Returns actual PV power of site in W */
+pub fn parse_power_flow(power_flow: &JsonValue) -> Option<f64> {
+    let site = &power_flow["Body"]["Data"]["Site"];
+    if !site.is_object() {
+        return None;
+    }
+
+    match &site["P_PV"] {
+        JsonValue::Null => Some(0.0),
+        power => power.as_f64().map(|power| power.max(0.0)),
+    }
+}
+
+pub struct FroniusInverter {
+    inverter: Inverter,
+    last_poll: Option<(Instant, f64)>,
+    /* Energy below 1 Wmin carried to next poll */
+    remainder_wmin: f64,
+}
+
+impl FroniusInverter {
+    pub fn new(inverter: &Inverter) -> Self {
+        FroniusInverter {
+            inverter: inverter.clone(),
+            last_poll: None,
+            remainder_wmin: 0.0,
+        }
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.inverter.id
+    }
+
+    pub fn get_interval(&self) -> Duration {
+        Duration::from_secs(self.inverter.interval)
+    }
+
+    /* Returns energy produced since last poll in Wmin and actual power in W,
+    None is returned after first poll or after polls were missed */
+    pub fn poll(&mut self) -> Result<Option<(u64, f32)>, String> {
+        let body = http_get(&self.inverter.address, POWER_FLOW_PATH)?;
+        let power_flow = json::parse(&body).map_err(|error| error.to_string())?;
+        let power = parse_power_flow(&power_flow).ok_or(String::from("Power flow of site is missing"))?;
+        let now = Instant::now();
+
+        let energy = match self.last_poll {
+            Some((last_ts, last_power)) if now - last_ts <= self.get_interval() * MAX_MISSED_POLLS => {
+                /* Trapezoidal rule */
+                let energy_wmin = (last_power + power) / 2.0 * (now - last_ts).as_secs_f64() / 60.0 + self.remainder_wmin;
+                self.remainder_wmin = energy_wmin.fract();
+                Some((energy_wmin.trunc() as u64, power as f32))
+            },
+            _ => {
+                self.remainder_wmin = 0.0;
+                None
+            },
+        };
+
+        self.last_poll = Some((now, power));
+        Ok(energy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    /* Stand-in of Datamanager answering every request with given power flow */
+    fn spawn_server(responses: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            for body in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 1024];
+                let size = stream.read(&mut request).unwrap();
+                assert!(std::str::from_utf8(&request[..size]).unwrap().starts_with(&format!("GET {} ", POWER_FLOW_PATH)));
+
+                let response = format!(
+                    "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(), body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        address
+    }
+
+    fn get_power_flow(power: &str) -> String {
+        format!(
+            r#"{{"Body": {{"Data": {{"Site": {{"Mode": "produce-only", "P_Grid": null, "P_Load": null, "P_PV": {}, "E_Day": 1234, "E_Total": 5678900}}, "Version": "12"}}}}, "Head": {{"Status": {{"Code": 0}}}}}}"#,
+            power
+        )
+    }
+
+    #[test]
+    fn power_flow_parsing() {
+        assert_eq!(parse_power_flow(&json::parse(&get_power_flow("2345.5")).unwrap()), Some(2345.5));
+        /* Inverter is sleeping at night */
+        assert_eq!(parse_power_flow(&json::parse(&get_power_flow("null")).unwrap()), Some(0.0));
+        assert_eq!(parse_power_flow(&json::parse(r#"{"Head": {"Status": {"Code": 8}}}"#).unwrap()), None);
+    }
+
+    #[test]
+    fn inverter_integrates_power() {
+        let address = spawn_server(vec![get_power_flow("1200"), get_power_flow("1800")]);
+        let mut inverter = FroniusInverter::new(&Inverter {
+            id: String::from("fronius"),
+            address,
+            phases: vec![0, 1, 2],
+            interval: 10,
+        });
+
+        assert_eq!(inverter.poll(), Ok(None));
+        thread::sleep(Duration::from_millis(200));
+
+        /* 1500 W in average during at least 0.2 s is at least 5 Wmin */
+        let (energy, power) = inverter.poll().unwrap().unwrap();
+        assert!((5..25).contains(&energy));
+        assert_eq!(power, 1800.0);
+    }
+}
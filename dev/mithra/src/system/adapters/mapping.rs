@@ -0,0 +1,527 @@
+use json::JsonValue;
+use std::{collections::HashMap, str::FromStr};
+use yaml_rust::Yaml;
+
+use super::{MeterReading, MeterSource, PlugDriver, get_delta_reading};
+use crate::system::structs::{PlugStatus, PlugType, ShellyType};
+
+/* Declarative description of MQTT device defined in devices config, like:
+
+mappings:
+  zigbee-plug:
+    kind: plug
+    power: {topic: "zigbee2mqtt/{id}", pointer: /power}
+    energy: {topic: "zigbee2mqtt/{id}", pointer: /energy, scale: 60000}
+    state: {topic: "zigbee2mqtt/{id}", pointer: /state, on: "ON", off: "OFF"}
+    command: {topic: "zigbee2mqtt/{id}/set", on: '{"state": "ON"}', off: '{"state": "OFF"}'}
+    availability: {topic: "zigbee2mqtt/{id}/availability", pointer: /state, on: online}
+  esphome-meter:
+    kind: meter
+    phases: 3
+    consumed: {topic: "{id}/sensor/consumed_{phase}/state", scale: 1000}
+    returned: {topic: "{id}/sensor/returned_{phase}/state", scale: 1000}
+
+Topics and pointers may contain {id} of device and {phase} counted from 0.
+Value is taken by JSON pointer or from plain payload when pointer is missing and multiplied by scale.
+Meter totals are scaled to Wh, plug power to W and plug energy counter to Wmin.
+Plug is alive while any of its mapped values is published, state may be left out or published on change only. */
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueMapping {
+    pub topic: String,
+    pub pointer: Option<String>,
+    pub scale: f64,
+}
+
+/* Value compared with on and off payloads */
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateMapping {
+    pub topic: String,
+    pub pointer: Option<String>,
+    pub on: String,
+    pub off: String,
+}
+
+/* Relay command templates, query asks device for relay state */
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandMapping {
+    pub topic: String,
+    pub on: String,
+    pub off: String,
+    pub query: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceMapping {
+    Meter {
+        phases: usize,
+        consumed: ValueMapping,
+        returned: ValueMapping,
+        availability: Option<StateMapping>,
+    },
+    Plug {
+        power: Option<ValueMapping>,
+        energy: ValueMapping,
+        state: Option<StateMapping>,
+        command: CommandMapping,
+        availability: Option<StateMapping>,
+    },
+}
+
+/* Returns all mappings from devices config, None when any of them is improper */
+pub fn parse_mappings(conf: &Yaml) -> Option<HashMap<String, DeviceMapping>> {
+    let mut mappings = HashMap::new();
+
+    let mappings_hash = match &conf["mappings"] {
+        Yaml::BadValue => return Some(mappings),
+        Yaml::Hash(hash) => hash,
+        _ => return None,
+    };
+
+    for (name, mapping) in mappings_hash.iter() {
+        let name = name.as_str()?;
+
+        /* Mapping must not shadow built in device */
+        if ShellyType::from_str(name).is_ok() || name == "Tasmota" {
+            return None;
+        }
+
+        mappings.insert(String::from(name), parse_mapping(mapping)?);
+    }
+
+    Some(mappings)
+}
+
+fn parse_mapping(conf: &Yaml) -> Option<DeviceMapping> {
+    fn parse_pointer(conf: &Yaml) -> Option<Option<String>> {
+        match conf {
+            Yaml::BadValue => Some(None),
+            Yaml::String(pointer) if pointer.is_empty() || pointer.starts_with('/') => Some(Some(pointer.clone())),
+            _ => None,
+        }
+    }
+
+    fn parse_value(conf: &Yaml) -> Option<ValueMapping> {
+        let scale = match &conf["scale"] {
+            Yaml::BadValue => 1.0,
+            Yaml::Integer(scale) => *scale as f64,
+            Yaml::Real(_) => conf["scale"].as_f64()?,
+            _ => return None,
+        };
+
+        Some(ValueMapping {
+            topic: String::from(conf["topic"].as_str()?),
+            pointer: parse_pointer(&conf["pointer"])?,
+            scale,
+        })
+    }
+
+    fn parse_state(conf: &Yaml) -> Option<StateMapping> {
+        Some(StateMapping {
+            topic: String::from(conf["topic"].as_str()?),
+            pointer: parse_pointer(&conf["pointer"])?,
+            on: String::from(conf["on"].as_str()?),
+            off: String::from(conf["off"].as_str().unwrap_or("")),
+        })
+    }
+
+    fn parse_optional<T>(conf: &Yaml, parse: fn(&Yaml) -> Option<T>) -> Option<Option<T>> {
+        match conf {
+            Yaml::BadValue => Some(None),
+            conf => parse(conf).map(Some),
+        }
+    }
+
+    let availability = parse_optional(&conf["availability"], parse_state)?;
+
+    match conf["kind"].as_str()? {
+        "meter" => {
+            let phases = conf["phases"].as_i64()?;
+            if phases < 1 || phases > 3 {
+                return None;
+            }
+
+            Some(DeviceMapping::Meter {
+                phases: phases as usize,
+                consumed: parse_value(&conf["consumed"])?,
+                returned: parse_value(&conf["returned"])?,
+                availability,
+            })
+        },
+        "plug" => {
+            let command = &conf["command"];
+
+            Some(DeviceMapping::Plug {
+                power: parse_optional(&conf["power"], parse_value)?,
+                energy: parse_value(&conf["energy"])?,
+                state: parse_optional(&conf["state"], parse_state)?,
+                command: CommandMapping {
+                    topic: String::from(command["topic"].as_str()?),
+                    on: String::from(command["on"].as_str()?),
+                    off: String::from(command["off"].as_str()?),
+                    query: command["query"].as_str().map(String::from),
+                },
+                availability,
+            })
+        },
+        _ => None,
+    }
+}
+
+/* Resolves JSON pointer as defined by RFC 6901 */
+fn get_pointed<'a>(value: &'a JsonValue, pointer: &str) -> Option<&'a JsonValue> {
+    if pointer.is_empty() {
+        return Some(value);
+    }
+
+    let mut value = value;
+    for token in pointer.strip_prefix('/')?.split('/') {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        value = match value {
+            JsonValue::Object(object) => object.get(&token)?,
+            JsonValue::Array(array) => array.get(token.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    Some(value)
+}
+
+fn fill(pattern: &str, id: &str, phase: usize) -> String {
+    pattern.replace("{id}", id).replace("{phase}", phase.to_string().as_str())
+}
+
+/* Returns payload or value pointed in json payload as text */
+fn get_text(payload: &str, pointer: &Option<String>) -> Option<String> {
+    let pointer = match pointer {
+        Some(pointer) => pointer,
+        None => return Some(String::from(payload.trim())),
+    };
+
+    let message = json::parse(payload).ok()?;
+    let value = get_pointed(&message, pointer)?;
+    match value.as_str() {
+        Some(text) => Some(String::from(text)),
+        None if value.is_null() => None,
+        None => Some(value.dump()),
+    }
+}
+
+impl ValueMapping {
+    fn bind(&self, id: &str, phase: usize) -> Self {
+        ValueMapping {
+            topic: fill(&self.topic, id, phase),
+            pointer: self.pointer.as_ref().map(|pointer| fill(pointer, id, phase)),
+            scale: self.scale,
+        }
+    }
+
+    fn get_value(&self, payload: &str) -> Option<f64> {
+        get_text(payload, &self.pointer)?.parse::<f64>().ok().map(|value| value * self.scale)
+    }
+}
+
+impl StateMapping {
+    fn bind(&self, id: &str) -> Self {
+        StateMapping {
+            topic: fill(&self.topic, id, 0),
+            pointer: self.pointer.as_ref().map(|pointer| fill(pointer, id, 0)),
+            on: self.on.clone(),
+            off: self.off.clone(),
+        }
+    }
+
+    fn get_state(&self, payload: &str) -> Option<bool> {
+        let text = get_text(payload, &self.pointer)?;
+        if text == self.on {
+            Some(true)
+        } else if text == self.off {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/* Meter publishing total energies, every phase may have its own topic */
+pub struct MappedMeter {
+    consumed: Vec<ValueMapping>,
+    returned: Vec<ValueMapping>,
+    availability: Option<StateMapping>,
+    consumed_wh: Vec<Option<f64>>,
+    returned_wh: Vec<Option<f64>>,
+    last_totals: Option<(Vec<f64>, Vec<f64>)>,
+}
+
+impl MappedMeter {
+    pub fn new(id: &str, mapping: &DeviceMapping) -> Self {
+        if let DeviceMapping::Meter{phases, consumed, returned, availability} = mapping {
+            MappedMeter {
+                consumed: (0..*phases).map(|phase| consumed.bind(id, phase)).collect(),
+                returned: (0..*phases).map(|phase| returned.bind(id, phase)).collect(),
+                availability: availability.as_ref().map(|availability| availability.bind(id)),
+                consumed_wh: vec![None; *phases],
+                returned_wh: vec![None; *phases],
+                last_totals: None,
+            }
+        } else {
+            panic!("Mapping of plug used for meter");
+        }
+    }
+}
+
+impl MeterSource for MappedMeter {
+    fn get_topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = vec![];
+        for mapping in self.consumed.iter().chain(self.returned.iter()) {
+            if !topics.contains(&mapping.topic) {
+                topics.push(mapping.topic.clone());
+            }
+        }
+        topics
+    }
+
+    fn parse(&mut self, topic: &str, payload: &str) -> Option<MeterReading> {
+        for (mappings, values) in [(&self.consumed, &mut self.consumed_wh), (&self.returned, &mut self.returned_wh)] {
+            for (mapping, value) in mappings.iter().zip(values.iter_mut()) {
+                if mapping.topic == topic {
+                    if let Some(energy) = mapping.get_value(payload) {
+                        *value = Some(energy);
+                    }
+                }
+            }
+        }
+
+        if self.consumed_wh.iter().chain(self.returned_wh.iter()).any(|value| value.is_none()) {
+            return None;
+        }
+
+        let consumed_wh = self.consumed_wh.iter_mut().map(|value| value.take().unwrap()).collect();
+        let returned_wh = self.returned_wh.iter_mut().map(|value| value.take().unwrap()).collect();
+
+        get_delta_reading(&mut self.last_totals, consumed_wh, returned_wh)
+    }
+
+    fn get_totals(&self) -> Option<(Vec<f64>, Vec<f64>)> {
+        self.last_totals.clone()
+    }
+
+    fn get_availability_topic(&self) -> Option<String> {
+        self.availability.as_ref().map(|availability| availability.topic.clone())
+    }
+
+    fn is_online(&self, payload: &str) -> bool {
+        match &self.availability {
+            Some(availability) => availability.get_state(payload) == Some(true),
+            None => true,
+        }
+    }
+
+    fn is_always_available(&self) -> bool {
+        self.availability.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub struct MappedPlug {
+    id: String,
+    power: Option<ValueMapping>,
+    energy: ValueMapping,
+    state: Option<StateMapping>,
+    command: CommandMapping,
+    availability: Option<StateMapping>,
+}
+
+impl MappedPlug {
+    pub fn new(id: &str, mapping: &DeviceMapping) -> Self {
+        if let DeviceMapping::Plug{power, energy, state, command, availability} = mapping {
+            MappedPlug {
+                id: String::from(id),
+                power: power.as_ref().map(|power| power.bind(id, 0)),
+                energy: energy.bind(id, 0),
+                state: state.as_ref().map(|state| state.bind(id)),
+                command: command.clone(),
+                availability: availability.as_ref().map(|availability| availability.bind(id)),
+            }
+        } else {
+            panic!("Mapping of meter used for plug");
+        }
+    }
+}
+
+impl PlugDriver for MappedPlug {
+    fn get_data_topics(&self) -> Vec<String> {
+        let mut topics = vec![self.energy.topic.clone()];
+        if let Some(power) = &self.power {
+            if !topics.contains(&power.topic) {
+                topics.push(power.topic.clone());
+            }
+        }
+        topics
+    }
+
+    fn get_state_topics(&self) -> Vec<String> {
+        match &self.state {
+            Some(state) if !self.get_data_topics().contains(&state.topic) => vec![state.topic.clone()],
+            _ => vec![],
+        }
+    }
+
+    fn parse(&self, topic: &str, payload: &str) -> PlugStatus {
+        let mut status = PlugStatus::default();
+
+        if let Some(power) = self.power.as_ref().filter(|power| power.topic == topic) {
+            status.power = power.get_value(payload).map(|power| power as f32);
+        }
+        if self.energy.topic == topic {
+            status.energy_wmin = self.energy.get_value(payload).map(|energy| energy.round() as u64);
+        }
+        if let Some(state) = self.state.as_ref().filter(|state| state.topic == topic) {
+            status.output = state.get_state(payload);
+        }
+
+        status
+    }
+
+    fn get_switch_command(&self, on: bool) -> (String, String) {
+        (
+            fill(&self.command.topic, &self.id, 0),
+            fill(if on { &self.command.on } else { &self.command.off }, &self.id, 0),
+        )
+    }
+
+    fn get_state_query(&self) -> Option<(String, String)> {
+        self.command.query.as_ref().map(|query| (fill(&self.command.topic, &self.id, 0), fill(query, &self.id, 0)))
+    }
+
+    fn get_availability_topic(&self) -> Option<String> {
+        self.availability.as_ref().map(|availability| availability.topic.clone())
+    }
+
+    fn is_online(&self, payload: &str) -> bool {
+        match &self.availability {
+            Some(availability) => availability.get_state(payload) == Some(true),
+            None => true,
+        }
+    }
+
+    fn is_always_available(&self) -> bool {
+        self.availability.is_none()
+    }
+}
+
+/* Checks type name refers to mapping of given kind */
+pub fn is_mapped_plug(name: &str, mappings: &HashMap<String, DeviceMapping>) -> bool {
+    matches!(mappings.get(name), Some(DeviceMapping::Plug{..}))
+}
+
+pub fn get_mapped_meter_phases(name: &str, mappings: &HashMap<String, DeviceMapping>) -> Option<usize> {
+    match mappings.get(name) {
+        Some(DeviceMapping::Meter{phases, ..}) => Some(*phases),
+        _ => None,
+    }
+}
+
+/* Plug type given in config is built in or name of mapping */
+pub fn parse_plug_type(name: &str, mappings: &HashMap<String, DeviceMapping>) -> Option<PlugType> {
+    if is_mapped_plug(name, mappings) {
+        Some(PlugType::Mapped(String::from(name)))
+    } else {
+        PlugType::from_str(name).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    static MAPPINGS: &str = r#"
+mappings:
+  zigbee-plug:
+    kind: plug
+    power: {topic: "zigbee2mqtt/{id}", pointer: /power}
+    energy: {topic: "zigbee2mqtt/{id}", pointer: /energy, scale: 60000}
+    state: {topic: "zigbee2mqtt/{id}", pointer: /state, on: "ON", off: "OFF"}
+    command: {topic: "zigbee2mqtt/{id}/set", on: '{"state": "ON"}', off: '{"state": "OFF"}'}
+    availability: {topic: "zigbee2mqtt/{id}/availability", pointer: /state, on: online}
+  esphome-meter:
+    kind: meter
+    phases: 2
+    consumed: {topic: "{id}/sensor/consumed_{phase}/state", scale: 1000}
+    returned: {topic: "{id}/sensor/returned", pointer: "/phases/{phase}"}
+"#;
+
+    fn get_mappings() -> HashMap<String, DeviceMapping> {
+        let conf = YamlLoader::load_from_str(MAPPINGS).unwrap();
+        parse_mappings(&conf[0]).unwrap()
+    }
+
+    #[test]
+    fn parses_mappings() {
+        let mappings = get_mappings();
+
+        assert_eq!(get_mapped_meter_phases("esphome-meter", &mappings), Some(2));
+        assert_eq!(parse_plug_type("zigbee-plug", &mappings), Some(PlugType::Mapped(String::from("zigbee-plug"))));
+        assert_eq!(parse_plug_type("Tasmota", &mappings), Some(PlugType::Tasmota));
+        assert_eq!(parse_plug_type("esphome-meter", &mappings), None);
+
+        let conf = YamlLoader::load_from_str("mappings:\n  Tasmota: {kind: plug}").unwrap();
+        assert!(parse_mappings(&conf[0]).is_none());
+    }
+
+    #[test]
+    fn resolves_json_pointer() {
+        let value = json::parse(r#"{"a/b": {"~c": [1, 2, 3]}}"#).unwrap();
+
+        assert_eq!(get_pointed(&value, "/a~1b/~0c/2"), Some(&JsonValue::from(3)));
+        assert_eq!(get_pointed(&value, ""), Some(&value));
+        assert!(get_pointed(&value, "/a~1b/~0c/3").is_none());
+        assert!(get_pointed(&value, "a").is_none());
+    }
+
+    #[test]
+    fn mapped_plug_reads_one_message() {
+        let mappings = get_mappings();
+        let plug = MappedPlug::new("miner-plug", mappings.get("zigbee-plug").unwrap());
+
+        assert_eq!(plug.get_data_topics(), vec![String::from("zigbee2mqtt/miner-plug")]);
+        assert!(plug.get_state_topics().is_empty());
+        assert_eq!(
+            plug.parse("zigbee2mqtt/miner-plug", r#"{"power": 210.5, "energy": 1.5, "state": "ON", "linkquality": 120}"#),
+            PlugStatus { output: Some(true), power: Some(210.5), energy_wmin: Some(90000) }
+        );
+        assert_eq!(
+            plug.get_switch_command(false),
+            (String::from("zigbee2mqtt/miner-plug/set"), String::from(r#"{"state": "OFF"}"#))
+        );
+        assert_eq!(plug.get_availability_topic(), Some(String::from("zigbee2mqtt/miner-plug/availability")));
+        assert!(plug.is_online(r#"{"state": "online"}"#));
+        assert!(!plug.is_online(r#"{"state": "offline"}"#));
+    }
+
+    #[test]
+    fn mapped_meter_collects_all_phases() {
+        let mappings = get_mappings();
+        let mut meter = MappedMeter::new("meter", mappings.get("esphome-meter").unwrap());
+
+        assert_eq!(meter.get_topics(), vec![
+            String::from("meter/sensor/consumed_0/state"),
+            String::from("meter/sensor/consumed_1/state"),
+            String::from("meter/sensor/returned"),
+        ]);
+        assert!(meter.is_always_available());
+
+        assert!(meter.parse("meter/sensor/consumed_0/state", "1.0").is_none());
+        assert!(meter.parse("meter/sensor/consumed_1/state", "2.0").is_none());
+        assert!(meter.parse("meter/sensor/returned", r#"{"phases": [100, 200]}"#).is_none());
+        assert_eq!(meter.get_totals(), Some((vec![1000.0, 2000.0], vec![100.0, 200.0])));
+
+        meter.parse("meter/sensor/consumed_0/state", "1.25");
+        meter.parse("meter/sensor/consumed_1/state", "2.0");
+        assert_eq!(
+            meter.parse("meter/sensor/returned", r#"{"phases": [100, 200.5]}"#),
+            Some((vec![15000, 0], vec![0, 30], vec![1250.0, 2000.0], vec![100.0, 200.5]))
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc, time::Duration};
+
+use super::structs::{MeterType, PlugStatus, PlugType};
+
+pub mod cgminer;
+pub mod fronius;
+pub mod mapping;
+pub mod modbus;
+pub mod p1;
+pub mod shelly;
+pub mod sma;
+pub mod tasmota;
+
+use mapping::DeviceMapping;
+
+/* Energy reading of all phases as (consumed Wmin, returned Wmin, total consumed Wh, total returned Wh) */
+pub type MeterReading = (Vec<u64>, Vec<u64>, Vec<f64>, Vec<f64>);
+
+/* Energy meter of switchboard, it collects messages until reading of all phases is complete */
+pub trait MeterSource: Send {
+    fn get_topics(&self) -> Vec<String>;
+    fn parse(&mut self, topic: &str, payload: &str) -> Option<MeterReading>;
+    /* Last complete total energies, used to obtain initial state of meter */
+    fn get_totals(&self) -> Option<(Vec<f64>, Vec<f64>)>;
+
+    /* Topic telling whether device is online, None when device announces itself */
+    fn get_availability_topic(&self) -> Option<String> { None }
+    fn is_online(&self, _payload: &str) -> bool { true }
+    /* Devices which neither announce nor report availability are assumed to be available */
+    fn is_always_available(&self) -> bool { false }
+}
+
+/* Switchboard meter read outside of MQTT by its own worker loop */
+pub trait PolledMeter: Send {
+    /* Blocks until next reading, None is returned until energy of interval is known */
+    fn poll(&mut self) -> Result<Option<MeterReading>, String>;
+    /* Total consumed and returned Wh of all phases, used to obtain initial state of meter */
+    fn read_totals(&mut self) -> Result<(Vec<f64>, Vec<f64>), String>;
+    /* Pause between polls */
+    fn get_interval(&self) -> Duration;
+}
+
+/* Smart plug of miner, driver is bound to one plug and keeps no state */
+pub trait PlugDriver: Debug + Send + Sync {
+    /* Topics with power and energy readings, unsubscribed when miner is excluded */
+    fn get_data_topics(&self) -> Vec<String>;
+    /* Topics with relay state only */
+    fn get_state_topics(&self) -> Vec<String> { vec![] }
+    fn parse(&self, topic: &str, payload: &str) -> PlugStatus;
+    /* Returns (topic, payload) of relay command */
+    fn get_switch_command(&self, on: bool) -> (String, String);
+    /* Command asking for relay state, None when plug publishes it on its own */
+    fn get_state_query(&self) -> Option<(String, String)> { None }
+
+    fn get_availability_topic(&self) -> Option<String> { None }
+    fn is_online(&self, _payload: &str) -> bool { true }
+    fn is_always_available(&self) -> bool { false }
+
+    /* Longest expected gap between energy readings, energy after longer gap is not accounted */
+    fn get_report_interval(&self) -> Duration { Duration::from_secs(90) }
+}
+
+/* Returns None for meters which are polled instead of publishing to MQTT */
+pub fn get_meter_source(meter_type: &MeterType, id: &str, mappings: &HashMap<String, DeviceMapping>) -> Option<Box<dyn MeterSource>> {
+    match meter_type {
+        MeterType::Shelly(shelly_type) if shelly_type.uses_rpc() => {
+            Some(Box::new(shelly::RpcMeter::new(id, shelly_type.get_phase_count())))
+        },
+        MeterType::Shelly(shelly_type) => Some(Box::new(shelly::Gen1Meter::new(id, shelly_type.get_phase_count()))),
+        MeterType::Mapped(name, _) => Some(Box::new(mapping::MappedMeter::new(id, mappings.get(name).unwrap()))),
+        MeterType::Modbus(_) | MeterType::P1(_) | MeterType::Sma(_) => None,
+    }
+}
+
+pub fn get_polled_meter(meter_type: &MeterType) -> Option<Box<dyn PolledMeter>> {
+    match meter_type {
+        MeterType::Modbus(config) => Some(Box::new(modbus::ModbusMeter::new(config))),
+        MeterType::P1(config) => Some(Box::new(p1::P1Meter::new(config))),
+        MeterType::Sma(config) => Some(Box::new(sma::SmaMeter::new(config))),
+        _ => None,
+    }
+}
+
+pub fn get_plug_driver(plug_type: &PlugType, id: &str, mappings: &HashMap<String, DeviceMapping>) -> Arc<dyn PlugDriver> {
+    match plug_type {
+        PlugType::Shelly(shelly_type) if shelly_type.uses_rpc() => Arc::new(shelly::RpcPlug::new(id)),
+        PlugType::Shelly(_) => Arc::new(shelly::Gen1Plug::new(id)),
+        PlugType::Tasmota => Arc::new(tasmota::TasmotaPlug::new(id)),
+        PlugType::Mapped(name) => Arc::new(mapping::MappedPlug::new(id, mappings.get(name).unwrap())),
+    }
+}
+
+/* Meters publishing only total counters, energy of interval is difference to last totals.
+First totals are not reported because there is nothing to compare with. */
+fn get_delta_reading(last_totals: &mut Option<(Vec<f64>, Vec<f64>)>, consumed_wh: Vec<f64>, returned_wh: Vec<f64>) -> Option<MeterReading> {
+    fn get_delta_wmin(actual_wh: &Vec<f64>, last_wh: &Vec<f64>) -> Vec<u64> {
+        actual_wh.iter().zip(last_wh.iter())
+            .map(|(actual, last)| ((actual - last) * 60.0).max(0.0).round() as u64)
+            .collect()
+    }
+
+    let reading = last_totals.as_ref().map(|(last_consumed_wh, last_returned_wh)| (
+        get_delta_wmin(&consumed_wh, last_consumed_wh),
+        get_delta_wmin(&returned_wh, last_returned_wh),
+        consumed_wh.clone(),
+        returned_wh.clone(),
+    ));
+
+    *last_totals = Some((consumed_wh, returned_wh));
+    reading
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_reading_starts_after_first_totals() {
+        let mut last_totals = None;
+
+        assert!(get_delta_reading(&mut last_totals, vec![100.0, 200.0], vec![10.0, 20.0]).is_none());
+        assert_eq!(
+            get_delta_reading(&mut last_totals, vec![100.5, 200.0], vec![12.0, 19.0]),
+            Some((vec![30, 0], vec![120, 0], vec![100.5, 200.0], vec![12.0, 19.0]))
+        );
+    }
+}
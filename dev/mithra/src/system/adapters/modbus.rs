@@ -0,0 +1,306 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use super::{MeterReading, PolledMeter, get_delta_reading};
+use crate::system::structs::{ModbusConfig, ModbusModel};
+
+/* Modbus TCP meter is not connected to MQTT, it is polled by its own worker loop.
+Eastron SDM630 keeps per phase import and export energy in input registers as float32 kWh.
+SunSpec devices keep holding registers starting with "SunS" marker at 40000 followed by models,
+every model starts with its id and length. Meter models 201 - 204 share the same layout with
+per phase exported and imported energy as acc32 Wh scaled by TotWh_SF. */
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const SDM630_PHASE_IMPORT: u16 = 0x015A;
+const SDM630_PHASE_EXPORT: u16 = 0x0160;
+
+const SUNSPEC_BASE: u16 = 40000;
+const SUNSPEC_MARKER: [u16; 2] = [0x5375, 0x6E53];
+const SUNSPEC_END: u16 = 0xFFFF;
+const SUNSPEC_METERS: std::ops::RangeInclusive<u16> = 201..=204;
+/* Offsets in meter model behind its id and length */
+const SUNSPEC_EXPORTED_PHASE: u16 = 38;
+const SUNSPEC_IMPORTED_PHASE: u16 = 46;
+const SUNSPEC_ENERGY_SF: u16 = 52;
+
+pub struct ModbusClient {
+    stream: TcpStream,
+    unit: u8,
+    transaction: u16,
+}
+
+impl ModbusClient {
+    pub fn connect(address: &str, unit: u8) -> Result<Self, String> {
+        let address = address.to_socket_addrs()
+            .map_err(|error| error.to_string())?
+            .next()
+            .ok_or(format!("Address {} is not resolved", address))?;
+
+        let stream = TcpStream::connect_timeout(&address, TIMEOUT).map_err(|error| error.to_string())?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(|error| error.to_string())?;
+        stream.set_write_timeout(Some(TIMEOUT)).map_err(|error| error.to_string())?;
+
+        Ok(ModbusClient { stream, unit, transaction: 0 })
+    }
+
+    pub fn read_registers(&mut self, function: u8, address: u16, count: u16) -> Result<Vec<u16>, String> {
+        self.transaction = self.transaction.wrapping_add(1);
+
+        /* MBAP header is followed by PDU, length counts unit id and PDU */
+        let mut request = vec![];
+        request.extend_from_slice(&self.transaction.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&6u16.to_be_bytes());
+        request.push(self.unit);
+        request.push(function);
+        request.extend_from_slice(&address.to_be_bytes());
+        request.extend_from_slice(&count.to_be_bytes());
+        self.stream.write_all(&request).map_err(|error| error.to_string())?;
+
+        let mut header = [0u8; 7];
+        self.stream.read_exact(&mut header).map_err(|error| error.to_string())?;
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if u16::from_be_bytes([header[0], header[1]]) != self.transaction || length < 2 {
+            return Err(String::from("Malformed response header"));
+        }
+
+        let mut pdu = vec![0u8; length - 1];
+        self.stream.read_exact(&mut pdu).map_err(|error| error.to_string())?;
+
+        if pdu[0] == function | 0x80 {
+            return Err(format!("Exception code {}", pdu.get(1).unwrap_or(&0)));
+        }
+        if pdu[0] != function || pdu.len() < 2 || pdu[1] as usize != 2 * count as usize || pdu.len() != 2 + pdu[1] as usize {
+            return Err(String::from("Malformed response"));
+        }
+
+        Ok(pdu[2..].chunks(2).map(|word| u16::from_be_bytes([word[0], word[1]])).collect())
+    }
+}
+
+/* Multi register values are stored with high word first */
+fn get_f32(registers: &[u16], i: usize) -> f32 {
+    f32::from_bits((registers[i] as u32) << 16 | registers[i + 1] as u32)
+}
+
+fn get_u32(registers: &[u16], i: usize) -> u32 {
+    (registers[i] as u32) << 16 | registers[i + 1] as u32
+}
+
+pub struct ModbusMeter {
+    config: ModbusConfig,
+    client: Option<ModbusClient>,
+    /* Address of SunSpec meter model id */
+    sunspec_model: Option<u16>,
+    last_totals: Option<(Vec<f64>, Vec<f64>)>,
+}
+
+impl ModbusMeter {
+    pub fn new(config: &ModbusConfig) -> Self {
+        ModbusMeter {
+            config: config.clone(),
+            client: None,
+            sunspec_model: None,
+            last_totals: None,
+        }
+    }
+
+    fn read_sdm630_totals(&mut self) -> Result<(Vec<f64>, Vec<f64>), String> {
+        let phases = self.config.phases;
+        let client = self.client.as_mut().unwrap();
+        let registers = client.read_registers(
+            READ_INPUT_REGISTERS,
+            SDM630_PHASE_IMPORT,
+            SDM630_PHASE_EXPORT - SDM630_PHASE_IMPORT + 6
+        )?;
+
+        let offset = (SDM630_PHASE_EXPORT - SDM630_PHASE_IMPORT) as usize;
+        Ok((
+            (0..phases).map(|i| get_f32(&registers, 2 * i) as f64 * 1000.0).collect(),
+            (0..phases).map(|i| get_f32(&registers, offset + 2 * i) as f64 * 1000.0).collect(),
+        ))
+    }
+
+    fn read_sunspec_totals(&mut self) -> Result<(Vec<f64>, Vec<f64>), String> {
+        if self.sunspec_model.is_none() {
+            self.sunspec_model = Some(find_sunspec_meter(self.client.as_mut().unwrap())?);
+        }
+
+        let phases = self.config.phases;
+        let client = self.client.as_mut().unwrap();
+        let registers = client.read_registers(
+            READ_HOLDING_REGISTERS,
+            self.sunspec_model.unwrap() + 2,
+            SUNSPEC_ENERGY_SF + 1
+        )?;
+
+        let scale = 10f64.powi(registers[SUNSPEC_ENERGY_SF as usize] as i16 as i32);
+        let get_energy = |start: u16| -> Vec<f64> {
+            (0..phases).map(|i| get_u32(&registers, start as usize + 2 * i) as f64 * scale).collect()
+        };
+
+        /* Meter imports energy consumed from grid and exports energy returned to grid */
+        Ok((get_energy(SUNSPEC_IMPORTED_PHASE), get_energy(SUNSPEC_EXPORTED_PHASE)))
+    }
+}
+
+impl PolledMeter for ModbusMeter {
+    /* Energy of interval is known since second successful reading */
+    fn poll(&mut self) -> Result<Option<MeterReading>, String> {
+        let (consumed_wh, returned_wh) = self.read_totals()?;
+        Ok(get_delta_reading(&mut self.last_totals, consumed_wh, returned_wh))
+    }
+
+    /* Connection is renewed after failure */
+    fn read_totals(&mut self) -> Result<(Vec<f64>, Vec<f64>), String> {
+        if self.client.is_none() {
+            self.client = Some(ModbusClient::connect(&self.config.address, self.config.unit)?);
+        }
+
+        let totals = match self.config.model {
+            ModbusModel::SDM630 => self.read_sdm630_totals(),
+            ModbusModel::SunSpec => self.read_sunspec_totals(),
+        };
+
+        if totals.is_err() {
+            self.client = None;
+        }
+        totals
+    }
+
+    fn get_interval(&self) -> Duration {
+        Duration::from_secs(self.config.interval)
+    }
+}
+
+fn find_sunspec_meter(client: &mut ModbusClient) -> Result<u16, String> {
+    if client.read_registers(READ_HOLDING_REGISTERS, SUNSPEC_BASE, 2)? != SUNSPEC_MARKER {
+        return Err(String::from("SunSpec marker not found"));
+    }
+
+    let mut address = SUNSPEC_BASE + 2;
+    loop {
+        let header = client.read_registers(READ_HOLDING_REGISTERS, address, 2)?;
+        if header[0] == SUNSPEC_END {
+            return Err(String::from("SunSpec meter model not found"));
+        }
+        if SUNSPEC_METERS.contains(&header[0]) {
+            return Ok(address);
+        }
+
+        address = address.checked_add(2 + header[1]).ok_or(String::from("SunSpec models overflow"))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, net::TcpListener, thread};
+
+    /* Stand-in of Modbus server serving registers of one unit, it answers until client disconnects */
+    fn spawn_server(unit: u8, function: u8, registers: HashMap<u16, u16>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 12];
+            while stream.read_exact(&mut request).is_ok() {
+                let start = u16::from_be_bytes([request[8], request[9]]);
+                let count = u16::from_be_bytes([request[10], request[11]]);
+
+                let values: Option<Vec<u16>> = (start..start + count).map(|i| registers.get(&i).copied()).collect();
+                let pdu = match values {
+                    Some(values) if request[6] == unit && request[7] == function => {
+                        let mut pdu = vec![function, (2 * count) as u8];
+                        values.iter().for_each(|value| pdu.extend_from_slice(&value.to_be_bytes()));
+                        pdu
+                    },
+                    _ => vec![request[7] | 0x80, 0x02],
+                };
+
+                let mut response = request[0..4].to_vec();
+                response.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+                response.push(unit);
+                response.extend_from_slice(&pdu);
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        address
+    }
+
+    fn insert_f32(registers: &mut HashMap<u16, u16>, address: u16, value: f32) {
+        registers.insert(address, (value.to_bits() >> 16) as u16);
+        registers.insert(address + 1, value.to_bits() as u16);
+    }
+
+    fn get_config(model: ModbusModel, address: String) -> ModbusConfig {
+        ModbusConfig { model, address, unit: 1, phases: 3, interval: 30 }
+    }
+
+    #[test]
+    fn sdm630_reading() {
+        let mut registers = HashMap::new();
+        for (i, (imported, exported)) in [(1.5, 0.25), (2.0, 0.0), (0.125, 4.0)].iter().enumerate() {
+            insert_f32(&mut registers, SDM630_PHASE_IMPORT + 2 * i as u16, *imported);
+            insert_f32(&mut registers, SDM630_PHASE_EXPORT + 2 * i as u16, *exported);
+        }
+
+        let address = spawn_server(1, READ_INPUT_REGISTERS, registers);
+        let mut meter = ModbusMeter::new(&get_config(ModbusModel::SDM630, address));
+
+        assert_eq!(meter.read_totals(), Ok((vec![1500.0, 2000.0, 125.0], vec![250.0, 0.0, 4000.0])));
+        assert_eq!(meter.poll(), Ok(None));
+        assert_eq!(
+            meter.poll(),
+            Ok(Some((vec![0, 0, 0], vec![0, 0, 0], vec![1500.0, 2000.0, 125.0], vec![250.0, 0.0, 4000.0])))
+        );
+    }
+
+    #[test]
+    fn sunspec_meter_is_found_behind_other_models() {
+        let mut registers = HashMap::new();
+        registers.insert(40000, SUNSPEC_MARKER[0]);
+        registers.insert(40001, SUNSPEC_MARKER[1]);
+        /* Common model 1 with length 66 */
+        registers.insert(40002, 1);
+        registers.insert(40003, 66);
+        /* Wye-connect meter model 203 */
+        let model = 40004 + 66;
+        registers.insert(model, 203);
+        registers.insert(model + 1, 105);
+        for i in 0..105 {
+            registers.insert(model + 2 + i, 0);
+        }
+        for i in 0..3 {
+            registers.insert(model + 2 + SUNSPEC_EXPORTED_PHASE + 2 * i + 1, 10 * (i + 1));
+            registers.insert(model + 2 + SUNSPEC_IMPORTED_PHASE + 2 * i, 1);
+            registers.insert(model + 2 + SUNSPEC_IMPORTED_PHASE + 2 * i + 1, i);
+        }
+        /* Energy is kept in tens of Wh */
+        registers.insert(model + 2 + SUNSPEC_ENERGY_SF, 1);
+        registers.insert(model + 2 + 105, SUNSPEC_END);
+
+        let address = spawn_server(1, READ_HOLDING_REGISTERS, registers);
+        let mut meter = ModbusMeter::new(&get_config(ModbusModel::SunSpec, address));
+
+        assert_eq!(meter.read_totals(), Ok((vec![655360.0, 655370.0, 655380.0], vec![100.0, 200.0, 300.0])));
+    }
+
+    #[test]
+    fn exception_drops_connection() {
+        let address = spawn_server(1, READ_INPUT_REGISTERS, HashMap::new());
+        let mut meter = ModbusMeter::new(&get_config(ModbusModel::SDM630, address));
+
+        assert_eq!(meter.read_totals(), Err(String::from("Exception code 2")));
+        assert!(meter.client.is_none());
+    }
+}
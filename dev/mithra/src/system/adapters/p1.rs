@@ -0,0 +1,360 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use super::{MeterReading, PolledMeter, get_delta_reading};
+use crate::system::structs::P1Config;
+
+/* DSMR telegram starts with "/" and identification of meter, then every line holds one OBIS
+object like 1-0:1.8.1(001234.567*kWh), and ends with "!" followed by CRC16 of whole telegram
+including "!". Meters older than DSMR 4 send no CRC. Energy delivered to client (1.8.x) and
+delivered by client (2.8.x) is counted separately for every tariff. Energy is not counted per
+phase, only actual power of phases L1, L2, L3 is sent, like 1-0:21.7.0(00.303*kW).
+Telegram is sent every second or every ten seconds by older meters, so readings are reported
+once per interval. Serial port must be configured in advance, like `stty -F /dev/ttyUSB0 115200 raw`. */
+
+const DELIVERED_TO_CLIENT: &str = "1-0:1.8.";
+const DELIVERED_BY_CLIENT: &str = "1-0:2.8.";
+/* Power delivered to and by client of phases L1, L2, L3 */
+const PHASE_POWER: [(&str, &str); 3] = [
+    ("1-0:21.7.0", "1-0:22.7.0"),
+    ("1-0:41.7.0", "1-0:42.7.0"),
+    ("1-0:61.7.0", "1-0:62.7.0"),
+];
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/* CRC-16/ARC, polynomial x^16 + x^15 + x^2 + 1 */
+fn get_crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/* Returns energy in Wh of object value like 001234.567*kWh */
+fn parse_energy(value: &str) -> Option<f64> {
+    let (number, unit) = value.split_once('*')?;
+    let number = number.parse::<f64>().ok()?;
+    match unit {
+        "kWh" => Some(number * 1000.0),
+        "Wh" => Some(number),
+        _ => None,
+    }
+}
+
+/* Returns power in W of object value like 00.303*kW */
+fn parse_power(value: &str) -> Option<f64> {
+    let (number, unit) = value.split_once('*')?;
+    let number = number.parse::<f64>().ok()?;
+    match unit {
+        "kW" => Some(number * 1000.0),
+        "W" => Some(number),
+        _ => None,
+    }
+}
+
+/* Returns (delivered to client Wh, delivered by client Wh) summed over tariffs and (delivered to client W,
+delivered by client W) of phases L1, L2, L3. Total register x.8.0 is used only by meters without tariff
+registers, power of phases is zero for meters which do not send it. */
+pub fn parse_telegram(telegram: &str) -> Result<(f64, f64, Vec<(f64, f64)>), String> {
+    if !telegram.starts_with('/') {
+        return Err(String::from("Telegram header is missing"));
+    }
+    let end = telegram.find('!').ok_or(String::from("Telegram end is missing"))?;
+
+    let crc = telegram[end + 1..].trim();
+    if !crc.is_empty() {
+        let expected = u16::from_str_radix(crc, 16).map_err(|_| format!("Malformed CRC {}", crc))?;
+        if get_crc16(telegram[..=end].as_bytes()) != expected {
+            return Err(String::from("CRC mismatch"));
+        }
+    }
+
+    let mut delivered = (vec![], None);
+    let mut returned = (vec![], None);
+    let mut phase_power = vec![(0.0, 0.0); PHASE_POWER.len()];
+    for line in telegram[..end].lines() {
+        let (code, value) = if let Some((code, value)) = line.split_once('(') {
+            (code, value.trim_end_matches(')'))
+        } else {
+            continue;
+        };
+
+        for (prefix, registers) in [(DELIVERED_TO_CLIENT, &mut delivered), (DELIVERED_BY_CLIENT, &mut returned)] {
+            if let Some(tariff) = code.strip_prefix(prefix) {
+                let energy = parse_energy(value).ok_or(format!("Malformed value of {}", code))?;
+                if tariff == "0" {
+                    registers.1 = Some(energy);
+                } else {
+                    registers.0.push(energy);
+                }
+            }
+        }
+
+        for (i, (to_client, by_client)) in PHASE_POWER.iter().enumerate() {
+            if code == *to_client || code == *by_client {
+                let power = parse_power(value).ok_or(format!("Malformed value of {}", code))?;
+                if code == *to_client {
+                    phase_power[i].0 = power;
+                } else {
+                    phase_power[i].1 = power;
+                }
+            }
+        }
+    }
+
+    let get_total = |(tariffs, total): (Vec<f64>, Option<f64>)| -> Result<f64, String> {
+        match (tariffs.is_empty(), total) {
+            (false, _) => Ok(tariffs.iter().sum()),
+            (true, Some(total)) => Ok(total),
+            (true, None) => Err(String::from("Energy registers are missing")),
+        }
+    };
+
+    Ok((get_total(delivered)?, get_total(returned)?, phase_power))
+}
+
+/* Energy is split over phases in proportion to their power, evenly when no power flows */
+fn split_over_phases(energy: f64, powers: &[f64]) -> Vec<f64> {
+    let sum = powers.iter().sum::<f64>();
+    powers.iter()
+        .map(|power| if sum > 0.0 { energy * power / sum } else { energy / powers.len() as f64 })
+        .collect()
+}
+
+pub struct P1Meter {
+    config: P1Config,
+    reader: Option<BufReader<Box<dyn Read + Send>>>,
+    /* Totals of meter and totals of phases accumulated from their shares since start */
+    meter_totals: Option<(f64, f64)>,
+    phase_totals: (Vec<f64>, Vec<f64>),
+    last_totals: Option<(Vec<f64>, Vec<f64>)>,
+    last_reading: Instant,
+}
+
+impl P1Meter {
+    pub fn new(config: &P1Config) -> Self {
+        P1Meter {
+            config: config.clone(),
+            reader: None,
+            meter_totals: None,
+            phase_totals: (vec![0.0; config.phases], vec![0.0; config.phases]),
+            last_totals: None,
+            last_reading: Instant::now(),
+        }
+    }
+
+    /* Totals known at start are split evenly, every following delta by power of phases in telegram */
+    fn add_telegram(&mut self, (delivered, returned, phase_power): (f64, f64, Vec<(f64, f64)>)) {
+        let phases = self.config.phases;
+        let (delivered_shares, returned_shares) = match self.meter_totals {
+            Some((last_delivered, last_returned)) => (
+                split_over_phases(
+                    (delivered - last_delivered).max(0.0),
+                    &phase_power.iter().take(phases).map(|(to_client, _)| *to_client).collect::<Vec<f64>>()
+                ),
+                split_over_phases(
+                    (returned - last_returned).max(0.0),
+                    &phase_power.iter().take(phases).map(|(_, by_client)| *by_client).collect::<Vec<f64>>()
+                ),
+            ),
+            None => (split_over_phases(delivered, &vec![0.0; phases]), split_over_phases(returned, &vec![0.0; phases])),
+        };
+
+        for i in 0..phases {
+            self.phase_totals.0[i] += delivered_shares[i];
+            self.phase_totals.1[i] += returned_shares[i];
+        }
+        self.meter_totals = Some((delivered, returned));
+    }
+
+    /* Serial device is given by path, TCP bridge by address */
+    fn open(&self) -> Result<Box<dyn Read + Send>, String> {
+        if self.config.source.starts_with('/') {
+            let file = File::open(&self.config.source).map_err(|error| error.to_string())?;
+            Ok(Box::new(file))
+        } else {
+            let stream = TcpStream::connect(&self.config.source).map_err(|error| error.to_string())?;
+            stream.set_read_timeout(Some(TIMEOUT)).map_err(|error| error.to_string())?;
+            Ok(Box::new(stream))
+        }
+    }
+
+    /* Lines before header are skipped, because reading may start in the middle of telegram */
+    fn read_telegram(&mut self) -> Result<String, String> {
+        let reader = self.reader.as_mut().unwrap();
+        let mut telegram = String::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).map_err(|error| error.to_string())? == 0 {
+                return Err(String::from("P1 port is closed"));
+            }
+
+            if line.starts_with('/') {
+                telegram.clear();
+            } else if telegram.is_empty() {
+                continue;
+            }
+
+            telegram.push_str(&line);
+            if line.starts_with('!') {
+                return Ok(telegram);
+            }
+        }
+    }
+}
+
+impl PolledMeter for P1Meter {
+    /* Every telegram is read so port buffer does not hold outdated ones */
+    fn poll(&mut self) -> Result<Option<MeterReading>, String> {
+        let interval = Duration::from_secs(self.config.interval);
+        loop {
+            let totals = self.read_totals()?;
+            let now = Instant::now();
+            if self.last_totals.is_none() || now - self.last_reading >= interval {
+                self.last_reading = now;
+                return Ok(get_delta_reading(&mut self.last_totals, totals.0, totals.1));
+            }
+        }
+    }
+
+    /* Port is reopened after failure */
+    fn read_totals(&mut self) -> Result<(Vec<f64>, Vec<f64>), String> {
+        if self.reader.is_none() {
+            self.reader = Some(BufReader::new(self.open()?));
+        }
+
+        let telegram = self.read_telegram().and_then(|telegram| parse_telegram(&telegram));
+        match telegram {
+            Ok(telegram) => {
+                self.add_telegram(telegram);
+                Ok(self.phase_totals.clone())
+            },
+            Err(error_msg) => {
+                self.reader = None;
+                Err(error_msg)
+            },
+        }
+    }
+
+    /* Polling waits for telegrams itself */
+    fn get_interval(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, net::TcpListener, thread};
+
+    fn get_telegram(delivered: [&str; 2], returned: [&str; 2]) -> String {
+        let telegram = format!(
+            "/ISk5\\2MT382-1000\r\n\r\n\
+            1-3:0.2.8(50)\r\n\
+            0-0:1.0.0(101209113020W)\r\n\
+            1-0:1.8.1({}*kWh)\r\n\
+            1-0:1.8.2({}*kWh)\r\n\
+            1-0:2.8.1({}*kWh)\r\n\
+            1-0:2.8.2({}*kWh)\r\n\
+            0-0:96.14.0(0002)\r\n\
+            1-0:1.7.0(01.193*kW)\r\n\
+            1-0:21.7.0(00.303*kW)\r\n\
+            1-0:22.7.0(00.000*kW)\r\n\
+            !",
+            delivered[0], delivered[1], returned[0], returned[1]
+        );
+        let crc = get_crc16(telegram.as_bytes());
+        format!("{}{:04X}\r\n", telegram, crc)
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(get_crc16(b"123456789"), 0xBB3D);
+    }
+
+    #[test]
+    fn telegram_tariffs_are_summed() {
+        let telegram = get_telegram(["001234.500", "000100.250"], ["000050.500", "000000.250"]);
+        assert_eq!(parse_telegram(&telegram), Ok((1334750.0, 50750.0, vec![(303.0, 0.0), (0.0, 0.0), (0.0, 0.0)])));
+
+        let corrupted = telegram.replace("000050.500", "000051.500");
+        assert_eq!(parse_telegram(&corrupted), Err(String::from("CRC mismatch")));
+
+        /* DSMR 2.2 telegram without CRC and with total registers */
+        let telegram = "/KFM5KAIFA-METER\r\n\r\n1-0:1.8.0(00012.5*kWh)\r\n1-0:2.8.0(00001*kWh)\r\n!\r\n";
+        assert_eq!(parse_telegram(telegram), Ok((12500.0, 1000.0, vec![(0.0, 0.0); 3])));
+    }
+
+    #[test]
+    fn meter_reads_from_tcp_bridge() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let source = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            /* Connection starts in the middle of telegram */
+            stream.write_all(b"1-0:2.8.2(000000.250*kWh)\r\n!1234\r\n").unwrap();
+            for delivered in ["000001.000", "000001.125", "000001.250"] {
+                stream.write_all(get_telegram([delivered, "000001.000"], ["000000.000", "000000.500"]).as_bytes()).unwrap();
+            }
+        });
+
+        let mut meter = P1Meter::new(&P1Config { source, phases: 1, interval: 0 });
+        assert_eq!(meter.read_totals(), Ok((vec![2000.0], vec![500.0])));
+        assert_eq!(meter.poll(), Ok(None));
+        assert_eq!(meter.poll(), Ok(Some((vec![7500], vec![0], vec![2250.0], vec![500.0]))));
+    }
+
+    #[test]
+    fn energy_is_split_over_phases_by_power() {
+        let get_telegram = |delivered: &str, returned: &str| {
+            let telegram = format!(
+                "/ISk5\\2MT382-1000\r\n\r\n\
+                1-0:1.8.1({}*kWh)\r\n\
+                1-0:2.8.1({}*kWh)\r\n\
+                1-0:21.7.0(01.000*kW)\r\n\
+                1-0:41.7.0(03.000*kW)\r\n\
+                1-0:61.7.0(00.000*kW)\r\n\
+                1-0:22.7.0(00.000*kW)\r\n\
+                1-0:42.7.0(00.000*kW)\r\n\
+                1-0:62.7.0(02.000*kW)\r\n\
+                !",
+                delivered, returned
+            );
+            let crc = get_crc16(telegram.as_bytes());
+            format!("{}{:04X}\r\n", telegram, crc)
+        };
+
+        assert_eq!(
+            parse_telegram(&get_telegram("000003.000", "000000.300")),
+            Ok((3000.0, 300.0, vec![(1000.0, 0.0), (3000.0, 0.0), (0.0, 2000.0)]))
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let source = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for (delivered, returned) in [("000003.000", "000000.300"), ("000003.004", "000000.302")] {
+                stream.write_all(get_telegram(delivered, returned).as_bytes()).unwrap();
+            }
+        });
+
+        /* Totals at start are split evenly, 4 Wh delivered and 2 Wh returned then follow power of phases */
+        let mut meter = P1Meter::new(&P1Config { source, phases: 3, interval: 0 });
+        assert_eq!(meter.poll(), Ok(None));
+        assert_eq!(
+            meter.poll(),
+            Ok(Some((vec![60, 180, 0], vec![0, 0, 120], vec![1001.0, 1003.0, 1000.0], vec![100.0, 100.0, 102.0])))
+        );
+    }
+}
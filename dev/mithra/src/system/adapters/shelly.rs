@@ -0,0 +1,397 @@
+use json::JsonValue;
+use sscanf::scanf;
+
+use super::{MeterReading, MeterSource, PlugDriver, get_delta_reading};
+use crate::system::structs::PlugStatus;
+
+/* Gen2 and newer Shelly devices speak JSON-RPC over MQTT.
+Requests are sent to <id>/rpc and responses are published to <src>/rpc.
+With generic status update enabled device publishes <id>/status/<component>
+and notifications about changes to <id>/events/rpc. */
+
+pub static ANNOUNCE_SRC: &str = "mithra/announce";
+pub static INITIAL_SRC: &str = "mithra/initial";
+pub static PLUGS_SRC: &str = "mithra/plugs";
+
+pub static EMDATA_COMPONENT: &str = "emdata:0";
+pub static SWITCH_COMPONENT: &str = "switch:0";
+
+/* Energy meter phases are named by letters */
+static PHASE_PREFIXES: [&str; 3] = ["a", "b", "c"];
+
+pub fn get_request_topic(device_id: &str) -> String {
+    format!("{}/rpc", device_id)
+}
+
+pub fn get_response_topic(src: &str) -> String {
+    format!("{}/rpc", src)
+}
+
+pub fn get_status_topic(device_id: &str, component: &str) -> String {
+    format!("{}/status/{}", device_id, component)
+}
+
+pub fn get_events_topic(device_id: &str) -> String {
+    format!("{}/events/rpc", device_id)
+}
+
+pub fn get_request(src: &str, method: &str, params: JsonValue) -> String {
+    let mut request = JsonValue::new_object();
+    request["id"] = 0.into();
+    request["src"] = src.into();
+    request["method"] = method.into();
+    if !params.is_null() {
+        request["params"] = params;
+    }
+    request.dump()
+}
+
+pub fn get_switch_request(on: bool) -> String {
+    let mut params = JsonValue::new_object();
+    params["id"] = 0.into();
+    params["on"] = on.into();
+    get_request(PLUGS_SRC, "Switch.Set", params)
+}
+
+/* Returns (device id, application name) from Shelly.GetDeviceInfo response */
+pub fn parse_device_info(response: &JsonValue) -> Option<(String, String)> {
+    let id = response["result"]["id"].as_str()?;
+    let app = response["result"]["app"].as_str()?;
+    Some((String::from(id), String::from(app)))
+}
+
+/* Status of component carried by NotifyStatus or NotifyFullStatus notification */
+pub fn get_notified_status<'a>(notification: &'a JsonValue, component: &str) -> Option<&'a JsonValue> {
+    match notification["method"].as_str() {
+        Some("NotifyStatus") | Some("NotifyFullStatus") => {},
+        _ => return None,
+    }
+
+    let status = &notification["params"][component];
+    if status.is_object() {
+        Some(status)
+    } else {
+        None
+    }
+}
+
+/* Returns total consumed and returned energy in Wh for every phase */
+pub fn parse_emdata(status: &JsonValue, phases: usize) -> Option<(Vec<f64>, Vec<f64>)> {
+    let mut consumed = vec![];
+    let mut returned = vec![];
+
+    for prefix in PHASE_PREFIXES.iter().take(phases) {
+        consumed.push(status[format!("{}_total_act_energy", prefix).as_str()].as_f64()?);
+        returned.push(status[format!("{}_total_act_ret_energy", prefix).as_str()].as_f64()?);
+    }
+
+    Some((consumed, returned))
+}
+
+/* Notifications contain only changed fields, total energy in Wh is converted to Wmin */
+pub fn parse_switch_status(status: &JsonValue) -> PlugStatus {
+    PlugStatus {
+        output: status["output"].as_bool(),
+        power: status["apower"].as_f32(),
+        energy_wmin: status["aenergy"]["total"].as_f64().map(|total| (total * 60.0).round() as u64),
+    }
+}
+
+/* Gen1 energy meter publishes energy of last interval and totals for every channel,
+totals are collected only after energy of all phases */
+pub struct Gen1Meter {
+    id: String,
+    energy_consumed_wmin: Vec<Option<u64>>,
+    energy_returned_wmin: Vec<Option<u64>>,
+    total_consumed_wh: Vec<Option<f64>>,
+    total_returned_wh: Vec<Option<f64>>,
+    last_totals: Option<(Vec<f64>, Vec<f64>)>,
+}
+
+impl Gen1Meter {
+    pub fn new(id: &str, phases: usize) -> Self {
+        Gen1Meter {
+            id: String::from(id),
+            energy_consumed_wmin: vec![None; phases],
+            energy_returned_wmin: vec![None; phases],
+            total_consumed_wh: vec![None; phases],
+            total_returned_wh: vec![None; phases],
+            last_totals: None,
+        }
+    }
+}
+
+impl MeterSource for Gen1Meter {
+    fn get_topics(&self) -> Vec<String> {
+        let mut topics = vec![];
+        for i in 0..self.energy_consumed_wmin.len() {
+            topics.push(format!("shellies/{}/emeter/{}/energy", self.id, i));
+            topics.push(format!("shellies/{}/emeter/{}/returned_energy", self.id, i));
+            topics.push(format!("shellies/{}/emeter/{}/total", self.id, i));
+            topics.push(format!("shellies/{}/emeter/{}/total_returned", self.id, i));
+        }
+        topics
+    }
+
+    fn parse(&mut self, topic: &str, payload: &str) -> Option<MeterReading> {
+        fn is_collected<T>(array: &Vec<Option<T>>) -> bool {
+            array.iter().all(|x: &Option<T>| x.is_some())
+        }
+
+        fn take_array<T>(array: &mut Vec<Option<T>>) -> Vec<T> {
+            array.iter_mut().map(|x| x.take().unwrap()).collect()
+        }
+
+        let (_, i, data_type) = scanf!(topic, "shellies/{/[^/]+/}/emeter/{/[^/]+/}/{/[^/]+/}", String, usize, String)?;
+        if i >= self.energy_consumed_wmin.len() {
+            return None;
+        }
+
+        let energy_collected = is_collected(&self.energy_consumed_wmin) && is_collected(&self.energy_returned_wmin);
+
+        match data_type.as_str() {
+            "energy" => self.energy_consumed_wmin[i] = payload.parse::<u64>().ok(),
+            "returned_energy" => self.energy_returned_wmin[i] = payload.parse::<u64>().ok(),
+            "total" if energy_collected => self.total_consumed_wh[i] = payload.parse::<f64>().ok(),
+            "total_returned" if energy_collected => self.total_returned_wh[i] = payload.parse::<f64>().ok(),
+            _ => {}
+        }
+
+        if !energy_collected || !is_collected(&self.total_consumed_wh) || !is_collected(&self.total_returned_wh) {
+            return None;
+        }
+
+        let total_consumed_wh = take_array(&mut self.total_consumed_wh);
+        let total_returned_wh = take_array(&mut self.total_returned_wh);
+        self.last_totals = Some((total_consumed_wh.clone(), total_returned_wh.clone()));
+
+        Some((
+            take_array(&mut self.energy_consumed_wmin),
+            take_array(&mut self.energy_returned_wmin),
+            total_consumed_wh,
+            total_returned_wh,
+        ))
+    }
+
+    fn get_totals(&self) -> Option<(Vec<f64>, Vec<f64>)> {
+        self.last_totals.clone()
+    }
+}
+
+/* Gen2 energy meter publishes only total counters */
+pub struct RpcMeter {
+    id: String,
+    phases: usize,
+    last_totals: Option<(Vec<f64>, Vec<f64>)>,
+}
+
+impl RpcMeter {
+    pub fn new(id: &str, phases: usize) -> Self {
+        RpcMeter { id: String::from(id), phases, last_totals: None }
+    }
+}
+
+impl MeterSource for RpcMeter {
+    fn get_topics(&self) -> Vec<String> {
+        vec![get_status_topic(&self.id, EMDATA_COMPONENT)]
+    }
+
+    fn parse(&mut self, _topic: &str, payload: &str) -> Option<MeterReading> {
+        let status = json::parse(payload).ok()?;
+        let (consumed_wh, returned_wh) = parse_emdata(&status, self.phases)?;
+
+        get_delta_reading(&mut self.last_totals, consumed_wh, returned_wh)
+    }
+
+    fn get_totals(&self) -> Option<(Vec<f64>, Vec<f64>)> {
+        self.last_totals.clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct Gen1Plug {
+    id: String,
+}
+
+impl Gen1Plug {
+    pub fn new(id: &str) -> Self {
+        Gen1Plug { id: String::from(id) }
+    }
+}
+
+impl PlugDriver for Gen1Plug {
+    fn get_data_topics(&self) -> Vec<String> {
+        vec![
+            format!("shellies/{}/relay/0/power", self.id),
+            format!("shellies/{}/relay/0/energy", self.id),
+        ]
+    }
+
+    fn get_state_topics(&self) -> Vec<String> {
+        vec![format!("shellies/{}/relay/0", self.id)]
+    }
+
+    fn parse(&self, topic: &str, payload: &str) -> PlugStatus {
+        let mut status = PlugStatus::default();
+
+        match topic.strip_prefix(format!("shellies/{}/relay/", self.id).as_str()) {
+            Some("0/power") => status.power = payload.parse::<f32>().ok(),
+            Some("0/energy") => status.energy_wmin = payload.parse::<u64>().ok(),
+            Some("0") => status.output = match payload {
+                "on" => Some(true),
+                "off" => Some(false),
+                _ => None,
+            },
+            _ => {},
+        }
+
+        status
+    }
+
+    fn get_switch_command(&self, on: bool) -> (String, String) {
+        (
+            format!("shellies/{}/relay/0/command", self.id),
+            String::from(if on { "on" } else { "off" }),
+        )
+    }
+}
+
+/* Gen2 plug publishes status as whole or notifies changed fields, relay state is part of status */
+#[derive(Debug)]
+pub struct RpcPlug {
+    id: String,
+}
+
+impl RpcPlug {
+    pub fn new(id: &str) -> Self {
+        RpcPlug { id: String::from(id) }
+    }
+}
+
+impl PlugDriver for RpcPlug {
+    fn get_data_topics(&self) -> Vec<String> {
+        vec![
+            get_status_topic(&self.id, SWITCH_COMPONENT),
+            get_events_topic(&self.id),
+        ]
+    }
+
+    fn parse(&self, topic: &str, payload: &str) -> PlugStatus {
+        let message = match json::parse(payload) {
+            Ok(message) => message,
+            Err(_) => return PlugStatus::default(),
+        };
+
+        if topic == get_events_topic(&self.id) {
+            match get_notified_status(&message, SWITCH_COMPONENT) {
+                Some(status) => parse_switch_status(status),
+                None => PlugStatus::default(),
+            }
+        } else {
+            parse_switch_status(&message)
+        }
+    }
+
+    fn get_switch_command(&self, on: bool) -> (String, String) {
+        (get_request_topic(&self.id), get_switch_request(on))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_device_info_response() {
+        let response = json::parse(
+            r#"{"id": 0, "src": "shellyplusplugs-a8032ab1", "dst": "mithra/announce",
+            "result": {"id": "shellyplusplugs-a8032ab1", "mac": "A8032AB1", "model": "SNPL-00112EU", "gen": 2, "app": "PlusPlugS"}}"#
+        ).unwrap();
+
+        assert_eq!(
+            parse_device_info(&response),
+            Some((String::from("shellyplusplugs-a8032ab1"), String::from("PlusPlugS")))
+        );
+    }
+
+    #[test]
+    fn parses_switch_status_and_notification() {
+        let status = json::parse(
+            r#"{"id": 0, "source": "init", "output": true, "apower": 231.5, "voltage": 230.1, "aenergy": {"total": 1520.25}}"#
+        ).unwrap();
+
+        assert_eq!(parse_switch_status(&status), PlugStatus {
+            output: Some(true),
+            power: Some(231.5),
+            energy_wmin: Some(91215),
+        });
+
+        let notification = json::parse(
+            r#"{"src": "shellyplusplugs-a8032ab1", "method": "NotifyStatus", "params": {"ts": 1655000000.12, "switch:0": {"id": 0, "output": false}}}"#
+        ).unwrap();
+        let status = get_notified_status(&notification, SWITCH_COMPONENT).unwrap();
+
+        assert_eq!(parse_switch_status(status), PlugStatus {
+            output: Some(false),
+            ..Default::default()
+        });
+        assert!(get_notified_status(&notification, EMDATA_COMPONENT).is_none());
+    }
+
+    #[test]
+    fn parses_emdata_totals() {
+        let status = json::parse(
+            r#"{"id": 0, "a_total_act_energy": 100.5, "a_total_act_ret_energy": 10.0,
+            "b_total_act_energy": 200.0, "b_total_act_ret_energy": 20.0,
+            "c_total_act_energy": 300.0, "c_total_act_ret_energy": 30.5,
+            "total_act": 600.5, "total_act_ret": 60.5}"#
+        ).unwrap();
+
+        assert_eq!(
+            parse_emdata(&status, 3),
+            Some((vec![100.5, 200.0, 300.0], vec![10.0, 20.0, 30.5]))
+        );
+        assert!(parse_emdata(&json::parse(r#"{"id": 0}"#).unwrap(), 3).is_none());
+    }
+
+    #[test]
+    fn builds_switch_request() {
+        let request = json::parse(&get_switch_request(false)).unwrap();
+
+        assert_eq!(request["src"], PLUGS_SRC);
+        assert_eq!(request["method"], "Switch.Set");
+        assert_eq!(request["params"]["on"], false);
+    }
+
+    #[test]
+    fn gen1_meter_collects_energy_before_totals() {
+        let mut meter = Gen1Meter::new("shellyem3-0", 2);
+
+        /* Totals before energy of all phases are ignored */
+        assert!(meter.parse("shellies/shellyem3-0/emeter/0/total", "1000.5").is_none());
+        for i in 0..2 {
+            assert!(meter.parse(&format!("shellies/shellyem3-0/emeter/{}/energy", i), "30").is_none());
+            assert!(meter.parse(&format!("shellies/shellyem3-0/emeter/{}/returned_energy", i), "5").is_none());
+        }
+        assert!(meter.parse("shellies/shellyem3-0/emeter/0/total", "1001.5").is_none());
+        assert!(meter.parse("shellies/shellyem3-0/emeter/0/total_returned", "10.0").is_none());
+        assert!(meter.parse("shellies/shellyem3-0/emeter/1/total", "2000.0").is_none());
+
+        assert_eq!(
+            meter.parse("shellies/shellyem3-0/emeter/1/total_returned", "20.0"),
+            Some((vec![30, 30], vec![5, 5], vec![1001.5, 2000.0], vec![10.0, 20.0]))
+        );
+        assert_eq!(meter.get_totals(), Some((vec![1001.5, 2000.0], vec![10.0, 20.0])));
+        assert!(meter.parse("shellies/shellyem3-0/emeter/1/total_returned", "20.0").is_none());
+    }
+
+    #[test]
+    fn gen1_plug_parses_relay_topics() {
+        let plug = Gen1Plug::new("shellyplug-s-0");
+
+        assert_eq!(plug.parse("shellies/shellyplug-s-0/relay/0/power", "215.5").power, Some(215.5));
+        assert_eq!(plug.parse("shellies/shellyplug-s-0/relay/0/energy", "1200").energy_wmin, Some(1200));
+        assert_eq!(plug.parse("shellies/shellyplug-s-0/relay/0", "off").output, Some(false));
+        assert_eq!(plug.parse("shellies/shellyplug-s-1/relay/0", "off"), PlugStatus::default());
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use super::{MeterReading, PolledMeter, get_delta_reading};
+use crate::system::structs::SmaConfig;
+
+/* SMA Energy Meter and Home Manager multicast Speedwire datagram every second or faster:
+"SMA\0", tag 0x02A0 of group 1, length of data and tag 0x0010, protocol id 0x6069 of meter,
+SUSy id, serial number and ticker. Then OBIS objects follow, each with channel, index, type
+and tariff bytes and value of type size, 4 bytes for actual value and 8 bytes for counter.
+Counters of energy imported from grid (index 21, 41, 61) and exported to grid (22, 42, 62)
+of phases are in Ws. Software version is the only object of channel 0x90 with 4 bytes value. */
+
+pub const MULTICAST_ADDRESS: &str = "239.12.255.254:9522";
+
+const HEADER: &[u8] = b"SMA\0";
+const METER_PROTOCOL: u16 = 0x6069;
+const DATA_START: usize = 16;
+const OBIS_START: usize = 28;
+const VERSION_CHANNEL: u8 = 0x90;
+const COUNTER_SIZE: usize = 8;
+
+const PHASE_IMPORT: [u8; 3] = [21, 41, 61];
+const PHASE_EXPORT: [u8; 3] = [22, 42, 62];
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/* Returns serial number of meter and its counters indexed by measurement index */
+pub fn parse_datagram(data: &[u8]) -> Option<(u32, HashMap<u8, u64>)> {
+    if data.len() < OBIS_START || &data[0..4] != HEADER {
+        return None;
+    }
+    if u16::from_be_bytes([data[DATA_START], data[DATA_START + 1]]) != METER_PROTOCOL {
+        return None;
+    }
+
+    /* Data length counts bytes from protocol id */
+    let end = DATA_START + u16::from_be_bytes([data[12], data[13]]) as usize;
+    if end < OBIS_START {
+        return None;
+    }
+    let data = data.get(..end)?;
+    let serial = u32::from_be_bytes([data[20], data[21], data[22], data[23]]);
+
+    let mut counters = HashMap::new();
+    let mut i = OBIS_START;
+    while i + 4 <= data.len() {
+        let (channel, index, size) = (data[i], data[i + 1], data[i + 2]);
+        let size = if channel == VERSION_CHANNEL { 4 } else { size as usize };
+        let value = data.get(i + 4..i + 4 + size)?;
+
+        match size {
+            4 => {},
+            COUNTER_SIZE => {
+                counters.insert(index, u64::from_be_bytes(value.try_into().unwrap()));
+            },
+            _ => return None,
+        }
+        i += 4 + size;
+    }
+
+    Some((serial, counters))
+}
+
+pub struct SmaMeter {
+    config: SmaConfig,
+    socket: Option<UdpSocket>,
+    last_totals: Option<(Vec<f64>, Vec<f64>)>,
+    last_reading: Instant,
+}
+
+impl SmaMeter {
+    pub fn new(config: &SmaConfig) -> Self {
+        SmaMeter {
+            config: config.clone(),
+            socket: None,
+            last_totals: None,
+            last_reading: Instant::now(),
+        }
+    }
+
+    /* Multicast group is joined on all interfaces, other address is listened on directly */
+    fn open(&self) -> Result<UdpSocket, String> {
+        let address: SocketAddr = self.config.address.parse().map_err(|_| format!("Malformed address {}", self.config.address))?;
+        let socket = match address {
+            SocketAddr::V4(address) if address.ip().is_multicast() => {
+                let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, address.port()))
+                    .map_err(|error| error.to_string())?;
+                socket.join_multicast_v4(address.ip(), &Ipv4Addr::UNSPECIFIED).map_err(|error| error.to_string())?;
+                socket
+            },
+            address => UdpSocket::bind(address).map_err(|error| error.to_string())?,
+        };
+
+        socket.set_read_timeout(Some(TIMEOUT)).map_err(|error| error.to_string())?;
+        Ok(socket)
+    }
+
+    /* Datagrams of other devices and of other meters are skipped */
+    fn receive_totals(&mut self) -> Result<(Vec<f64>, Vec<f64>), String> {
+        let socket = self.socket.as_ref().unwrap();
+        let phases = self.config.phases;
+        let mut buffer = [0u8; 1024];
+        loop {
+            let size = socket.recv(&mut buffer).map_err(|error| error.to_string())?;
+            let (serial, counters) = if let Some(datagram) = parse_datagram(&buffer[..size]) {
+                datagram
+            } else {
+                continue;
+            };
+            if self.config.serial.map_or(false, |expected| expected != serial) {
+                continue;
+            }
+
+            let get_wh = |indexes: &[u8]| -> Option<Vec<f64>> {
+                indexes[..phases].iter().map(|index| counters.get(index).map(|&ws| ws as f64 / 3600.0)).collect()
+            };
+
+            return match (get_wh(&PHASE_IMPORT), get_wh(&PHASE_EXPORT)) {
+                (Some(imported), Some(exported)) => Ok((imported, exported)),
+                _ => Err(format!("Meter {} does not report energy of all phases", serial)),
+            };
+        }
+    }
+}
+
+impl PolledMeter for SmaMeter {
+    /* Every datagram is received so socket buffer does not hold outdated ones */
+    fn poll(&mut self) -> Result<Option<MeterReading>, String> {
+        let interval = Duration::from_secs(self.config.interval);
+        loop {
+            let totals = self.read_totals()?;
+            let now = Instant::now();
+            if self.last_totals.is_none() || now - self.last_reading >= interval {
+                self.last_reading = now;
+                return Ok(get_delta_reading(&mut self.last_totals, totals.0, totals.1));
+            }
+        }
+    }
+
+    /* Socket is opened again after failure */
+    fn read_totals(&mut self) -> Result<(Vec<f64>, Vec<f64>), String> {
+        if self.socket.is_none() {
+            self.socket = Some(self.open()?);
+        }
+
+        let totals = self.receive_totals();
+        if totals.is_err() {
+            self.socket = None;
+        }
+        totals
+    }
+
+    /* Polling waits for datagrams itself */
+    fn get_interval(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Datagram as sent by Home Manager 2.0, only objects needed here are included */
+    fn get_datagram(serial: u32, imported_ws: [u64; 3], exported_ws: [u64; 3]) -> Vec<u8> {
+        let mut objects = vec![];
+        /* Actual power imported in 0.1 W and total imported counter */
+        objects.extend_from_slice(&[0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x1B, 0x58]);
+        objects.extend_from_slice(&[0x00, 0x01, 0x08, 0x00]);
+        objects.extend_from_slice(&imported_ws.iter().sum::<u64>().to_be_bytes());
+        for i in 0..3 {
+            objects.extend_from_slice(&[0x00, PHASE_IMPORT[i], 0x08, 0x00]);
+            objects.extend_from_slice(&imported_ws[i].to_be_bytes());
+            objects.extend_from_slice(&[0x00, PHASE_EXPORT[i], 0x08, 0x00]);
+            objects.extend_from_slice(&exported_ws[i].to_be_bytes());
+        }
+        objects.extend_from_slice(&[0x90, 0x00, 0x00, 0x00, 0x02, 0x00, 0x10, 0x52]);
+
+        let mut datagram = b"SMA\0\x00\x04\x02\xA0\x00\x00\x00\x01".to_vec();
+        datagram.extend_from_slice(&(12 + objects.len() as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0x00, 0x10, 0x60, 0x69, 0x01, 0x74]);
+        datagram.extend_from_slice(&serial.to_be_bytes());
+        datagram.extend_from_slice(&[0x00, 0x0F, 0x42, 0x40]);
+        datagram.extend_from_slice(&objects);
+        datagram.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        datagram
+    }
+
+    #[test]
+    fn datagram_counters() {
+        let datagram = get_datagram(1900123456, [3600, 7200, 0], [0, 36000, 1800]);
+        let (serial, counters) = parse_datagram(&datagram).unwrap();
+
+        assert_eq!(serial, 1900123456);
+        assert_eq!(counters.get(&1), Some(&10800));
+        assert_eq!(counters.get(&41), Some(&7200));
+        assert_eq!(counters.get(&62), Some(&1800));
+
+        /* Discovery and inverter datagrams are not meter readings */
+        let mut datagram = datagram;
+        datagram[17] = 0x65;
+        assert_eq!(parse_datagram(&datagram), None);
+    }
+
+    #[test]
+    fn meter_replays_datagrams() {
+        let config = SmaConfig {
+            address: String::from("127.0.0.1:0"),
+            serial: Some(1900123456),
+            phases: 3,
+            interval: 0,
+        };
+        let mut meter = SmaMeter::new(&config);
+        let socket = meter.open().unwrap();
+        let address = socket.local_addr().unwrap();
+        meter.socket = Some(socket);
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"SMA\0 not a meter", address).unwrap();
+        sender.send_to(&get_datagram(1900000001, [0, 0, 0], [0, 0, 0]), address).unwrap();
+        sender.send_to(&get_datagram(1900123456, [3600, 7200, 0], [0, 36000, 1800]), address).unwrap();
+        sender.send_to(&get_datagram(1900123456, [7200, 7200, 0], [0, 36000, 1800]), address).unwrap();
+        sender.send_to(&get_datagram(1900123456, [10800, 7200, 0], [0, 36000, 9000]), address).unwrap();
+
+        assert_eq!(meter.read_totals(), Ok((vec![1.0, 2.0, 0.0], vec![0.0, 10.0, 0.5])));
+        assert_eq!(meter.poll(), Ok(None));
+        assert_eq!(meter.poll(), Ok(Some((vec![60, 0, 0], vec![0, 0, 120], vec![3.0, 2.0, 0.0], vec![0.0, 10.0, 2.5]))));
+    }
+}
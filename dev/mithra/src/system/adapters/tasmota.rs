@@ -0,0 +1,143 @@
+use json::JsonValue;
+use std::time::Duration;
+
+use super::PlugDriver;
+use crate::system::structs::PlugStatus;
+
+/* Tasmota device is addressed by its topic.
+Sensor readings are published to tele/<topic>/SENSOR every TelePeriod, 300 seconds by default,
+TelePeriod longer than report interval drops energy readings.
+Relay state is published to stat/<topic>/POWER and set by cmnd/<topic>/POWER. */
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(330);
+
+pub fn get_sensor_topic(plug_id: &str) -> String {
+    format!("tele/{}/SENSOR", plug_id)
+}
+
+pub fn get_lwt_topic(plug_id: &str) -> String {
+    format!("tele/{}/LWT", plug_id)
+}
+
+pub fn get_power_state_topic(plug_id: &str) -> String {
+    format!("stat/{}/POWER", plug_id)
+}
+
+pub fn get_power_command_topic(plug_id: &str) -> String {
+    format!("cmnd/{}/POWER", plug_id)
+}
+
+/* Empty command payload only asks for relay state */
+pub fn get_power_command(on: Option<bool>) -> &'static str {
+    match on {
+        Some(true) => "ON",
+        Some(false) => "OFF",
+        None => "",
+    }
+}
+
+/* Last will message is retained, so it tells whether device is connected */
+pub fn is_online(payload: &str) -> bool {
+    payload == "Online"
+}
+
+pub fn parse_power_state(payload: &str) -> Option<bool> {
+    match payload {
+        "ON" => Some(true),
+        "OFF" => Some(false),
+        _ => None,
+    }
+}
+
+/* Total energy in kWh is converted to Wmin */
+pub fn parse_sensor(sensor: &JsonValue) -> PlugStatus {
+    PlugStatus {
+        output: None,
+        power: sensor["ENERGY"]["Power"].as_f32(),
+        energy_wmin: sensor["ENERGY"]["Total"].as_f64().map(|total| (total * 60000.0).round() as u64),
+    }
+}
+
+#[derive(Debug)]
+pub struct TasmotaPlug {
+    id: String,
+}
+
+impl TasmotaPlug {
+    pub fn new(id: &str) -> Self {
+        TasmotaPlug { id: String::from(id) }
+    }
+}
+
+impl PlugDriver for TasmotaPlug {
+    fn get_data_topics(&self) -> Vec<String> {
+        vec![get_sensor_topic(&self.id)]
+    }
+
+    fn get_state_topics(&self) -> Vec<String> {
+        vec![get_power_state_topic(&self.id)]
+    }
+
+    fn parse(&self, topic: &str, payload: &str) -> PlugStatus {
+        if topic == get_power_state_topic(&self.id) {
+            return PlugStatus {
+                output: parse_power_state(payload),
+                ..Default::default()
+            };
+        }
+
+        match json::parse(payload) {
+            Ok(sensor) => parse_sensor(&sensor),
+            Err(_) => PlugStatus::default(),
+        }
+    }
+
+    fn get_switch_command(&self, on: bool) -> (String, String) {
+        (get_power_command_topic(&self.id), String::from(get_power_command(Some(on))))
+    }
+
+    fn get_state_query(&self) -> Option<(String, String)> {
+        Some((get_power_command_topic(&self.id), String::from(get_power_command(None))))
+    }
+
+    fn get_availability_topic(&self) -> Option<String> {
+        Some(get_lwt_topic(&self.id))
+    }
+
+    fn is_online(&self, payload: &str) -> bool {
+        is_online(payload)
+    }
+
+    fn get_report_interval(&self) -> Duration {
+        REPORT_INTERVAL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sensor_readings() {
+        let sensor = json::parse(
+            r#"{"Time": "2022-06-01T12:00:00", "ENERGY": {"TotalStartTime": "2022-01-01T00:00:00",
+            "Total": 12.345, "Yesterday": 1.2, "Today": 0.4, "Power": 215, "Voltage": 231, "Current": 0.93}}"#
+        ).unwrap();
+
+        assert_eq!(parse_sensor(&sensor), PlugStatus {
+            output: None,
+            power: Some(215.0),
+            energy_wmin: Some(740700),
+        });
+        assert_eq!(parse_sensor(&json::parse(r#"{"Time": "2022-06-01T12:00:00"}"#).unwrap()), PlugStatus::default());
+    }
+
+    #[test]
+    fn parses_power_state() {
+        assert_eq!(parse_power_state("ON"), Some(true));
+        assert_eq!(parse_power_state("OFF"), Some(false));
+        assert_eq!(parse_power_state("TOGGLE"), None);
+        assert!(is_online("Online"));
+        assert!(!is_online("Offline"));
+    }
+}
@@ -1,11 +1,11 @@
 
 use chrono::{NaiveDate, NaiveDateTime, Utc, Datelike};
-use postgres::{Client, Config, NoTls};
-use std::collections::HashSet;
+use postgres::{Client, Config, NoTls, types::ToSql};
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc::Receiver;
 
 mod queries;
-use super::structs::EnergyData;
+use super::structs::{AccuracyStats, EnergyData};
 
 
 fn next_month(date: NaiveDateTime) -> NaiveDateTime {
@@ -15,10 +15,10 @@ fn next_month(date: NaiveDateTime) -> NaiveDateTime {
         (date.month() + 1, date.year())
     };
     
-    return NaiveDate::from_ymd(year, month, 1).and_hms( 0, 0, 0); 
+    return NaiveDate::from_ymd_opt(year, month, 1).unwrap().and_hms_opt(0, 0, 0).unwrap(); 
 }
 
-pub fn check_db_schema(client: &mut Client, (period_start, period_end): (NaiveDateTime, NaiveDateTime)) {
+pub fn check_db_schema(client: &mut Client, (period_start, period_end): (NaiveDateTime, NaiveDateTime), phases: usize) {
     let mut tables = HashSet::new();
 
     let rows = client.query(queries::GET_ALL_TABLES, &[]).unwrap_or_else(|error_msg| {
@@ -31,6 +31,22 @@ pub fn check_db_schema(client: &mut Client, (period_start, period_end): (NaiveDa
         tables.insert(table);
     }
 
+    if let None = tables.get("billing_periods") {
+        client.execute(queries::CREATE_BILLING_PERIODS_TABLE, &[]).unwrap_or_else(|error_msg| {
+            eprintln!("{}", error_msg);
+            std::process::exit(1);
+        });
+        println!("Missing table 'billing_periods', created.");
+    }
+
+    if let None = tables.get("scheduling_accuracy") {
+        client.execute(queries::CREATE_SCHEDULING_ACCURACY_TABLE, &[]).unwrap_or_else(|error_msg| {
+            eprintln!("{}", error_msg);
+            std::process::exit(1);
+        });
+        println!("Missing table 'scheduling_accuracy', created.");
+    }
+
     println!("Got database schema, start checking tables for every month.");
 
     let mut month = period_start;
@@ -38,7 +54,7 @@ pub fn check_db_schema(client: &mut Client, (period_start, period_end): (NaiveDa
     while month < period_end {
         let table = format!("switchboard_{}_{:02}", month.year(), month.month());
         if let None = tables.get(&table) {
-            let query = queries::create_switchboard_table(month.year(), month.month());
+            let query = queries::create_switchboard_table(month.year(), month.month(), phases);
             client.execute(&query, &[]).unwrap_or_else(|error_msg| {
                 eprintln!("{}", error_msg);
                 std::process::exit(1);
@@ -66,12 +82,42 @@ pub fn check_db_schema(client: &mut Client, (period_start, period_end): (NaiveDa
             println!("Missing table '{}', created.", table);
         }
 
+        let table = format!("production_{}_{:02}", month.year(), month.month());
+        if let None = tables.get(&table) {
+            let query = queries::create_production_table(month.year(), month.month());
+            client.execute(&query, &[]).unwrap_or_else(|error_msg| {
+                eprintln!("{}", error_msg);
+                std::process::exit(1);
+            });
+            println!("Missing table '{}', created.", table);
+        }
+
+        let table = format!("miners_telemetry_{}_{:02}", month.year(), month.month());
+        if let None = tables.get(&table) {
+            let query = queries::create_miner_telemetry_table(month.year(), month.month());
+            client.execute(&query, &[]).unwrap_or_else(|error_msg| {
+                eprintln!("{}", error_msg);
+                std::process::exit(1);
+            });
+            println!("Missing table '{}', created.", table);
+        }
+
+        let table = format!("projections_{}_{:02}", month.year(), month.month());
+        if let None = tables.get(&table) {
+            let query = queries::create_projection_table(month.year(), month.month());
+            client.execute(&query, &[]).unwrap_or_else(|error_msg| {
+                eprintln!("{}", error_msg);
+                std::process::exit(1);
+            });
+            println!("Missing table '{}', created.", table);
+        }
+
         println!("{} checked.", month);
         month = next_month(month);
     }
 }
 
-pub fn get_switchboard_params(client: &mut Client, period_start: NaiveDateTime) -> Option<([f64; 3], [f64; 3])> {
+pub fn get_switchboard_params(client: &mut Client, period_start: NaiveDateTime, phases: usize) -> Option<(Vec<f64>, Vec<f64>)> {
     let mut month = period_start;
     let now = Utc::now().naive_utc();
 
@@ -83,16 +129,12 @@ pub fn get_switchboard_params(client: &mut Client, period_start: NaiveDateTime)
         });
 
         if let Some(row) = start_energy_state.iter().next() {
-            let total_consumed: [f64; 3] = [
-                row.get("total_consumed_wh_0"),
-                row.get("total_consumed_wh_1"),
-                row.get("total_consumed_wh_2"),
-            ];
-            let total_returned: [f64; 3] = [
-                row.get("total_returned_wh_0"),
-                row.get("total_returned_wh_1"),
-                row.get("total_returned_wh_2"),
-            ];
+            let total_consumed: Vec<f64> = (0..phases)
+                .map(|phase| row.get(format!("total_consumed_wh_{}", phase).as_str()))
+                .collect();
+            let total_returned: Vec<f64> = (0..phases)
+                .map(|phase| row.get(format!("total_returned_wh_{}", phase).as_str()))
+                .collect();
 
             return Some((total_consumed, total_returned));
         }
@@ -104,15 +146,15 @@ pub fn get_switchboard_params(client: &mut Client, period_start: NaiveDateTime)
     None
 }
 
-pub fn get_miners_consumption(client: &mut Client, period_start: NaiveDateTime) -> [u64; 3] {
+pub fn get_miners_consumption(client: &mut Client, period_start: NaiveDateTime, phases: usize) -> Vec<u64> {
     let mut month = period_start;
     let now = Utc::now().naive_utc();
-    let mut consumption = [0; 3];
+    let mut consumption = vec![0; phases];
 
 
     while month <= now {
-        for phase in 0..3 {
-            let query = queries::get_month_miner_consumption(month.year(), month.month(), phase);
+        for phase in 0..phases {
+            let query = queries::get_month_miner_consumption(month.year(), month.month(), phase as u32);
             let result = client.query(&query, &[]).unwrap_or_else(|error_msg| {
                 eprintln!("{}", error_msg);
                 std::process::exit(1);
@@ -120,7 +162,7 @@ pub fn get_miners_consumption(client: &mut Client, period_start: NaiveDateTime)
 
             if let Some(row) = result.first() {
                 let month_sum: i64 = row.get("sum");
-                consumption[phase as usize] += month_sum as u64;
+                consumption[phase] += month_sum as u64;
             }
         }
         
@@ -130,15 +172,15 @@ pub fn get_miners_consumption(client: &mut Client, period_start: NaiveDateTime)
     return consumption;
 }
 
-pub fn get_miners_grid_consumption(client: &mut Client, period_start: NaiveDateTime) -> [u64; 3] {
+pub fn get_miners_grid_consumption(client: &mut Client, period_start: NaiveDateTime, phases: usize) -> Vec<u64> {
     let mut month = period_start;
     let now = Utc::now().naive_utc();
-    let mut consumption = [0; 3];
+    let mut consumption = vec![0; phases];
 
 
     while month <= now {
-        for phase in 0..3 {
-            let query = queries::get_month_miner_grid_consumption(month.year(), month.month(), phase);
+        for phase in 0..phases {
+            let query = queries::get_month_miner_grid_consumption(month.year(), month.month(), phase as u32);
             let result = client.query(&query, &[]).unwrap_or_else(|error_msg| {
                 eprintln!("{}", error_msg);
                 std::process::exit(1);
@@ -146,7 +188,7 @@ pub fn get_miners_grid_consumption(client: &mut Client, period_start: NaiveDateT
 
             if let Some(row) = result.first() {
                 let month_sum: i64 = row.get("sum");
-                consumption[phase as usize] += month_sum as u64;
+                consumption[phase] += month_sum as u64;
             }
         }
         
@@ -156,6 +198,41 @@ pub fn get_miners_grid_consumption(client: &mut Client, period_start: NaiveDateT
     return consumption;
 }
 
+/* Returns accuracy statistics per (month, hour) with one entry for every phase */
+pub fn get_accuracy_stats(client: &mut Client, phases: usize) -> HashMap<(u32, u32), Vec<AccuracyStats>> {
+    let mut accuracy = HashMap::new();
+
+    let rows = client.query(queries::GET_SCHEDULING_ACCURACY, &[]).unwrap_or_else(|error_msg| {
+        eprintln!("{}", error_msg);
+        std::process::exit(1);
+    });
+
+    for row in rows {
+        let month: i16 = row.get("month");
+        let hour: i16 = row.get("hour");
+        let phase: i16 = row.get("phase");
+        let samples: i64 = row.get("samples");
+
+        if phase as usize >= phases {
+            continue;
+        }
+
+        let stats: &mut Vec<AccuracyStats> = accuracy
+            .entry((month as u32, hour as u32))
+            .or_insert_with(|| vec![AccuracyStats::default(); phases]);
+
+        stats[phase as usize] = AccuracyStats {
+            samples: samples as u64,
+            mean_error_W: row.get("mean_error_w"),
+            mean_abs_error_W: row.get("mean_abs_error_w"),
+            mean_grid_import_W: row.get("mean_grid_import_w"),
+            mean_wasted_export_W: row.get("mean_wasted_export_w"),
+        };
+    }
+
+    accuracy
+}
+
 pub fn insert_energy_data_loop(db_config: Config, rx: Receiver<EnergyData>) {
 
     let mut client = match db_config.connect(NoTls) {
@@ -169,18 +246,17 @@ pub fn insert_energy_data_loop(db_config: Config, rx: Receiver<EnergyData>) {
     while let Ok(item) = rx.recv() {
         match item {
             EnergyData::Switchboard{ts, ec, er, tc, tr} => {
-                let query = queries::insert_switchboard_row(ts.year(), ts.month());
-
-                if let Err(error_msg)  = client.execute(
-                    &query,
-             &[
-                        &ts,
-                        &(ec[0] as i64), &(ec[1] as i64), &(ec[2] as i64),
-                        &(er[0] as i64), &(er[1] as i64), &(er[2] as i64),
-                        &tc[0], &tc[1], &tc[2],
-                        &tr[0], &tr[1], &tr[2],
-                    ]
-                ) {
+                let query = queries::insert_switchboard_row(ts.year(), ts.month(), ec.len());
+
+                let ec: Vec<i64> = ec.iter().map(|&x| x as i64).collect();
+                let er: Vec<i64> = er.iter().map(|&x| x as i64).collect();
+                let mut params: Vec<&(dyn ToSql + Sync)> = vec![&ts];
+                params.extend(ec.iter().map(|x| x as &(dyn ToSql + Sync)));
+                params.extend(er.iter().map(|x| x as &(dyn ToSql + Sync)));
+                params.extend(tc.iter().map(|x| x as &(dyn ToSql + Sync)));
+                params.extend(tr.iter().map(|x| x as &(dyn ToSql + Sync)));
+
+                if let Err(error_msg)  = client.execute(&query, &params) {
                     eprintln!("Inserting switchboard row error: {}", error_msg);
                 }
             },
@@ -202,6 +278,54 @@ pub fn insert_energy_data_loop(db_config: Config, rx: Receiver<EnergyData>) {
                     eprintln!("Inserting miner row error: {}", error_msg);
                 }
             },
+            EnergyData::Production{ts, ep, power} => {
+                let query = queries::insert_production_row(ts.year(), ts.month());
+
+                if let Err(error_msg)  = client.execute(&query,
+                 &[&ts, &(ep as i64), &power]
+                ) {
+                    eprintln!("Inserting production row error: {}", error_msg);
+                }
+            },
+            EnergyData::Telemetry{ts, name, telemetry, is_stalled} => {
+                let query = queries::insert_miner_telemetry_row(ts.year(), ts.month());
+
+                if let Err(error_msg)  = client.execute(&query,
+                 &[
+                    &ts, &name, &telemetry.hashrate_ghs, &telemetry.temperature,
+                    &telemetry.fan_rpm.map(|rpm| rpm as i32), &(telemetry.accepted as i64), &is_stalled
+                 ]
+                ) {
+                    eprintln!("Inserting miner telemetry row error: {}", error_msg);
+                }
+            },
+            EnergyData::Projection{ts, balance_wh, projected_balance_wh} => {
+                let query = queries::insert_projection_row(ts.year(), ts.month());
+
+                if let Err(error_msg)  = client.execute(&query,
+                 &[&ts, &balance_wh, &projected_balance_wh]
+                ) {
+                    eprintln!("Inserting projection row error: {}", error_msg);
+                }
+            },
+            EnergyData::PeriodSummary{ts, period_start, period_end, balance_wh} => {
+                if let Err(error_msg)  = client.execute(queries::INSERT_BILLING_PERIOD_ROW,
+                 &[&ts, &period_start, &period_end, &balance_wh]
+                ) {
+                    eprintln!("Inserting billing period row error: {}", error_msg);
+                }
+            },
+            EnergyData::Accuracy{month, hour, phase, stats} => {
+                if let Err(error_msg)  = client.execute(queries::UPSERT_SCHEDULING_ACCURACY_ROW,
+                 &[
+                    &(month as i16), &(hour as i16), &(phase as i16), &(stats.samples as i64),
+                    &stats.mean_error_W, &stats.mean_abs_error_W,
+                    &stats.mean_grid_import_W, &stats.mean_wasted_export_W
+                 ]
+                ) {
+                    eprintln!("Upserting scheduling accuracy row error: {}", error_msg);
+                }
+            },
         }
     }
 
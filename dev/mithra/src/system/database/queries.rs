@@ -5,24 +5,24 @@ SELECT table_name FROM information_schema.tables
         table_type = 'BASE TABLE'
 ;";
 
-pub fn create_switchboard_table(year: i32, month: u32) -> String {
+pub fn create_switchboard_table(year: i32, month: u32, phases: usize) -> String {
+    let mut columns = String::new();
+    for column in ["energy_consumed_Wmin", "energy_returned_Wmin"] {
+        for phase in 0..phases {
+            columns += format!(",\n            {}_{} bigint", column, phase).as_str();
+        }
+    }
+    for column in ["total_consumed_Wh", "total_returned_Wh"] {
+        for phase in 0..phases {
+            columns += format!(",\n            {}_{} double precision", column, phase).as_str();
+        }
+    }
+
     format!(
         "CREATE TABLE switchboard_{}_{:02} (
-            ts timestamp PRIMARY KEY,
-            energy_consumed_Wmin_0 bigint,
-            energy_consumed_Wmin_1 bigint,
-            energy_consumed_Wmin_2 bigint,
-            energy_returned_Wmin_0 bigint,
-            energy_returned_Wmin_1 bigint,
-            energy_returned_Wmin_2 bigint,
-            total_consumed_Wh_0 double precision,
-            total_consumed_Wh_1 double precision,
-            total_consumed_Wh_2 double precision,
-            total_returned_Wh_0 double precision,
-            total_returned_Wh_1 double precision,
-            total_returned_Wh_2 double precision
+            ts timestamp PRIMARY KEY{}
         );",
-        year, month
+        year, month, columns
     )
 }
 
@@ -50,6 +50,67 @@ pub fn create_miner_grid_table(year: i32, month: u32) -> String {
     )
 }
 
+pub fn create_production_table(year: i32, month: u32) -> String {
+    format!(
+        "CREATE TABLE production_{}_{:02} (
+            ts timestamp PRIMARY KEY,
+            energy_produced_Wmin bigint,
+            power_W real
+        );",
+        year, month
+    )
+}
+
+pub fn create_miner_telemetry_table(year: i32, month: u32) -> String {
+    format!(
+        "CREATE TABLE miners_telemetry_{}_{:02} (
+            ts timestamp,
+            name text,
+            hashrate_GHs double precision,
+            temperature_C real,
+            fan_rpm integer,
+            accepted_shares bigint,
+            is_stalled boolean,
+            PRIMARY KEY (ts, name)
+        );",
+        year, month
+    )
+}
+
+pub fn create_projection_table(year: i32, month: u32) -> String {
+    format!(
+        "CREATE TABLE projections_{}_{:02} (
+            ts timestamp PRIMARY KEY,
+            balance_Wh double precision,
+            projected_balance_Wh double precision
+        );",
+        year, month
+    )
+}
+
+pub static CREATE_BILLING_PERIODS_TABLE: &str = "
+CREATE TABLE billing_periods (
+    ts timestamp PRIMARY KEY,
+    period_start timestamp,
+    period_end timestamp,
+    balance_Wh double precision
+);";
+
+pub static CREATE_SCHEDULING_ACCURACY_TABLE: &str = "
+CREATE TABLE scheduling_accuracy (
+    month smallint,
+    hour smallint,
+    phase smallint,
+    samples bigint,
+    mean_error_W double precision,
+    mean_abs_error_W double precision,
+    mean_grid_import_W double precision,
+    mean_wasted_export_W double precision,
+    PRIMARY KEY (month, hour, phase)
+);";
+
+pub static GET_SCHEDULING_ACCURACY: &str = "SELECT * FROM scheduling_accuracy;";
+
 pub fn get_first_row(year: i32, month: u32) -> String {
     let table_name = format!("switchboard_{}_{:02}", year, month);
     format!(
@@ -77,10 +138,15 @@ pub fn get_month_miner_grid_consumption(year: i32, month: u32, phase: u32) -> St
     )
 }
 
-pub fn insert_switchboard_row(year: i32, month: u32) -> String {
+pub fn insert_switchboard_row(year: i32, month: u32, phases: usize) -> String {
+    let values = (1..=(1 + 4 * phases))
+        .map(|i| format!("${}", i))
+        .collect::<Vec<String>>()
+        .join(", ");
+
     format!(
-        "INSERT INTO switchboard_{}_{:02} VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13);",
-        year, month
+        "INSERT INTO switchboard_{}_{:02} VALUES ({});",
+        year, month, values
     )
 }
 
@@ -91,6 +157,39 @@ pub fn insert_miners_row(year: i32, month: u32) -> String {
     )
 }
 
+pub fn insert_production_row(year: i32, month: u32) -> String {
+    format!(
+        "INSERT INTO production_{}_{:02} VALUES ($1, $2, $3);",
+        year, month
+    )
+}
+
+pub fn insert_miner_telemetry_row(year: i32, month: u32) -> String {
+    format!(
+        "INSERT INTO miners_telemetry_{}_{:02} VALUES ($1, $2, $3, $4, $5, $6, $7);",
+        year, month
+    )
+}
+
+pub fn insert_projection_row(year: i32, month: u32) -> String {
+    format!(
+        "INSERT INTO projections_{}_{:02} VALUES ($1, $2, $3);",
+        year, month
+    )
+}
+
+pub static INSERT_BILLING_PERIOD_ROW: &str = "INSERT INTO billing_periods VALUES ($1, $2, $3, $4);";
+
+pub static UPSERT_SCHEDULING_ACCURACY_ROW: &str = "
+INSERT INTO scheduling_accuracy VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+    ON CONFLICT (month, hour, phase) DO UPDATE SET
+        samples = EXCLUDED.samples,
+        mean_error_W = EXCLUDED.mean_error_W,
+        mean_abs_error_W = EXCLUDED.mean_abs_error_W,
+        mean_grid_import_W = EXCLUDED.mean_grid_import_W,
+        mean_wasted_export_W = EXCLUDED.mean_wasted_export_W
+;";
+
 pub fn insert_miners_grid_row(year: i32, month: u32) -> String {
     format!(
         "INSERT INTO miners_grid_{}_{:02} VALUES ($1, $2, $3);",
@@ -0,0 +1,177 @@
+use chrono::NaiveDateTime;
+use json::JsonValue;
+use rumqttc::{Client, QoS};
+use std::{collections::HashMap, time::Duration};
+
+use super::adapters::shelly;
+
+pub static SHELLIES_ANNOUNCE_TOPIC: &str = "shellies/announce";
+pub static GUARDS_ANNOUNCE_TOPIC: &str = "guards/announce";
+pub static DISCOVERED_TOPIC: &str = "mithra/discovered";
+/* Gen2 Shelly devices publish retained online status under their topic prefix, which is device id by default */
+pub static ONLINE_TOPIC: &str = "+/online";
+
+/* Gen2 Shelly devices are asked for device info periodically, because they do not announce themselves */
+pub const DEVICE_INFO_INTERVAL: Duration = Duration::from_secs(300);
+
+/* Device which announced itself, Gen2 Shelly devices answer device info request instead */
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnnouncedDevice {
+    Shelly {id: String, model: String},
+    /* Guards without versioned protocol announce no version */
+    Guard {id: String, board_type: String, version: Option<u32>, miners: Vec<(String, u32)>},
+}
+
+impl AnnouncedDevice {
+    pub fn get_id(&self) -> &str {
+        match self {
+            AnnouncedDevice::Shelly{id, ..} | AnnouncedDevice::Guard{id, ..} => id,
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut device = JsonValue::new_object();
+        match self {
+            AnnouncedDevice::Shelly{id, model} => {
+                device["kind"] = "shelly".into();
+                device["id"] = id.as_str().into();
+                device["model"] = model.as_str().into();
+            },
+            AnnouncedDevice::Guard{id, board_type, version, miners} => {
+                device["kind"] = "guard".into();
+                device["id"] = id.as_str().into();
+                device["type"] = board_type.as_str().into();
+                device["version"] = (*version).into();
+                device["miners"] = JsonValue::Array(miners.iter()
+                    .map(|(miner_id, pinset)| {
+                        let mut miner = JsonValue::new_object();
+                        miner["id"] = miner_id.as_str().into();
+                        miner["pinset"] = (*pinset).into();
+                        miner
+                    })
+                    .collect()
+                );
+            },
+        }
+        device
+    }
+}
+
+pub fn parse_announce(topic: &str, payload: &str) -> Result<AnnouncedDevice, String> {
+    let device = json::parse(payload).map_err(|error| format!("Announce on topic {} is not json: {}", topic, error))?;
+
+    if topic == SHELLIES_ANNOUNCE_TOPIC {
+        match (device["id"].as_str(), device["model"].as_str()) {
+            (Some(id), Some(model)) => Ok(AnnouncedDevice::Shelly{id: String::from(id), model: String::from(model)}),
+            _ => Err(format!("Got improper Shelly announce: {}", device.dump())),
+        }
+    } else if topic == shelly::get_response_topic(shelly::ANNOUNCE_SRC) {
+        match shelly::parse_device_info(&device) {
+            Some((id, model)) => Ok(AnnouncedDevice::Shelly{id, model}),
+            None => Err(format!("Got improper device info response: {}", device.dump())),
+        }
+    } else if topic == GUARDS_ANNOUNCE_TOPIC {
+        let miners = if let JsonValue::Array(miners) = &device["miners"] {
+            miners.iter()
+                .map(|miner| Some((String::from(miner["id"].as_str()?), miner["pinset"].as_u32()?)))
+                .collect::<Option<Vec<(String, u32)>>>()
+        } else {
+            None
+        };
+
+        match (device["id"].as_str(), device["type"].as_str(), miners) {
+            (Some(id), Some(board_type), Some(miners)) => Ok(AnnouncedDevice::Guard{
+                id: String::from(id),
+                board_type: String::from(board_type),
+                version: device["version"].as_u32(),
+                miners,
+            }),
+            _ => Err(format!("Guard sent improper json format: {}", device.dump())),
+        }
+    } else {
+        Err(format!("Got message from undefined topic: {}", topic))
+    }
+}
+
+/* Returns id of Gen2 device which is online */
+pub fn parse_online<'a>(topic: &'a str, payload: &str) -> Option<&'a str> {
+    match topic.split_once('/') {
+        Some((device_id, "online")) if payload == "true" && !device_id.is_empty() => Some(device_id),
+        _ => None,
+    }
+}
+
+/* Answer comes to response topic of announce source */
+pub fn request_device_info(mqtt_client: &mut Client, device_id: &str) -> Result<(), String> {
+    mqtt_client.publish(
+        shelly::get_request_topic(device_id),
+        QoS::ExactlyOnce,
+        false,
+        shelly::get_request(shelly::ANNOUNCE_SRC, "Shelly.GetDeviceInfo", JsonValue::Null)
+    ).map_err(|error| error.to_string())
+}
+
+/* Devices not defined in config file are published as retained list */
+pub fn publish_discovered(mqtt_client: &mut Client, devices: &HashMap<String, (AnnouncedDevice, NaiveDateTime)>) {
+    let discovered = JsonValue::Array(devices.values()
+        .map(|(device, ts)| {
+            let mut device = device.to_json();
+            device["last_seen"] = ts.to_string().into();
+            device
+        })
+        .collect()
+    );
+
+    if let Err(error_msg) = mqtt_client.publish(DISCOVERED_TOPIC, QoS::ExactlyOnce, true, discovered.dump()) {
+        eprintln!("[Main loop] Publishing discovered devices error: {}", error_msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_announces() {
+        assert_eq!(
+            parse_announce(SHELLIES_ANNOUNCE_TOPIC, r#"{"id": "shellyplug-s-7", "model": "SHPLG-S", "mac": "A8032AB1"}"#),
+            Ok(AnnouncedDevice::Shelly{id: String::from("shellyplug-s-7"), model: String::from("SHPLG-S")})
+        );
+        assert_eq!(
+            parse_announce(
+                &shelly::get_response_topic(shelly::ANNOUNCE_SRC),
+                r#"{"id": 1, "src": "shellyplusplugs-a8", "result": {"id": "shellyplusplugs-a8", "app": "PlusPlugS"}}"#
+            ),
+            Ok(AnnouncedDevice::Shelly{id: String::from("shellyplusplugs-a8"), model: String::from("PlusPlugS")})
+        );
+        assert_eq!(
+            parse_announce(GUARDS_ANNOUNCE_TOPIC, r#"{"id": "Guard07", "type": "ESP32", "version": 1, "miners": [{"id": "Miner10", "pinset": 1}]}"#),
+            Ok(AnnouncedDevice::Guard{
+                id: String::from("Guard07"),
+                board_type: String::from("ESP32"),
+                version: Some(1),
+                miners: vec![(String::from("Miner10"), 1)],
+            })
+        );
+        assert_eq!(
+            parse_announce(GUARDS_ANNOUNCE_TOPIC, r#"{"id": "Guard08", "type": "ESP32", "miners": []}"#),
+            Ok(AnnouncedDevice::Guard{
+                id: String::from("Guard08"),
+                board_type: String::from("ESP32"),
+                version: None,
+                miners: vec![],
+            })
+        );
+
+        assert!(parse_announce(GUARDS_ANNOUNCE_TOPIC, r#"{"id": "Guard07", "type": "ESP32", "miners": [{"id": "Miner10"}]}"#).is_err());
+        assert!(parse_announce(SHELLIES_ANNOUNCE_TOPIC, "online").is_err());
+        assert!(parse_announce("shellies/other", "{}").is_err());
+    }
+
+    #[test]
+    fn parses_online_devices() {
+        assert_eq!(parse_online("shellyplusplugs-a8/online", "true"), Some("shellyplusplugs-a8"));
+        assert_eq!(parse_online("shellyplusplugs-a8/online", "false"), None);
+        assert_eq!(parse_online("shellies/shellyplug-s-7/online", "true"), None);
+    }
+}
@@ -0,0 +1,64 @@
+use json::JsonValue;
+
+/* Version of config message, guard acknowledges version it understands in configured message */
+pub const PROTOCOL_VERSION: u32 = 1;
+/* Power button is held this long by HardStop and HardReset */
+pub const HARD_STOP_DURATION_S: u32 = 5;
+/* Miner shows it runs within this time after power button of PowerOn is released */
+pub const START_TIMEOUT_S: u32 = 5;
+
+/* Config sent to guard after it started, miners are given as (id, pinset), like:
+{"version": 1, "miners": [{"id": "Miner00", "pinset": 0}], "timing": {"hard_stop_s": 5, "start_timeout_s": 5}} */
+pub fn get_config(miners: &Vec<(String, u32)>) -> String {
+    let mut config = JsonValue::new_object();
+    config["version"] = PROTOCOL_VERSION.into();
+    config["miners"] = JsonValue::Array(miners.iter()
+        .map(|(miner_id, pinset)| {
+            let mut miner = JsonValue::new_object();
+            miner["id"] = miner_id.as_str().into();
+            miner["pinset"] = (*pinset).into();
+            miner
+        })
+        .collect()
+    );
+    config["timing"]["hard_stop_s"] = HARD_STOP_DURATION_S.into();
+    config["timing"]["start_timeout_s"] = START_TIMEOUT_S.into();
+
+    config.dump()
+}
+
+/* Configured message carries version of config guard applied, guards without versioned protocol send none */
+pub fn parse_configured(payload: &str) -> Option<u32> {
+    json::parse(payload).ok()?["version"].as_u32()
+}
+
+pub fn is_compatible(version: Option<u32>) -> bool {
+    version == Some(PROTOCOL_VERSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_lists_miners_with_timing() {
+        let config = json::parse(&get_config(&vec![(String::from("Miner00"), 0), (String::from("Miner01"), 2)])).unwrap();
+
+        assert_eq!(config["version"].as_u32(), Some(PROTOCOL_VERSION));
+        assert_eq!(config["miners"].len(), 2);
+        assert_eq!(config["miners"][1]["id"].as_str(), Some("Miner01"));
+        assert_eq!(config["miners"][1]["pinset"].as_u32(), Some(2));
+        assert_eq!(config["timing"]["hard_stop_s"].as_u32(), Some(HARD_STOP_DURATION_S));
+        assert_eq!(config["timing"]["start_timeout_s"].as_u32(), Some(START_TIMEOUT_S));
+    }
+
+    #[test]
+    fn configured_acknowledges_version() {
+        assert_eq!(parse_configured(r#"{"version": 1}"#), Some(1));
+        assert_eq!(parse_configured("Guard00"), None);
+        assert_eq!(parse_configured(""), None);
+        assert!(is_compatible(parse_configured(r#"{"version": 1}"#)));
+        assert!(!is_compatible(parse_configured(r#"{"version": 2}"#)));
+        assert!(!is_compatible(None));
+    }
+}
@@ -4,8 +4,8 @@ use sscanf::scanf;
 use std::{
     collections::HashMap,
     str::FromStr,
-    sync::mpsc::Sender,
-    time::{Duration, Instant},
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    time::Instant,
 };
 
 use super::structs::{
@@ -14,71 +14,25 @@ use super::structs::{
     GuardData,
     Message,
     MinerAlert,
-    MinerData,
+    MinerApiCommand,
     MinerState,
+    PlugData,
+    PlugStatus,
+    SoftPower,
     UserCommands,
 };
+use super::{adapters::{MeterReading, MeterSource, PolledMeter, cgminer, fronius::FroniusInverter}, discovery, guard_protocol, whatif};
 
-pub fn switchboard_loop(mut connection: Connection, tx_db: Sender<EnergyData>, tx_main: Sender<Message>) {
-    fn is_collected<T>(array: &[Option<T>; 3]) -> bool {
-        array.iter().all(|x: &Option<T>| x.is_some())
-    }
-
-    fn erase_array<T>(array: &mut [Option<T>; 3]) {
-        for elem in array.iter_mut() {*elem = None}
-    }
-
-    let mut energy_collected = false;
-    let mut total_collected = false;
-
-    let mut energy_consumed_wmin = [None; 3];
-    let mut energy_returned_wmin = [None; 3];
-    let mut total_consumed_wh = [None; 3];
-    let mut total_returned_wh = [None; 3];
-
+pub fn switchboard_loop(mut connection: Connection, mut meter: Box<dyn MeterSource>, tx_db: Sender<EnergyData>, tx_main: Sender<Message>) {
     for msg in connection.iter() {
         match msg {
             Ok(Event::Incoming(Packet::Publish(data))) => {
-                let (_, i, data_type) = if let Some(parsed) = scanf!(
-                    data.topic,
-                    "shellies/{/[^/]+/}/emeter/{/[^/]+/}/{/[^/]+/}",
-                    String, usize, String
-                ) {
-                    parsed
-                } else {
-                    eprintln!("[Switchboard loop] Received wrong topic: {}", data.topic);
-                    continue;
-                };
-
-                if i > 2 {
-                    eprintln!("[Switchboard loop] Got message of phase: {}", i);
-                    continue;
-                }
-
                 let payload = std::str::from_utf8(&data.payload).unwrap();
 
-                match data_type.as_str() {
-                    "energy" => {
-                        let value = payload.parse::<u64>().unwrap();
-                        energy_consumed_wmin[i] = Some(value);
-                    },
-                    "returned_energy" => {
-                        let value = payload.parse::<u64>().unwrap();
-                        energy_returned_wmin[i] = Some(value);
-                    },
-                    "total" => {
-                        if !energy_collected { continue; }
-
-                        let value = payload.parse::<f64>().unwrap();
-                        total_consumed_wh[i] = Some(value);
-                    }
-                    "total_returned" => {
-                        if !energy_collected { continue; }
-
-                        let value = payload.parse::<f64>().unwrap();
-                        total_returned_wh[i] = Some(value);
+                if let Some(reading) = meter.parse(&data.topic, payload) {
+                    if !send_switchboard_reading(reading, &tx_db, &tx_main) {
+                        break;
                     }
-                    _ => {}
                 }
             },
             Ok(Event::Outgoing(Outgoing::Disconnect)) => {
@@ -90,128 +44,236 @@ pub fn switchboard_loop(mut connection: Connection, tx_db: Sender<EnergyData>, t
             Ok(_) => (), 
             Err(_) => (),
         }
+    }
 
-        if !energy_collected {
-            energy_collected = is_collected(&energy_consumed_wmin) && is_collected(&energy_returned_wmin);
-        } else if !total_collected {
-            total_collected = is_collected(&total_consumed_wh) && is_collected(&total_returned_wh);
-        } else {
-            /* Send data to database and to main thread by channel */
-            
-            let msg = EnergyData::Switchboard{
-                ts: Utc::now().naive_utc(),
-                ec: [energy_consumed_wmin[0].unwrap(), energy_consumed_wmin[1].unwrap(), energy_consumed_wmin[2].unwrap()],
-                er: [energy_returned_wmin[0].unwrap(), energy_returned_wmin[1].unwrap(), energy_returned_wmin[2].unwrap()],
-                tc: [total_consumed_wh[0].unwrap(), total_consumed_wh[1].unwrap(), total_consumed_wh[2].unwrap()],
-                tr: [total_returned_wh[0].unwrap(), total_returned_wh[1].unwrap(), total_returned_wh[2].unwrap()],
-            };
+    println!("Switchboard MQTT messages receiver exits.");
+}
 
-            if let Err(_) = tx_db.send(msg.clone()) {
-                eprintln!("[Switchboard loop] Database channel is closed!");
-                break;
+/* Polled meter is read every interval until stop channel is closed */
+pub fn polled_meter_loop(mut meter: Box<dyn PolledMeter>, stop: Receiver<()>, tx_db: Sender<EnergyData>, tx_main: Sender<Message>) {
+    loop {
+        match meter.poll() {
+            Ok(Some(reading)) => {
+                if !send_switchboard_reading(reading, &tx_db, &tx_main) {
+                    break;
+                }
+            },
+            Ok(None) => (),
+            Err(error_msg) => eprintln!("[Polled meter loop] Reading meter error: {}", error_msg),
+        }
+
+        match stop.recv_timeout(meter.get_interval()) {
+            Err(RecvTimeoutError::Timeout) => (),
+            _ => break,
+        }
+    }
+
+    println!("Switchboard meter poller exits.");
+}
+
+/* Inverter is polled every interval until stop channel is closed */
+pub fn inverter_loop(mut inverter: FroniusInverter, stop: Receiver<()>, tx_db: Sender<EnergyData>, tx_main: Sender<Message>) {
+    loop {
+        match inverter.poll() {
+            Ok(Some((ep, power))) => {
+                let msg = EnergyData::Production{
+                    ts: Utc::now().naive_utc(),
+                    ep,
+                    power,
+                };
+
+                if let Err(_) = tx_db.send(msg.clone()) {
+                    eprintln!("[Inverter loop] Database channel is closed!");
+                    break;
+                }
+
+                if let Err(_) = tx_main.send(Message::Energy(msg)) {
+                    println!("[Inverter loop] Main thread channel is closed!");
+                    break;
+                }
+            },
+            Ok(None) => (),
+            Err(error_msg) => eprintln!("[Inverter loop] Reading inverter {} error: {}", inverter.get_id(), error_msg),
+        }
+
+        match stop.recv_timeout(inverter.get_interval()) {
+            Err(RecvTimeoutError::Timeout) => (),
+            _ => break,
+        }
+    }
+
+    println!("Inverter poller exits.");
+}
+
+/* Miners are indexed by id and given as (API address, soft power), telemetry is polled every interval
+and commands are run as they come until command channel is closed, unreachable API is only reported */
+pub fn miner_api_loop(
+    miners: HashMap<String, (String, Option<SoftPower>)>,
+    commands: Receiver<(String, MinerApiCommand)>,
+    tx_main: Sender<Message>
+) {
+    let mut deadline = Instant::now();
+    'polling: loop {
+        if Instant::now() >= deadline {
+            for (miner_id, (address, _)) in miners.iter() {
+                match cgminer::get_telemetry(address) {
+                    Ok(telemetry) => {
+                        let msg = Message::Telemetry{
+                            miner_id: miner_id.clone(),
+                            ts: Utc::now().naive_utc(),
+                            telemetry,
+                        };
+
+                        if let Err(_) = tx_main.send(msg) {
+                            println!("[Miner API loop] Main thread channel is closed!");
+                            break 'polling;
+                        }
+                    },
+                    Err(error_msg) => eprintln!("[Miner API loop] Reading miner {} API error: {}", miner_id, error_msg),
+                }
             }
+            deadline = Instant::now() + cgminer::TELEMETRY_INTERVAL;
+        }
 
-            if let Err(_) = tx_main.send(Message::Energy(msg)) {
-                println!("[Switchboard loop] Main thread channel is closed!");
-                drop(tx_db);
-                break;
+        match commands.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok((miner_id, command)) => {
+                let result = match miners.get(&miner_id) {
+                    Some((address, Some(soft_power))) => {
+                        cgminer::control(address, soft_power.control, command, soft_power.power_target)
+                    },
+                    _ => Err(String::from("Miner has no soft power management")),
+                };
+
+                if let Err(_) = tx_main.send(Message::MinerApi{miner_id, command, result}) {
+                    println!("[Miner API loop] Main thread channel is closed!");
+                    break;
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    println!("Miner API loop exits.");
+}
+
+/* Send data to database and to main thread by channel, returns false when any of them is closed */
+fn send_switchboard_reading((ec, er, tc, tr): MeterReading, tx_db: &Sender<EnergyData>, tx_main: &Sender<Message>) -> bool {
+    let msg = EnergyData::Switchboard{
+        ts: Utc::now().naive_utc(),
+        ec,
+        er,
+        tc,
+        tr,
+    };
+
+    if let Err(_) = tx_db.send(msg.clone()) {
+        eprintln!("[Switchboard loop] Database channel is closed!");
+        return false;
+    }
+
+    if let Err(_) = tx_main.send(Message::Energy(msg)) {
+        println!("[Switchboard loop] Main thread channel is closed!");
+        return false;
+    }
+
+    true
+}
+
+/* Readings are continuous while they come within report interval of plug driver */
+fn update_plug_power(plug: &mut PlugData, power_now: f32, now: Instant) {
+    if let Some(last) = plug.last_received {
+        if last + plug.driver.get_report_interval() > now {
+            plug.power = plug.power.max(power_now);
+        } else {
+            plug.power = power_now
+        }
+    } else {
+        plug.power = power_now
+    }
+}
+
+/* Returns false when channels are closed */
+fn update_plug_energy(plug: &mut PlugData, consumed_now: u64, now: Instant, tx_db: &Sender<EnergyData>, tx_main: &Sender<Message>) -> bool {
+    if let Some(last) = plug.last_received {
+        if last + plug.driver.get_report_interval() > now && plug.energy_consumed + 5 < consumed_now {
+            let ts = Utc::now().naive_utc();
+
+            for (name, ec, power) in split_plug_reading(&plug.miners, consumed_now - plug.energy_consumed, plug.power) {
+                let msg = EnergyData::Miner{ts, name, ec, phase: plug.phase, power};
+
+                if let Err(_) = tx_db.send(msg.clone()) {
+                    eprintln!("[Plugs loop] Database channel is closed!");
+                    return false;
+                }
+                if let Err(_) = tx_main.send(Message::Energy(msg)) {
+                    println!("[Plugs loop] Main thread channel is closed!");
+                    return false;
+                }
             }
+        }
+    }
 
-            erase_array(&mut energy_consumed_wmin);
-            erase_array(&mut energy_returned_wmin);
-            erase_array(&mut total_consumed_wh);
-            erase_array(&mut total_returned_wh);
+    plug.energy_consumed = consumed_now;
+    plug.last_received = Some(now);
+    true
+}
 
-            energy_collected = false;
-            total_collected = false;
+/* Returns false when channels are closed */
+fn handle_plug_status(plug_id: &str, plug: &mut PlugData, status: PlugStatus, now: Instant, tx_db: &Sender<EnergyData>, tx_main: &Sender<Message>) -> bool {
+    if status == PlugStatus::default() {
+        return true;
+    }
+
+    if let Some(power_now) = status.power {
+        update_plug_power(plug, power_now, now);
+    }
+    if let Some(consumed_now) = status.energy_wmin {
+        if !update_plug_energy(plug, consumed_now, now, tx_db, tx_main) {
+            return false;
         }
     }
 
-    println!("Switchboard MQTT messages receiver exits.");
+    /* Plugs reporting only their sensor, like Tasmota, are kept alive by readings */
+    if let Err(_) = tx_main.send(Message::Plug{
+        plug_id: String::from(plug_id),
+        ts: Utc::now().naive_utc(),
+        is_on: status.output
+    }) {
+        eprintln!("[Plugs loop] Main thread channel is closed!");
+        return false;
+    }
+    true
 }
 
-pub fn plugs_loop(mut connection: Connection, mut miners: HashMap<String, MinerData>, tx_db: Sender<EnergyData>, tx_main: Sender<Message>) {
-    let interval = Duration::from_secs(90);
+/* Miners are indexed by plug id */
+pub fn plugs_loop(mut connection: Connection, mut plugs: HashMap<String, PlugData>, tx_db: Sender<EnergyData>, tx_main: Sender<Message>) {
+    /* Topic of every plug reading points to plug id */
+    let mut topics = HashMap::new();
+    for (plug_id, plug) in plugs.iter() {
+        for topic in plug.driver.get_data_topics().into_iter().chain(plug.driver.get_state_topics().into_iter()) {
+            topics.insert(topic, plug_id.clone());
+        }
+    }
 
     for msg in connection.iter() {
         //println!("Miner loop msg got.");
         match msg {
             Ok(Event::Incoming(Packet::Publish(data))) => {
-                let (plug_id, data_type) = if let Some(parsed) = scanf!(data.topic ,"shellies/{/[^/]+/}/relay/{}", String, String) {
-                    parsed
+                let plug_id = if let Some(plug_id) = topics.get(&data.topic) {
+                    plug_id
                 } else {
                     eprintln!("[Plugs loop] Arrived message from undefined topic: {}", data.topic);
                     continue;
                 };
 
                 let payload = std::str::from_utf8(&data.payload).unwrap();
-                let mut miner = if let Some(miner) = miners.get_mut(&plug_id) {
-                    miner
-                } else {
-                    eprintln!("[Plugs loop] Arrived message from undefined plug: {}", plug_id);
-                    continue;
-                };
-
-                let now = Instant::now();
-
-                match data_type.as_str() {
-                    "0/power" => {
-                        let power_now = payload.parse::<f32>().unwrap();
-                        if let Some(last) = miner.last_received {
-                            if last + interval > now {
-                                miner.power = miner.power.max(power_now);
-                            } else {
-                                miner.power = power_now
-                            }
-                        } else {
-                            miner.power = power_now
-                        }
-                    },
-                    "0/energy" => {
-                        let consumed_now = payload.parse::<u64>().unwrap();
-                        
-                        if let Some(last) = miner.last_received {
-                            if last + interval > now && miner.energy_consumed + 5 < consumed_now {
-                                let msg = EnergyData::Miner{
-                                    ts: Utc::now().naive_utc(),
-                                    name: miner.name.clone(),
-                                    ec: consumed_now - miner.energy_consumed,
-                                    phase: miner.phase,
-                                    power: miner.power, 
-                                };
-
-                                if let Err(_) = tx_db.send(msg.clone()) {
-                                    eprintln!("[Plugs loop] Database channel is closed!");
-                                    break;
-                                }
-                                if let Err(_) = tx_main.send(Message::Energy(msg)) {
-                                    println!("[Plugs loop] Main thread channel is closed!");
-                                    drop(tx_db);
-                                    break;
-                                }
-                            }
-                        }
+                let plug = plugs.get_mut(plug_id).unwrap();
+                let status = plug.driver.parse(&data.topic, payload);
 
-                        miner.energy_consumed = consumed_now;
-                        miner.last_received = Some(now);
-                    },
-                    "0" => { 
-                        let is_on = match payload {
-                            "on" => true,
-                            "off" => false,
-                            _ => continue
-                        };
-                        if let Err(_) = tx_main.send(Message::Plug{
-                            plug_id,
-                            ts: Utc::now().naive_utc(),
-                            is_on
-                        }) {
-                            eprintln!("[Plugs loop] Main thread channel is closed!");
-                            drop(tx_db);
-                            break;
-                        }
-                    }
-                    _ => {},
+                if !handle_plug_status(plug_id, plug, status, Instant::now(), &tx_db, &tx_main) {
+                    drop(tx_db);
+                    break;
                 }
             },
             Ok(Event::Outgoing(Outgoing::Disconnect)) => {
@@ -227,6 +289,54 @@ pub fn plugs_loop(mut connection: Connection, mut miners: HashMap<String, MinerD
     println!("Plugs MQTT messages receiver exits.");
 }
 
+/* Energy and power of plug shared by several miners are split by their estimated consumption,
+rounding remainder of energy goes to the last miner */
+fn split_plug_reading(miners: &Vec<(String, f32)>, ec: u64, power: f32) -> Vec<(String, u64, f32)> {
+    let total = miners.iter().map(|(_, consumption)| *consumption as f64).sum::<f64>();
+    let mut remaining = ec;
+
+    miners.iter().enumerate()
+        .map(|(i, (miner_id, consumption))| {
+            let ratio = if total > 0.0 { *consumption as f64 / total } else { 1.0 / miners.len() as f64 };
+            let miner_ec = if i + 1 == miners.len() {
+                remaining
+            } else {
+                ((ec as f64 * ratio).round() as u64).min(remaining)
+            };
+            remaining -= miner_ec;
+
+            (miner_id.clone(), miner_ec, (power as f64 * ratio) as f32)
+        })
+        .collect()
+}
+
+/* Devices announcing after start up */
+pub fn announce_loop(mut connection: Connection, tx: Sender<Message>) {
+    for msg in connection.iter() {
+        match msg {
+            Ok(Event::Incoming(Packet::Publish(data))) => {
+                let payload = std::str::from_utf8(&data.payload).unwrap();
+
+                match discovery::parse_announce(&data.topic, payload) {
+                    Ok(device) => if let Err(_) = tx.send(Message::Announce{ts: Utc::now().naive_utc(), device}) {
+                        println!("[Announce loop] Main thead channel is closed!");
+                        break;
+                    },
+                    Err(error_msg) => eprintln!("[Announce loop] {}", error_msg),
+                }
+            },
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                /* Mithra is terminating */
+                drop(tx);
+                break;
+            }
+            Ok(_) => (), 
+            Err(_) => (),
+        }
+    }
+    println!("Announce MQTT messages receiver exits.");
+}
+
 pub fn guards_loop(mut connection: Connection, tx: Sender<Message>) {
 
     for msg in connection.iter() { match msg {
@@ -270,7 +380,7 @@ pub fn guards_loop(mut connection: Connection, tx: Sender<Message>) {
                             msg = Some( Message::Guard{
                                 guard_id,
                                 ts,
-                                data: GuardData::Configured
+                                data: GuardData::Configured{version: guard_protocol::parse_configured(payload)}
                             });
                         },
                         "ping" => {
@@ -354,6 +464,14 @@ pub fn user_loop(mut connection: Connection, tx: Sender<Message>) {
             Ok(Event::Incoming(Packet::Publish(data))) => {
                 let payload = std::str::from_utf8(&data.payload).unwrap();
 
+                if data.topic == whatif::REQUEST_TOPIC {
+                    if let Err(_) = tx.send(Message::WhatIf(whatif::parse_request(payload))) {
+                        eprintln!("[User loop] Main thread channel is closed!");
+                        break;
+                    }
+                    continue;
+                }
+
                 let miner_id = if let Some(miner_id) = scanf!(
                     data.topic,
                     "user/{/[^/]+/}",
@@ -393,3 +511,111 @@ pub fn user_loop(mut connection: Connection, tx: Sender<Message>) {
 
     println!("User MQTT messages receiver exits. Connection is disconnected by client.");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::{Arc, mpsc}, time::Duration};
+
+    use crate::system::adapters::{PlugDriver, mapping::{MappedPlug, parse_mappings}, shelly::Gen1Plug, tasmota::TasmotaPlug};
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn shared_plug_reading_is_split_by_consumption() {
+        let miners = vec![(String::from("Miner00"), 100.0), (String::from("Miner01"), 200.0)];
+
+        assert_eq!(
+            split_plug_reading(&miners, 301, 300.0),
+            vec![(String::from("Miner00"), 100, 100.0), (String::from("Miner01"), 201, 200.0)]
+        );
+        assert_eq!(
+            split_plug_reading(&vec![(String::from("Miner00"), 100.0)], 17, 150.0),
+            vec![(String::from("Miner00"), 17, 150.0)]
+        );
+    }
+
+    #[test]
+    fn plug_energy_is_accounted_within_report_interval() {
+        let (tx_db, rx_db) = mpsc::channel();
+        let (tx_main, _rx_main) = mpsc::channel();
+        let now = Instant::now();
+        let get_plug = |driver: Arc<dyn PlugDriver>| PlugData {
+            driver,
+            last_received: Some(now - Duration::from_secs(300)),
+            miners: vec![(String::from("Miner00"), 100.0)],
+            energy_consumed: 1000,
+            phase: 1,
+            power: 120.0,
+        };
+
+        /* Tasmota reports every 300 s by default */
+        let mut plug = get_plug(Arc::new(TasmotaPlug::new("tasmota-1")));
+        assert!(update_plug_energy(&mut plug, 1600, now, &tx_db, &tx_main));
+        match rx_db.try_recv() {
+            Ok(EnergyData::Miner{name, ec, ..}) => assert_eq!((name.as_str(), ec), ("Miner00", 600)),
+            other => panic!("Unexpected plug reading: {:?}", other),
+        }
+
+        let mut plug = get_plug(Arc::new(Gen1Plug::new("shellyplug-s-1")));
+        assert!(update_plug_energy(&mut plug, 1600, now, &tx_db, &tx_main));
+        assert!(rx_db.try_recv().is_err());
+        assert_eq!((plug.energy_consumed, plug.last_received), (1600, Some(now)));
+    }
+
+    #[test]
+    fn sensor_reading_keeps_plug_alive() {
+        let (tx_db, _rx_db) = mpsc::channel();
+        let (tx_main, rx_main) = mpsc::channel();
+        let driver: Arc<dyn PlugDriver> = Arc::new(TasmotaPlug::new("tasmota-1"));
+        let mut plug = PlugData {
+            driver: driver.clone(),
+            last_received: None,
+            miners: vec![(String::from("Miner00"), 100.0)],
+            energy_consumed: 0,
+            phase: 1,
+            power: 0.0,
+        };
+        let status = driver.parse("tele/tasmota-1/SENSOR",
+            r#"{"Time": "2022-06-01T12:00:00", "ENERGY": {"Total": 12.345, "Power": 215}}"#);
+
+        assert!(handle_plug_status("tasmota-1", &mut plug, status, Instant::now(), &tx_db, &tx_main));
+        match rx_main.try_recv() {
+            Ok(Message::Plug{plug_id, is_on, ..}) => assert_eq!((plug_id.as_str(), is_on), ("tasmota-1", None)),
+            other => panic!("Unexpected plug message: {:?}", other),
+        }
+
+        /* Message without any reading does not refresh plug */
+        assert!(handle_plug_status("tasmota-1", &mut plug, PlugStatus::default(), Instant::now(), &tx_db, &tx_main));
+        assert!(rx_main.try_recv().is_err());
+    }
+
+    #[test]
+    fn mapped_reading_keeps_plug_without_state_alive() {
+        let (tx_db, _rx_db) = mpsc::channel();
+        let (tx_main, rx_main) = mpsc::channel();
+        let conf = YamlLoader::load_from_str(r#"
+mappings:
+  energy-plug:
+    kind: plug
+    energy: {topic: "{id}/energy", scale: 60000}
+    command: {topic: "{id}/set", on: "ON", off: "OFF"}
+"#).unwrap();
+        let mappings = parse_mappings(&conf[0]).unwrap();
+        let driver: Arc<dyn PlugDriver> = Arc::new(MappedPlug::new("plug-1", mappings.get("energy-plug").unwrap()));
+        let mut plug = PlugData {
+            driver: driver.clone(),
+            last_received: None,
+            miners: vec![(String::from("Miner00"), 100.0)],
+            energy_consumed: 0,
+            phase: 1,
+            power: 0.0,
+        };
+        let status = driver.parse("plug-1/energy", "1.5");
+
+        assert!(handle_plug_status("plug-1", &mut plug, status, Instant::now(), &tx_db, &tx_main));
+        match rx_main.try_recv() {
+            Ok(Message::Plug{plug_id, is_on, ..}) => assert_eq!((plug_id.as_str(), is_on), ("plug-1", None)),
+            other => panic!("Unexpected plug message: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use json::JsonValue;
+use rumqttc::{Client, QoS};
+use std::collections::HashMap;
+
+use super::structs::AccuracyStats;
+
+/* Statistics are averaged over all samples until window is filled, later older samples fade out */
+static ROLLING_WINDOW: u64 = 100;
+
+impl AccuracyStats {
+    #[allow(non_snake_case)]
+    fn update(&mut self, error_W: f64, grid_import_W: f64, wasted_export_W: f64) {
+        self.samples += 1;
+        let weight = 1.0 / self.samples.min(ROLLING_WINDOW) as f64;
+
+        self.mean_error_W += (error_W - self.mean_error_W) * weight;
+        self.mean_abs_error_W += (error_W.abs() - self.mean_abs_error_W) * weight;
+        self.mean_grid_import_W += (grid_import_W - self.mean_grid_import_W) * weight;
+        self.mean_wasted_export_W += (wasted_export_W - self.mean_wasted_export_W) * weight;
+    }
+}
+
+/* Export is wasted only when it could run the smallest miner which was not running on the phase,
+smaller export cannot be used by any miner */
+#[allow(non_snake_case)]
+pub fn get_wasted_export(export_W: &[f64], smallest_runnable_W: &[Option<f64>]) -> Vec<f64> {
+    export_W.iter().zip(smallest_runnable_W.iter())
+        .map(|(&export, smallest)| match smallest {
+            Some(smallest) if export >= *smallest => export,
+            _ => 0.0,
+        })
+        .collect()
+}
+
+/* Compares production assumed by scheduler with production measured in following interval */
+pub struct AccuracyTracker {
+    stats: HashMap<(u32, u32), Vec<AccuracyStats>>,
+    /* Time and production per phase assumed in last scheduling round */
+    assumption: Option<(NaiveDateTime, Vec<f64>)>,
+}
+
+impl AccuracyTracker {
+    pub fn new(stats: HashMap<(u32, u32), Vec<AccuracyStats>>) -> Self {
+        AccuracyTracker { stats, assumption: None }
+    }
+
+    #[allow(non_snake_case)]
+    pub fn set_assumption(&mut self, ts: NaiveDateTime, assumed_production_W: Vec<f64>) {
+        self.assumption = Some((ts, assumed_production_W));
+    }
+
+    /* Updates statistics of hour and month when assumption was made.
+    Returns (month, hour) and statistics for every phase, None when there was no assumption yet. */
+    #[allow(non_snake_case)]
+    pub fn evaluate(
+        &mut self,
+        measured_production_W: &[f64],
+        grid_import_W: &[f64],
+        wasted_export_W: &[f64]
+    ) -> Option<((u32, u32), Vec<AccuracyStats>)> {
+        let (ts, assumed_production_W) = self.assumption.take()?;
+        let key = (ts.month(), ts.hour());
+        let phases = assumed_production_W.len();
+
+        let stats = self.stats.entry(key).or_insert_with(|| vec![AccuracyStats::default(); phases]);
+        if stats.len() < phases {
+            stats.resize(phases, AccuracyStats::default());
+        }
+
+        for i in 0..phases {
+            stats[i].update(
+                assumed_production_W[i] - measured_production_W[i],
+                grid_import_W[i],
+                wasted_export_W[i]
+            );
+        }
+
+        Some((key, stats.clone()))
+    }
+}
+
+pub fn publish_accuracy(mqtt_client: &mut Client, (month, hour): (u32, u32), stats: &[AccuracyStats]) {
+    let mut accuracy = JsonValue::new_object();
+    accuracy["month"] = month.into();
+    accuracy["hour"] = hour.into();
+    accuracy["phases"] = JsonValue::new_array();
+
+    for (i, stats) in stats.iter().enumerate() {
+        let mut phase = JsonValue::new_object();
+        phase["phase"] = i.into();
+        phase["samples"] = stats.samples.into();
+        phase["mean_error_W"] = stats.mean_error_W.into();
+        phase["mean_abs_error_W"] = stats.mean_abs_error_W.into();
+        phase["mean_grid_import_W"] = stats.mean_grid_import_W.into();
+        phase["mean_wasted_export_W"] = stats.mean_wasted_export_W.into();
+        accuracy["phases"].push(phase).unwrap();
+    }
+
+    let topic = format!("mithra/accuracy/{:02}/{:02}", month, hour);
+    if let Err(error_msg) = mqtt_client.publish(topic, QoS::ExactlyOnce, true, accuracy.dump()) {
+        eprintln!("[Main loop] Publishing accuracy error: {}", error_msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn evaluates_previous_assumption() {
+        let mut tracker = AccuracyTracker::new(HashMap::new());
+        assert!(tracker.evaluate(&[0.0], &[0.0], &[0.0]).is_none());
+
+        let ts = NaiveDate::from_ymd_opt(2022, 6, 1).unwrap().and_hms_opt(12, 30, 0).unwrap();
+        tracker.set_assumption(ts, vec![1000.0, 500.0]);
+        let (key, stats) = tracker.evaluate(&[800.0, 700.0], &[150.0, 0.0], &[0.0, 120.0]).unwrap();
+
+        assert_eq!(key, (6, 12));
+        assert_eq!(stats[0].mean_error_W, 200.0);
+        assert_eq!(stats[1].mean_error_W, -200.0);
+        assert_eq!(stats[1].mean_abs_error_W, 200.0);
+        assert_eq!(stats[0].mean_grid_import_W, 150.0);
+        assert_eq!(stats[1].mean_wasted_export_W, 120.0);
+
+        /* Assumption is used only once */
+        assert!(tracker.evaluate(&[0.0, 0.0], &[0.0, 0.0], &[0.0, 0.0]).is_none());
+    }
+
+    #[test]
+    fn export_is_wasted_only_when_miner_could_use_it() {
+        assert_eq!(
+            get_wasted_export(&[150.0, 900.0, 900.0], &[Some(200.0), Some(800.0), None]),
+            vec![0.0, 900.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn rolling_mean_follows_recent_samples() {
+        let mut stats = AccuracyStats::default();
+        stats.update(100.0, 0.0, 0.0);
+        stats.update(-100.0, 0.0, 0.0);
+        assert_eq!(stats.mean_error_W, 0.0);
+        assert_eq!(stats.mean_abs_error_W, 100.0);
+
+        for _ in 0..10 * ROLLING_WINDOW {
+            stats.update(50.0, 0.0, 0.0);
+        }
+        assert!((stats.mean_error_W - 50.0).abs() < 0.01);
+    }
+}
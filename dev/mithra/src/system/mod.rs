@@ -3,6 +3,7 @@ use json::JsonValue;
 use postgres::{Config, NoTls};
 use rumqttc::{
     Client,
+    Connection,
     Event,
     MqttOptions,
     Outgoing,
@@ -20,8 +21,19 @@ use std::{
 };
 
 mod database;
+pub mod discovery;
+mod guard_protocol;
 mod handlers;
+mod metrics;
+mod optimizer;
+mod projection;
+mod scheduler;
+mod whatif;
+pub mod adapters;
+pub mod solar;
 pub mod structs;
+use adapters::{MeterSource, PolledMeter, fronius::FroniusInverter, mapping::DeviceMapping, shelly};
+use discovery::AnnouncedDevice;
 use structs::*;
 
 #[derive(Debug)]
@@ -32,6 +44,11 @@ pub struct MqttConfig {
     pub password: String,
 }
 
+/* Part of estimated consumption drawn by running plug-only miner */
+const PLUG_MINER_RUNNING_RATIO: f32 = 0.5;
+/* Running plug-only miner may draw less for a while, e.g. when its mining software restarts */
+const PLUG_MINER_DIP_SECONDS: i64 = 180;
+
 #[derive(Debug)]
 pub struct System {
     /* Contract data */
@@ -39,6 +56,12 @@ pub struct System {
     pub start_month: u32,
     pub billing_period: u32,
     pub recovery_ratio: f64,
+    pub balancing_mode: BalancingMode,
+    pub final_stretch_days: u32,
+    pub safety_margin_wh: f64,
+
+    /* Site location, optional */
+    pub site: Option<solar::Site>,
 
     /* Postgres configuration */
     pub db_config: Config,
@@ -48,9 +71,17 @@ pub struct System {
 
     /* Devices in system */
     pub switchboard: Switchboard,
+    pub inverter: Option<Inverter>,
     pub guards: HashMap<String, Guard>,
     pub miners: HashMap<String, Miner>,
     pub plugs: HashMap<String, Plug>,
+
+    /* Mapped device types from devices config */
+    pub device_mappings: HashMap<String, DeviceMapping>,
+    pub guard_types: HashMap<String, GuardType>,
+
+    /* Announced devices not defined in config file with time of last announce */
+    pub discovered_devices: HashMap<String, (AnnouncedDevice, NaiveDateTime)>,
 }
 
 impl System {
@@ -86,16 +117,18 @@ pub fn check_servers_connection(&self) -> Result<(), &'static str> {
 fn init(&mut self) -> 
 (
     (NaiveDateTime, NaiveDateTime),
-    ([f64; 3], [f64; 3]),
-    [u64; 3],
-    [u64; 3]
+    (Vec<f64>, Vec<f64>),
+    Vec<u64>,
+    Vec<u64>,
+    HashMap<(u32, u32), Vec<AccuracyStats>>
 ) {
     let mut mqtt_options = self.get_mqtt_options("Announce_loop");
     mqtt_options.set_keep_alive(5);
 
     let (mut client, mut connection) = Client::new(mqtt_options, 1024);
-    client.subscribe("shellies/announce", QoS::ExactlyOnce).unwrap();
-    client.subscribe("guards/announce", QoS::ExactlyOnce).unwrap();
+    client.subscribe(discovery::SHELLIES_ANNOUNCE_TOPIC, QoS::ExactlyOnce).unwrap();
+    client.subscribe(discovery::GUARDS_ANNOUNCE_TOPIC, QoS::ExactlyOnce).unwrap();
+    client.subscribe(shelly::get_response_topic(shelly::ANNOUNCE_SRC), QoS::ExactlyOnce).unwrap();
 
     if let Err(error_msg) = client.publish(
         "shellies/command",
@@ -117,6 +150,39 @@ fn init(&mut self) ->
         std::process::exit(1);
     }
 
+    let rpc_devices = self.get_rpc_devices();
+
+    /* Other devices report availability by retained message or are assumed to be available */
+    let meter = self.get_meter_source();
+    match &meter {
+        Some(meter) => if let Some(topic) = meter.get_availability_topic() {
+            client.subscribe(topic, QoS::ExactlyOnce).unwrap();
+        } else if meter.is_always_available() {
+            self.switchboard.state = DeviceState::Available;
+        },
+        /* Polled meter is available when it answers */
+        None => if let Some(mut meter) = self.get_polled_meter() {
+            match meter.read_totals() {
+                Ok(_) => self.switchboard.state = DeviceState::Available,
+                Err(error_msg) => eprintln!("Switchboard {} reading error: {}", self.switchboard.id, error_msg),
+            }
+        },
+    }
+    for (_, plug) in self.plugs.iter_mut() {
+        if let Some(topic) = plug.driver.get_availability_topic() {
+            client.subscribe(topic, QoS::ExactlyOnce).unwrap();
+        } else if plug.driver.is_always_available() {
+            plug.state = DeviceState::Available;
+        }
+    }
+
+    for device_id in rpc_devices {
+        if let Err(error_msg) = discovery::request_device_info(&mut client, &device_id) {
+            eprintln!("Shelly {} device info request error: {}", device_id, error_msg);
+            std::process::exit(1);
+        }
+    }
+
     let timer = Instant::now();
     let time = Duration::from_secs(5);
     println!("Announce message sent");
@@ -127,7 +193,7 @@ fn init(&mut self) ->
 
         match msg {
             Ok(Event::Incoming(Packet::Publish(data))) => {
-                self.parse_announce(&data);
+                self.parse_announce(&data, meter.as_deref());
             },
             Ok(Event::Outgoing(Outgoing::Disconnect)) => {
                 break;
@@ -153,10 +219,11 @@ fn init(&mut self) ->
     /* Check database schema and create required tables eventually */
     let period = current_biling_period(self.start_year as i32, self.start_month, self.billing_period);
     println!("Checking database tables for period from {:?} to {:?}", period.0, period.1);
-    database::check_db_schema(&mut db_client, period);
+    let phases = self.switchboard.board_type.get_phase_count();
+    database::check_db_schema(&mut db_client, period, phases);
 
     /* Obtaining power state of switchboard */
-    let switchboard_params = if let Some(params) = database::get_switchboard_params(&mut db_client, period.0) {
+    let switchboard_params = if let Some(params) = database::get_switchboard_params(&mut db_client, period.0, phases) {
         println!("Switchboard data obtained from database.");
         params
     } else {
@@ -166,13 +233,17 @@ fn init(&mut self) ->
     };
 
     /* Obtaining energy consumed by miners */
-    let miners_consumption = database::get_miners_consumption(&mut db_client, period.0);
+    let miners_consumption = database::get_miners_consumption(&mut db_client, period.0, phases);
     println!("Miners have consumed {:?} Wmin until now.", miners_consumption);
 
-    let miners_grid_consumption = database::get_miners_grid_consumption(&mut db_client, period.0);
+    let miners_grid_consumption = database::get_miners_grid_consumption(&mut db_client, period.0, phases);
     println!("Miners have consumed {:?} Wmin from grid until now.", miners_grid_consumption);
 
-    return (period, switchboard_params, miners_consumption, miners_grid_consumption);
+    /* Obtaining scheduling accuracy statistics gathered so far */
+    let accuracy_stats = database::get_accuracy_stats(&mut db_client, phases);
+    println!("Scheduling accuracy statistics obtained for {} hours.", accuracy_stats.len());
+
+    return (period, switchboard_params, miners_consumption, miners_grid_consumption, accuracy_stats);
 }
 
 
@@ -182,18 +253,15 @@ pub fn run(&mut self) {
         billing_period,
         (start_consumed_wh, start_returned_wh),
         mut miners_consumed_wmin,
-        mut miners_grid_consumed_wmin
+        mut miners_grid_consumed_wmin,
+        accuracy_stats
     ) = self.init();
 
     /* Creating all essentials channels */
     let (db_tx, db_rx) = mpsc::channel();
     let (main_tx, main_rx) = mpsc::channel();
 
-    /* Creating MQTT connection for every worker loop */
-    let mut mqtt_options = self.get_mqtt_options("Switchboard_loop");
-    mqtt_options.set_keep_alive(60);
-    let (mut switchboard_mqtt, switchboard_connection) = Client::new(mqtt_options, 1024);
-
+    /* Creating MQTT connection for every worker loop, switchboard one is created with its loop */
     let mut mqtt_options = self.get_mqtt_options("Miners_loop");
     mqtt_options.set_keep_alive(60);
     let (mut plugs_mqtt, plugs_connection) = Client::new(mqtt_options, 1024);
@@ -206,28 +274,22 @@ pub fn run(&mut self) {
     mqtt_options.set_keep_alive(60);
     let (mut user_mqtt, user_connection) = Client::new(mqtt_options, 1024);
 
+    let mut mqtt_options = self.get_mqtt_options("Discovery_loop");
+    mqtt_options.set_keep_alive(60);
+    let (mut announce_mqtt, announce_connection) = Client::new(mqtt_options, 1024);
+
     /* Subscribing all essentials topics */
 
-    /* Switchboard topics */
-    let switchboard_id = &self.switchboard.id;
-    let topics = vec![
-        format!("shellies/{}/emeter/+/energy", switchboard_id),
-        format!("shellies/{}/emeter/+/returned_energy", switchboard_id),
-        format!("shellies/{}/emeter/+/total", switchboard_id),
-        format!("shellies/{}/emeter/+/total_returned", switchboard_id),
-    ];
+    let phases = self.switchboard.board_type.get_phase_count();
 
-    for topic in topics {
-        switchboard_mqtt.subscribe(topic, QoS::ExactlyOnce).unwrap();
-    }
+    /* Plugs topics, relay state is subscribed permanently and asked for if plug does not publish it */
+    for (_, plug) in self.plugs.iter() {
+        plug_subscribe(&mut plugs_mqtt, plug);
 
-    /* Plugs topics */
-    for (_, miner) in self.miners.iter() {
-        plug_subscribe(&mut plugs_mqtt, &miner.plug_id);
-        plugs_mqtt.subscribe(
-            format!("shellies/{}/relay/0", miner.plug_id),
-            QoS::ExactlyOnce
-        ).unwrap();
+        for topic in plug.driver.get_state_topics() {
+            plugs_mqtt.subscribe(topic, QoS::ExactlyOnce).unwrap();
+        }
+        plug_query_state(&mut plugs_mqtt, plug);
     }
 
     /* Guards topics */
@@ -255,6 +317,13 @@ pub fn run(&mut self) {
     for (miner_id, _) in self.miners.iter() {
         user_mqtt.subscribe(format!("user/{}", miner_id), QoS::ExactlyOnce).unwrap();
     }
+    user_mqtt.subscribe(whatif::REQUEST_TOPIC, QoS::ExactlyOnce).unwrap();
+
+    /* Announce topics are subscribed permanently, devices booting later become available and unknown ones are discovered */
+    announce_mqtt.subscribe(discovery::SHELLIES_ANNOUNCE_TOPIC, QoS::ExactlyOnce).unwrap();
+    announce_mqtt.subscribe(discovery::GUARDS_ANNOUNCE_TOPIC, QoS::ExactlyOnce).unwrap();
+    announce_mqtt.subscribe(shelly::get_response_topic(shelly::ANNOUNCE_SRC), QoS::ExactlyOnce).unwrap();
+    discovery::publish_discovered(&mut user_mqtt, &self.discovered_devices);
 
     /* Spawn all workers */
     let db_thread = {
@@ -263,27 +332,77 @@ pub fn run(&mut self) {
     };
     println!("Database worker loop spawned.");
 
+    /* MQTT switchboard loop ends by disconnecting, polled one by closing stop channel */
+    let (switchboard_stop, stop_rx) = mpsc::channel();
+    let mut switchboard_mqtt = None;
     let switchboard_thread = {
         let db_tx = db_tx.clone();
         let main_tx = main_tx.clone();
-        thread::spawn(|| handlers::switchboard_loop(switchboard_connection, db_tx, main_tx))
+        match self.get_polled_meter() {
+            Some(meter) => thread::spawn(|| handlers::polled_meter_loop(meter, stop_rx, db_tx, main_tx)),
+            None => {
+                let mut mqtt_options = self.get_mqtt_options("Switchboard_loop");
+                mqtt_options.set_keep_alive(60);
+                let (mut client, connection) = Client::new(mqtt_options, 1024);
+
+                /* Switchboard topics, only channels measuring phases are subscribed */
+                let meter = self.get_meter_source().unwrap();
+                for topic in meter.get_topics() {
+                    client.subscribe(topic, QoS::ExactlyOnce).unwrap();
+                }
+
+                switchboard_mqtt = Some(client);
+                thread::spawn(|| handlers::switchboard_loop(connection, meter, db_tx, main_tx))
+            },
+        }
     };
     println!("Switchboard worker loop spawned.");
 
+    let (inverter_stop, inverter_stop_rx) = mpsc::channel();
+    let inverter_thread = self.inverter.as_ref().map(|inverter| {
+        let inverter = FroniusInverter::new(inverter);
+        let db_tx = db_tx.clone();
+        let main_tx = main_tx.clone();
+        let thread = thread::spawn(|| handlers::inverter_loop(inverter, inverter_stop_rx, db_tx, main_tx));
+        println!("Inverter worker loop spawned.");
+        thread
+    });
+
+    /* Miner API loop is stopped by closing its command channel */
+    let (miner_api_tx, miner_api_rx) = mpsc::channel();
+    let miner_api_thread = {
+        let miners: HashMap<String, (String, Option<SoftPower>)> = self.miners.iter()
+            .filter_map(|(id, miner)| miner.api.as_ref().map(|api| (id.clone(), (api.clone(), miner.soft_power.clone()))))
+            .collect();
+
+        if miners.is_empty() {
+            None
+        } else {
+            let main_tx = main_tx.clone();
+            let thread = thread::spawn(|| handlers::miner_api_loop(miners, miner_api_rx, main_tx));
+            println!("Miner API worker loop spawned.");
+            Some(thread)
+        }
+    };
+
     let plugs_thread = {
-        let mut miners = HashMap::new();
-        for (id, miner) in self.miners.iter() {
-            miners.insert(miner.plug_id.clone(), structs::MinerData{
+        /* Miners sharing plug are on the same phase */
+        let mut plugs = HashMap::new();
+        for (plug_id, plug) in self.plugs.iter() {
+            plugs.insert(plug_id.clone(), structs::PlugData{
+                driver: plug.driver.clone(),
                 last_received: None,
-                name: id.clone(),
+                miners: plug.miners.iter()
+                    .map(|miner_id| (miner_id.clone(), self.miners.get(miner_id).unwrap().estimated_consumption))
+                    .collect(),
                 energy_consumed: 0,
-                phase: miner.phase,
+                phase: self.miners.get(&plug.miners[0]).unwrap().phase,
                 power: 0.0,
             });
         }
         let db_tx = db_tx.clone();
         let main_tx = main_tx.clone();
-        thread::spawn(|| handlers::plugs_loop(plugs_connection, miners, db_tx, main_tx))
+        thread::spawn(|| handlers::plugs_loop(plugs_connection, plugs, db_tx, main_tx))
     };
     println!("Plugs worker loop spawned.");
     
@@ -294,29 +413,54 @@ pub fn run(&mut self) {
     println!("Guards worker loop spawned.");
     
     let user_thread = {
-        let main_tx = main_tx;
+        let main_tx = main_tx.clone();
         thread::spawn(|| handlers::user_loop(user_connection, main_tx))
     };
     println!("User worker loop spawned.");
 
-    let mut last_miners_consumed_wmin = [0; 3];
-    let mut last_switchboard_consumed_wmin = [0; 3];
-    let mut last_switchboard_returned_wmin = [0; 3];
-    let mut actual_total_consumed_wh = [0.0; 3];
-    let mut actual_total_returned_wh = [0.0; 3];
+    let announce_thread = {
+        let main_tx = main_tx;
+        thread::spawn(|| handlers::announce_loop(announce_connection, main_tx))
+    };
+    println!("Announce worker loop spawned.");
+
+    let mut last_miners_consumed_wmin = vec![0; phases];
+    let mut last_switchboard_consumed_wmin = vec![0; phases];
+    let mut last_switchboard_returned_wmin = vec![0; phases];
+    let mut last_inverter_produced_wmin = 0;
+    let mut inverter_received_msgs = 0;
+    let mut last_measured_production = None;
+    let mut actual_total_consumed_wh = vec![0.0; phases];
+    let mut actual_total_returned_wh = vec![0.0; phases];
 
     let mut switchboard_received_msgs = 0;
+    let mut projection_negative = false;
+    let mut night_reported = false;
+    let mut last_interval = None;
+    let mut accuracy = metrics::AccuracyTracker::new(accuracy_stats);
     let mut last_scheduling_ts = Instant::now();
+    let mut last_estimation_ts = Instant::now();
+    let mut estimation_consumed_wmin = vec![0; phases];
+    let mut estimation_returned_wmin = vec![0; phases];
+    let mut estimation_metered_wmin = vec![0; phases];
+    let mut estimation_switchboard_msgs = 0;
+    let mut last_device_info_ts = Instant::now();
 
     let mut deadline = Instant::now() + Duration::from_secs(60);
     let mut failure_exit = false;
 
     'main: loop {
         if failure_exit {
-            switchboard_mqtt.disconnect().unwrap();
+            if let Some(client) = switchboard_mqtt.as_mut() {
+                client.disconnect().unwrap();
+            }
+            drop(switchboard_stop);
+            drop(inverter_stop);
+            drop(miner_api_tx);
             plugs_mqtt.disconnect().unwrap();
             guards_mqtt.disconnect().unwrap();
             user_mqtt.disconnect().unwrap();
+            announce_mqtt.disconnect().unwrap();
             drop(db_tx);
             break;
         }
@@ -327,23 +471,32 @@ pub fn run(&mut self) {
                     /* Update local energy data */
                     let miner = self.miners.get_mut(&name).unwrap();
                     miner.power_consumption = Some(power);
+                    self.infer_plug_miner_state(&name);
     
                     let i = phase as usize;
                     miners_consumed_wmin[i] += ec;
                     last_miners_consumed_wmin[i] += ec;
+                    estimation_metered_wmin[i] += ec;
                 },
                 Message::Energy(EnergyData::Switchboard{ts, ec, er, tc, tr}) => {
                     /* Update local energy data */
                     self.switchboard.last_seen = ts;
-                    for i in 0..3 {
+                    for i in 0..phases {
                         last_switchboard_consumed_wmin[i] += ec[i];
                         last_switchboard_returned_wmin[i] += er[i];
+                        estimation_consumed_wmin[i] += ec[i];
+                        estimation_returned_wmin[i] += er[i];
                     }
+                    estimation_switchboard_msgs += 1;
                     actual_total_consumed_wh = tc;
                     actual_total_returned_wh = tr;
 
                     switchboard_received_msgs += 1;
                 },
+                Message::Energy(EnergyData::Production{ts: _, ep, power: _}) => {
+                    last_inverter_produced_wmin += ep;
+                    inverter_received_msgs += 1;
+                },
                 Message::Energy(_) => {
                     /* Mithra must not receive this type messages */
                     eprintln!("[Main loop] Received energy data that must not be sent to main channel!")
@@ -355,7 +508,48 @@ pub fn run(&mut self) {
                     let plug = self.plugs.get_mut(&plug_id).unwrap();
     
                     plug.last_seen = ts;
-                    plug.is_enabled = is_on;
+                    if let Some(is_on) = is_on {
+                        plug.is_enabled = is_on;
+                    }
+
+                    for miner_id in plug.miners.clone() {
+                        self.infer_plug_miner_state(&miner_id);
+                    }
+                },
+                Message::Announce{ts, device} => {
+                    self.handle_announce(ts, device, &mut guards_mqtt, &mut plugs_mqtt, &mut user_mqtt);
+                },
+                Message::Telemetry{miner_id, ts, telemetry} => {
+                    self.handle_telemetry_msg(&miner_id, ts, telemetry, &db_tx, &mut user_mqtt);
+                },
+                Message::MinerApi{miner_id, command, result} => {
+                    self.handle_miner_api_msg(&miner_id, command, result, &mut guards_mqtt, &mut plugs_mqtt);
+                },
+                Message::WhatIf(Ok(request)) => {
+                    let trends = projection::get_energy_trends(
+                        Utc::now().naive_utc(),
+                        billing_period.clone(),
+                        (
+                            &get_difference(&actual_total_consumed_wh, &start_consumed_wh),
+                            &get_difference(&actual_total_returned_wh, &start_returned_wh),
+                        ),
+                        &miners_grid_consumed_wmin,
+                        self.recovery_ratio,
+                    );
+
+                    let response = match self.what_if(&request, trends, &last_interval, &last_measured_production) {
+                        Ok(schedule) => whatif::get_response(&request.id, &schedule),
+                        Err(error_msg) => whatif::get_error_response(&request.id, error_msg),
+                    };
+                    if let Err(error_msg) = user_mqtt.publish(whatif::RESPONSE_TOPIC, QoS::ExactlyOnce, false, response) {
+                        eprintln!("[Main loop] Publishing what-if response error: {}", error_msg);
+                    }
+                },
+                Message::WhatIf(Err(error_msg)) => {
+                    let response = whatif::get_error_response(&None, error_msg);
+                    if let Err(error_msg) = user_mqtt.publish(whatif::RESPONSE_TOPIC, QoS::ExactlyOnce, false, response) {
+                        eprintln!("[Main loop] Publishing what-if response error: {}", error_msg);
+                    }
                 },
                 Message::User{miner_id, command} => {
                     let miner = self.miners.get_mut(&miner_id).unwrap();
@@ -365,17 +559,34 @@ pub fn run(&mut self) {
                             if miner.included {
                                 miner.included = false;
                                 miner.state = MinerState::Undefined;
-                                plug_unsubscribe(&mut plugs_mqtt, &miner.guard);
-                                miner_unsubscribe(&mut guards_mqtt, &miner.guard, &miner_id);
+                                if let Some(guard_id) = &miner.guard {
+                                    miner_unsubscribe(&mut guards_mqtt, guard_id, &miner_id);
+                                }
+                                /* Shared plug is still read for miners left included */
+                                if let Some(plug_id) = miner.plug_id.clone() {
+                                    let plug = self.plugs.get(&plug_id).unwrap();
+                                    if plug.miners.iter().all(|miner_id| !self.miners.get(miner_id).unwrap().included) {
+                                        plug_unsubscribe(&mut plugs_mqtt, plug);
+                                    }
+                                }
                             } else {
                                 eprintln!("[Main loop] User tried to exclude excluded miner = {}", miner_id);
                             }
                         },
                         UserCommands::Include => {
                             if !miner.included {
-                                plug_subscribe(&mut plugs_mqtt, &miner.guard);
-                                miner_subscribe(&mut guards_mqtt, &miner.guard, &miner_id);
-                                guard_send_command(&mut guards_mqtt, &miner.guard, &miner_id, "StateReport");
+                                let plug = miner.plug_id.as_ref().map(|plug_id| self.plugs.get(plug_id).unwrap());
+                                if let Some(plug) = plug {
+                                    plug_subscribe(&mut plugs_mqtt, plug);
+                                }
+                                match (&miner.guard, plug) {
+                                    (Some(guard_id), _) => {
+                                        miner_subscribe(&mut guards_mqtt, guard_id, &miner_id);
+                                        guard_send_command(&mut guards_mqtt, guard_id, &miner_id, "StateReport");
+                                    },
+                                    (None, Some(plug)) => plug_query_state(&mut plugs_mqtt, plug),
+                                    (None, None) => {},
+                                }
                                 miner.included = true;
                                 miner.state = MinerState::Undefined;
                                 miner.target_state = None;
@@ -397,8 +608,10 @@ pub fn run(&mut self) {
                     &switchboard_thread,
                     &plugs_thread,
                     &guards_thread,
-                    &user_thread
-                ].iter().all(|&t| t.is_running());
+                    &user_thread,
+                    &announce_thread
+                ].iter().all(|&t| t.is_running())
+                    && [&inverter_thread, &miner_api_thread].iter().all(|t| t.as_ref().map_or(true, |t| t.is_running()));
 
                 if !are_threads_running {
                     failure_exit = true;
@@ -406,7 +619,62 @@ pub fn run(&mut self) {
                 }
 
                 /* Validate devices status */
-                self.validate_devices(&mut guards_mqtt, &mut plugs_mqtt);
+                self.validate_devices(&mut guards_mqtt, &mut plugs_mqtt, &miner_api_tx);
+
+                /* Energy of miners without plug is taken from switchboard readings since last estimation,
+                it is subtracted from switchboard reading as energy of metered miners */
+                if estimation_switchboard_msgs > 0 || self.switchboard.state != DeviceState::Available {
+                    let ts = Utc::now().naive_utc();
+                    let trends = projection::get_energy_trends(
+                        ts,
+                        billing_period.clone(),
+                        (
+                            &get_difference(&actual_total_consumed_wh, &start_consumed_wh),
+                            &get_difference(&actual_total_returned_wh, &start_returned_wh),
+                        ),
+                        &miners_grid_consumed_wmin,
+                        self.recovery_ratio,
+                    );
+                    let switchboard_wmin = if estimation_switchboard_msgs > 0 {
+                        Some((&estimation_consumed_wmin, &estimation_returned_wmin))
+                    } else {
+                        None
+                    };
+
+                    for (name, ec, phase, power) in estimate_unmetered_miners(
+                        &self.miners,
+                        last_estimation_ts.elapsed(),
+                        switchboard_wmin,
+                        &estimation_metered_wmin,
+                        (trends.avg_consumption_W / phases as f64).max(0.0),
+                    ) {
+                        let i = phase as usize;
+                        miners_consumed_wmin[i] += ec;
+                        last_miners_consumed_wmin[i] += ec;
+
+                        if let Err(_) = db_tx.send(EnergyData::Miner{ts, name, ec, phase, power}) {
+                            eprintln!("[Main loop] - Database channel is closed!");
+                        }
+                    }
+
+                    last_estimation_ts = Instant::now();
+                    estimation_switchboard_msgs = 0;
+                    for i in 0..phases {
+                        estimation_consumed_wmin[i] = 0;
+                        estimation_returned_wmin[i] = 0;
+                        estimation_metered_wmin[i] = 0;
+                    }
+                }
+
+                /* Gen2 devices rebooted later are found by device info they answer */
+                if last_device_info_ts.elapsed() >= discovery::DEVICE_INFO_INTERVAL {
+                    for device_id in self.get_rpc_devices() {
+                        if let Err(error_msg) = discovery::request_device_info(&mut announce_mqtt, &device_id) {
+                            eprintln!("[Main loop] Shelly {} device info request error: {}", device_id, error_msg);
+                        }
+                    }
+                    last_device_info_ts = Instant::now();
+                }
 
                 /* Check is it scheduling time */
                 if self.switchboard.state != DeviceState::Available {
@@ -417,7 +685,9 @@ pub fn run(&mut self) {
 
                     last_scheduling_ts = Instant::now();
                     switchboard_received_msgs = 0;
-                    for i in 0..3 {
+                    last_inverter_produced_wmin = 0;
+                    inverter_received_msgs = 0;
+                    for i in 0..phases {
                         last_switchboard_consumed_wmin[i] = 0;
                         last_switchboard_returned_wmin[i] = 0;
                         last_miners_consumed_wmin[i] = 0;
@@ -425,13 +695,18 @@ pub fn run(&mut self) {
 
                 } else if switchboard_received_msgs >= 5 {
                     /* Calculate how much energy miners consumed from grid */
-                    for i in 0..3 {
-                        let consumed_from_grid = last_miners_consumed_wmin[i].min(last_switchboard_consumed_wmin[i]);
-                        miners_grid_consumed_wmin[i] += consumed_from_grid;
+                    let consumed_from_grid = self.get_miners_grid_consumption(
+                        &last_miners_consumed_wmin,
+                        &last_switchboard_consumed_wmin,
+                        &last_switchboard_returned_wmin,
+                    );
+
+                    for i in 0..phases {
+                        miners_grid_consumed_wmin[i] += consumed_from_grid[i];
 
                         if let Err(_) = db_tx.send(EnergyData::MinersGrid{
                             ts: Utc::now().naive_utc(),
-                            ec: consumed_from_grid,
+                            ec: consumed_from_grid[i],
                             phase: i as u8,
                         }) {
                             eprintln!("[Main loop] - Database channel is closed!");
@@ -440,40 +715,128 @@ pub fn run(&mut self) {
                         }
                     }
 
+                    /* Report when morning production should appear */
+                    if let Some(site) = &self.site {
+                        let ts = Utc::now().naive_utc();
+                        let is_night = solar::is_night(site, ts);
+                        if is_night && !night_reported {
+                            match solar::get_next_sunrise(site, ts) {
+                                Some(sunrise) => println!("[Main loop] Night has started, production expected after {}.", sunrise),
+                                None => println!("[Main loop] Night has started, no sunrise in next 48 hours."),
+                            }
+                        }
+                        night_reported = is_night;
+                    }
+
                     /* Obtain all running and runnable miners */
                     let (running_miners, runnable_miners) = self.collect_miners();
+                    let smallest_runnable_w: Vec<Option<f64>> = runnable_miners.iter()
+                        .map(|miners| miners.iter().map(|(_, power)| *power).reduce(f64::min))
+                        .collect();
                     
+                    /* Production measured by inverter is used only when it has reported in this interval */
+                    let measured_production = if inverter_received_msgs > 0 {
+                        self.get_phase_production(last_inverter_produced_wmin)
+                    } else {
+                        None
+                    };
+
                     /* Schedule resources */
                     let now = Instant::now() ;
-                    let (miners_to_run, miners_to_stop) = self.schedule_energy_resources(
-                        (running_miners, runnable_miners),
+                    let trends = projection::get_energy_trends(
+                        Utc::now().naive_utc(),
                         billing_period.clone(),
                         (
-                            [
-                                actual_total_consumed_wh[0] - start_consumed_wh[0],
-                                actual_total_consumed_wh[1] - start_consumed_wh[1],
-                                actual_total_consumed_wh[2] - start_consumed_wh[2],
-                            ],
-                            [
-                                actual_total_returned_wh[0] - start_returned_wh[0],
-                                actual_total_returned_wh[1] - start_returned_wh[1],
-                                actual_total_returned_wh[2] - start_returned_wh[2],
-                            ],
+                            &get_difference(&actual_total_consumed_wh, &start_consumed_wh),
+                            &get_difference(&actual_total_returned_wh, &start_returned_wh),
                         ),
-                        miners_grid_consumed_wmin.clone(),
+                        &miners_grid_consumed_wmin,
+                        self.recovery_ratio,
+                    );
+                    let schedule = scheduler::schedule_energy_resources(
+                        &self.get_scheduling_params(),
+                        Utc::now().naive_utc(),
+                        (running_miners, runnable_miners),
+                        &trends,
                         (
                             last_switchboard_consumed_wmin.clone(),
                             last_switchboard_returned_wmin.clone(),
                         ),
                         last_miners_consumed_wmin.clone(),
+                        measured_production.clone(),
                         now - last_scheduling_ts,
 
                     );
 
+                    /* Compare production assumed in last round with what really happened */
+                    let minutes = (now - last_scheduling_ts).as_secs_f64() / 60.0;
+                    let grid_import_w: Vec<f64> = consumed_from_grid.iter()
+                        .map(|&wmin| wmin as f64 / minutes)
+                        .collect();
+                    let export_w: Vec<f64> = last_switchboard_returned_wmin.iter()
+                        .map(|&wmin| wmin as f64 / minutes)
+                        .collect();
+                    let wasted_export_w = metrics::get_wasted_export(&export_w, &smallest_runnable_w);
+
+                    if let Some(((month, hour), stats)) = accuracy.evaluate(
+                        &schedule.measured_production_W,
+                        &grid_import_w,
+                        &wasted_export_w
+                    ) {
+                        metrics::publish_accuracy(&mut user_mqtt, (month, hour), &stats);
+
+                        for (i, stats) in stats.into_iter().enumerate() {
+                            if let Err(_) = db_tx.send(EnergyData::Accuracy{month, hour, phase: i as u8, stats}) {
+                                eprintln!("[Main loop] - Database channel is closed!");
+                                failure_exit = true;
+                                continue 'main;
+                            }
+                        }
+                    }
+                    accuracy.set_assumption(Utc::now().naive_utc(), schedule.assumed_production_W.clone());
+
+                    let scheduler::Schedule { to_run: miners_to_run, to_stop: miners_to_stop, .. } = schedule;
+
+                    /* Project balance at the end of billing period and warn when we would pay a bill */
+                    let ts = Utc::now().naive_utc();
+                    let projected_balance_wh = trends.get_projected_balance();
+                    projection::publish_projection(&mut user_mqtt, ts, billing_period.1, &trends);
+
+                    if let Err(_) = db_tx.send(EnergyData::Projection{
+                        ts,
+                        balance_wh: trends.get_balance(),
+                        projected_balance_wh,
+                    }) {
+                        eprintln!("[Main loop] - Database channel is closed!");
+                        failure_exit = true;
+                        continue 'main;
+                    }
+
+                    if projected_balance_wh < 0.0 && !projection_negative {
+                        let message = format!(
+                            "Projected balance at the end of billing period is {:.1} Wh, bill will have to be paid",
+                            projected_balance_wh
+                        );
+                        eprintln!("[Main loop] Warning: {}", message);
+                        projection::publish_warning(&mut user_mqtt, ts, "negative_projection", message);
+                    }
+                    projection_negative = projected_balance_wh < 0.0;
+
+                    /* Keep last interval for what-if queries */
+                    last_interval = Some((
+                        last_switchboard_consumed_wmin.clone(),
+                        last_switchboard_returned_wmin.clone(),
+                        last_miners_consumed_wmin.clone(),
+                        now - last_scheduling_ts,
+                    ));
+                    last_measured_production = measured_production;
+
                     /* Reinitialize variables before next scheduling  */
                     last_scheduling_ts = now;
                     switchboard_received_msgs = 0;
-                    for i in 0..3 {
+                    last_inverter_produced_wmin = 0;
+                    inverter_received_msgs = 0;
+                    for i in 0..phases {
                         last_switchboard_consumed_wmin[i] = 0;
                         last_switchboard_returned_wmin[i] = 0;
                         last_miners_consumed_wmin[i] = 0;
@@ -490,15 +853,47 @@ pub fn run(&mut self) {
                         miner.target_state = Some(MinerState::PoweredOff);
                     }
 
-                    self.validate_devices(&mut guards_mqtt, &mut plugs_mqtt);
+                    self.validate_devices(&mut guards_mqtt, &mut plugs_mqtt, &miner_api_tx);
                 }
 
                 /* If billing period is ending then disconnect mqtt clients and put thread to sleep until new period start */
                 if billing_period.1 - Utc::now().naive_utc() < chrono::Duration::seconds(60) {
-                    switchboard_mqtt.disconnect().unwrap();
+                    /* Report how close to zero the period has ended */
+                    let consumed_wh = actual_total_consumed_wh.iter().sum::<f64>() - start_consumed_wh.iter().sum::<f64>();
+                    let returned_wh = actual_total_returned_wh.iter().sum::<f64>() - start_returned_wh.iter().sum::<f64>();
+                    let balance_wh = returned_wh * self.recovery_ratio - consumed_wh;
+                    println!(
+                        "Billing period from {} to {} ends with balance {:.1} Wh.",
+                        billing_period.0, billing_period.1, balance_wh
+                    );
+
+                    let mut summary = JsonValue::new_object();
+                    summary["period_start"] = billing_period.0.to_string().into();
+                    summary["period_end"] = billing_period.1.to_string().into();
+                    summary["balance_wh"] = balance_wh.into();
+                    if let Err(error_msg) = user_mqtt.publish("mithra/period", QoS::ExactlyOnce, true, summary.dump()) {
+                        eprintln!("[Main loop] Publishing period summary error: {}", error_msg);
+                    }
+
+                    if let Err(_) = db_tx.send(EnergyData::PeriodSummary{
+                        ts: Utc::now().naive_utc(),
+                        period_start: billing_period.0,
+                        period_end: billing_period.1,
+                        balance_wh,
+                    }) {
+                        eprintln!("[Main loop] - Database channel is closed!");
+                    }
+
+                    if let Some(client) = switchboard_mqtt.as_mut() {
+                        client.disconnect().unwrap();
+                    }
+                    drop(switchboard_stop);
+                    drop(inverter_stop);
+                    drop(miner_api_tx);
                     plugs_mqtt.disconnect().unwrap();
                     guards_mqtt.disconnect().unwrap();
                     user_mqtt.disconnect().unwrap();
+                    announce_mqtt.disconnect().unwrap();
                     drop(db_tx);
 
                     thread::sleep(Duration::from_secs(60));
@@ -527,6 +922,19 @@ pub fn run(&mut self) {
     if let Err(error_msg) = user_thread.join() {
         eprintln!("User loop thread paniced: {:?}", error_msg);
     }
+    if let Err(error_msg) = announce_thread.join() {
+        eprintln!("Announce loop thread paniced: {:?}", error_msg);
+    }
+    if let Some(inverter_thread) = inverter_thread {
+        if let Err(error_msg) = inverter_thread.join() {
+            eprintln!("Inverter loop thread paniced: {:?}", error_msg);
+        }
+    }
+    if let Some(miner_api_thread) = miner_api_thread {
+        if let Err(error_msg) = miner_api_thread.join() {
+            eprintln!("Miner API loop thread paniced: {:?}", error_msg);
+        }
+    }
 
     if failure_exit {
         eprintln!("System is not in valid state. Exiting with failure!");
@@ -536,94 +944,208 @@ pub fn run(&mut self) {
     }
 }
 
-fn parse_announce(&mut self, data: &Publish) {
-    let device = json::parse(
-        std::str::from_utf8(&data.payload).unwrap()
-    ).unwrap();
+fn parse_announce(&mut self, data: &Publish, meter: Option<&dyn MeterSource>) {
+    /* Availability messages are not json in general */
+    let payload = std::str::from_utf8(&data.payload).unwrap();
+    if let Some(meter) = meter {
+        if meter.get_availability_topic().as_ref() == Some(&data.topic) {
+            if meter.is_online(payload) {
+                self.switchboard.state = DeviceState::Available;
+            }
+            return;
+        }
+    }
+    for (_, plug) in self.plugs.iter_mut() {
+        if plug.driver.get_availability_topic().as_ref() == Some(&data.topic) {
+            if plug.driver.is_online(payload) {
+                plug.state = DeviceState::Available;
+            }
+            return;
+        }
+    }
 
-    if data.topic == "shellies/announce" {
-        let id = device["id"].as_str().unwrap();
-        let dev_type =  ShellyType::from_str(device["model"].as_str().unwrap());
+    match discovery::parse_announce(&data.topic, payload) {
+        Ok(device) => if let Err(error_msg) = self.announce_device(device, Utc::now().naive_utc()) {
+            eprintln!("{}", error_msg);
+            std::process::exit(1);
+        },
+        Err(error_msg) => eprintln!("{}", error_msg),
+    }
+}
 
-        match dev_type {
-            Ok(ShellyType::SHEM_3) => {
-                /* It is switchboard */
-                if self.switchboard.id == id {
-                    self.switchboard.state = DeviceState::Available;
-                } else {
-                    return;
-                }
-            },
-            Ok(ShellyType::SHPLG_S) => {
-                /* It is plug */
-                if let Some(plug) = self.plugs.get_mut(id) {
-                    plug.state = DeviceState::Available;
-                }
-                else {
-                    return;
-                }
-            },
-            Err(_) => {}
+/* Gen2 devices have no broadcast announce, so every configured one is asked for its info */
+fn get_rpc_devices(&self) -> Vec<String> {
+    let mut rpc_devices = vec![];
+    if let MeterType::Shelly(shelly_type) = &self.switchboard.board_type {
+        if shelly_type.uses_rpc() {
+            rpc_devices.push(self.switchboard.id.clone());
         }
-    } else if data.topic == "guards/announce" {
-        let guard_id = device["id"].as_str().unwrap();
-        let dev_type = match GuardType::from_str(device["type"].as_str().unwrap()) {
-            Ok(t) => t,
-            Err(error_msg ) => {
-                eprintln!("{}", error_msg);
-                std::process::exit(1);
+    }
+    for (plug_id, plug) in self.plugs.iter() {
+        if let PlugType::Shelly(shelly_type) = &plug.plug_type {
+            if shelly_type.uses_rpc() {
+                rpc_devices.push(plug_id.clone());
             }
-        };
+        }
+    }
+    rpc_devices
+}
 
-        let guard = if let Some(guard) = self.guards.get_mut(guard_id) {
-            if guard.board_type != dev_type {
-                eprintln!("Guard {} has different board type than in config file", guard_id);
-                std::process::exit(1);
-            } 
-            guard.state = DeviceState::ConfigExpired;
-            guard
+/* Returns whether device is defined in config file, other devices are recorded as discovered */
+fn announce_device(&mut self, device: AnnouncedDevice, ts: NaiveDateTime) -> Result<bool, String> {
+    let is_defined = match &device {
+        AnnouncedDevice::Shelly{id, model} => self.announce_shelly(id, ShellyType::from_str(model))?,
+        AnnouncedDevice::Guard{id, board_type, version, miners} => self.announce_guard(id, board_type, *version, miners)?,
+    };
+
+    if !is_defined {
+        let id = String::from(device.get_id());
+        if !self.discovered_devices.contains_key(&id) {
+            println!("Discovered device {} which is not defined in config file", id);
         }
-        else {
-            /* Guard is not defined in config file */
-            return;
-        };
+        self.discovered_devices.insert(id, (device, ts));
+    }
 
-        let mut guard_miners = guard.miners.clone();
-
-        if let JsonValue::Array(miners) = &device["miners"] {
-            for miner in miners {
-                let miner_id = miner["id"].as_str().unwrap();
-                let pinset = miner["pinset"].as_u32().unwrap();
-                if let Some(miner) = self.miners.get_mut(miner_id) {
-                    /* Check miner_id from announce cover system data from config file */
-                    if miner.id != miner_id || miner.pinset != pinset {
-                        /* Miner has different configuration */
-                        continue;
-                    }
+    Ok(is_defined)
+}
 
-                    if let Some(position) = guard_miners.iter().position(|x| x == miner_id) {
-                        guard_miners.swap_remove(position);
-                    }
-                } else {
-                    /* Miner is not defined in config file */
-                    eprintln!("Miner {} is not configured in config file", miner_id);
-                    std::process::exit(1);
-                }
+fn announce_guard(&mut self, guard_id: &str, board_type: &str, version: Option<u32>, miners: &Vec<(String, u32)>) -> Result<bool, String> {
+    let dev_type = match self.guard_types.get(board_type) {
+        Some(t) => t,
+        None => return Err(format!("Guard type {} is not described in config file", board_type)),
+    };
+
+    let guard = if let Some(guard) = self.guards.get_mut(guard_id) {
+        if guard.board_type != *dev_type {
+            return Err(format!("Guard {} has different board type than in config file", guard_id));
+        } 
+        guard.state = DeviceState::ConfigExpired;
+        guard
+    }
+    else {
+        /* Guard is not defined in config file */
+        return Ok(false);
+    };
+
+    /* Guard with other protocol is reset to acknowledge version of config it gets */
+    if !guard_protocol::is_compatible(version) {
+        return Ok(true);
+    }
+
+    let mut guard_miners = guard.miners.clone();
+
+    for (miner_id, pinset) in miners {
+        if *pinset >= guard.board_type.get_pinset_limit() {
+            return Err(format!("Guard {} announced pinset {} out of its board", guard_id, pinset));
+        }
+        if let Some(miner) = self.miners.get_mut(miner_id) {
+            /* Check miner_id from announce cover system data from config file */
+            if miner.id != *miner_id || miner.pinset != *pinset {
+                /* Miner has different configuration */
+                continue;
             }
 
-            if guard_miners.is_empty() {
-                guard.state = DeviceState::Available;
+            if let Some(position) = guard_miners.iter().position(|x| x == miner_id) {
+                guard_miners.swap_remove(position);
             }
         } else {
-            eprintln!("Guard {} sent improper json format", guard_id);
-            std::process::exit(1);
+            /* Miner is not defined in config file */
+            return Err(format!("Miner {} is not configured in config file", miner_id));
         }
-    } else {
-        /* Got msg from unsubscribed topic */
-        eprintln!("Got message from undefined topic: {}", data.topic);
+    }
+
+    if guard_miners.is_empty() {
+        guard.state = DeviceState::Available;
+    }
+
+    Ok(true)
+}
+
+/* Returns whether device is defined in config file */
+fn announce_shelly(&mut self, id: &str, dev_type: Result<ShellyType, String>) -> Result<bool, String> {
+    match dev_type {
+        Ok(ShellyType::SHEM) | Ok(ShellyType::SHEM_3) | Ok(ShellyType::PRO_3EM) => {
+            /* It is switchboard */
+            if self.switchboard.id == id {
+                if dev_type.as_ref().map(|&dev_type| MeterType::Shelly(dev_type)) != Ok(self.switchboard.board_type.clone()) {
+                    return Err(format!("Switchboard {} has different type than in config file", id));
+                }
+                self.switchboard.state = DeviceState::Available;
+                return Ok(true);
+            }
+        },
+        Ok(ShellyType::SHPLG_S) | Ok(ShellyType::PLUS_PLUG_S) => {
+            /* It is plug */
+            if let Some(plug) = self.plugs.get_mut(id) {
+                if dev_type.as_ref().map(|&dev_type| PlugType::Shelly(dev_type)) != Ok(plug.plug_type.clone()) {
+                    return Err(format!("Plug {} has different type than in config file", id));
+                }
+                plug.state = DeviceState::Available;
+                return Ok(true);
+            }
+        },
+        Err(_) => {}
+    }
+    Ok(false)
+}
+
+/* Device announcing after start up, guard which is running already is left as it is
+and guard with expired config is reset to be configured again */
+fn handle_announce(&mut self, ts: NaiveDateTime, device: AnnouncedDevice, guards_mqtt: &mut Client, plugs_mqtt: &mut Client, user_mqtt: &mut Client) {
+    if let AnnouncedDevice::Guard{id, ..} = &device {
+        match self.guards.get(id).map(|guard| &guard.state) {
+            Some(DeviceState::Available) | Some(DeviceState::StartingUp) => return,
+            _ => {},
+        }
+    }
+
+    match self.announce_device(device.clone(), ts) {
+        Ok(true) => match &device {
+            AnnouncedDevice::Shelly{id, ..} => if let Some(plug) = self.plugs.get_mut(id) {
+                plug.last_seen = ts;
+                plug_query_state(plugs_mqtt, plug);
+            },
+            AnnouncedDevice::Guard{id, ..} => {
+                let guard = self.guards.get_mut(id).unwrap();
+                guard.last_seen = ts;
+
+                if guard.state == DeviceState::ConfigExpired {
+                    guard_reset(guards_mqtt, id);
+                } else {
+                    for miner_id in guard.miners.iter() {
+                        let miner = self.miners.get_mut(miner_id).unwrap();
+                        if miner.included {
+                            guard_send_command(guards_mqtt, id, miner_id, "StateReport");
+                            miner.state = MinerState::Undefined;
+                            miner.command_ts = Some(Utc::now().naive_utc());
+                        }
+                    }
+                }
+            },
+        },
+        Ok(false) => discovery::publish_discovered(user_mqtt, &self.discovered_devices),
+        Err(error_msg) => eprintln!("[Main loop] {}", error_msg),
     }
 }
 
+fn get_meter_source(&self) -> Option<Box<dyn MeterSource>> {
+    adapters::get_meter_source(&self.switchboard.board_type, &self.switchboard.id, &self.device_mappings)
+}
+
+/* Inverter production is spread evenly over phases it feeds */
+fn get_phase_production(&self, produced_wmin: u64) -> Option<Vec<f64>> {
+    let inverter = self.inverter.as_ref()?;
+    let mut production = vec![0.0; self.switchboard.board_type.get_phase_count()];
+    for &phase in inverter.phases.iter() {
+        production[phase as usize] = produced_wmin as f64 / inverter.phases.len() as f64;
+    }
+    Some(production)
+}
+
+fn get_polled_meter(&self) -> Option<Box<dyn PolledMeter>> {
+    adapters::get_polled_meter(&self.switchboard.board_type)
+}
+
 fn get_mqtt_options(&self, client_id: &str) -> MqttOptions {
     let mut mqtt_options = MqttOptions::new(
         client_id,
@@ -637,23 +1159,46 @@ fn get_mqtt_options(&self, client_id: &str) -> MqttOptions {
     return mqtt_options;
 } 
 
-fn get_switchboard_data(&self) -> ([f64; 3], [f64; 3]) {
+fn get_switchboard_data(&self) -> (Vec<f64>, Vec<f64>) {
+    if let Some(mut meter) = self.get_polled_meter() {
+        match meter.read_totals() {
+            Ok((consumed, returned)) => return (
+                consumed.into_iter().map(|x| x.ceil()).collect(),
+                returned.into_iter().map(|x| x.ceil()).collect(),
+            ),
+            Err(error_msg) => {
+                eprintln!("Switchboard {} reading error: {}", self.switchboard.id, error_msg);
+                std::process::exit(1);
+            },
+        }
+    }
+
     let mut mqtt_options = self.get_mqtt_options("Initial_loop");
     mqtt_options.set_keep_alive(5);
     let (mut client, mut connection) = Client::new(mqtt_options, 128);
 
-    let topic_consumed = format!("shellies/{}/emeter/+/total", self.switchboard.id);
-    let topic_returned = format!("shellies/{}/emeter/+/total_returned", self.switchboard.id);
+    let phases = self.switchboard.board_type.get_phase_count();
+    match &self.switchboard.board_type {
+        MeterType::Shelly(shelly_type) if shelly_type.uses_rpc() => {
+            return self.get_switchboard_rpc_data(client, connection, phases);
+        },
+        MeterType::Mapped(..) => return self.get_switchboard_mapped_data(client, connection),
+        _ => {},
+    }
 
-    client.subscribe(topic_consumed, QoS::ExactlyOnce).unwrap();
-    client.subscribe(topic_returned, QoS::ExactlyOnce).unwrap();
-    
+    for i in 0..phases {
+        let topic_consumed = format!("shellies/{}/emeter/{}/total", self.switchboard.id, i);
+        let topic_returned = format!("shellies/{}/emeter/{}/total_returned", self.switchboard.id, i);
 
-    let mut consumed = [None; 3];
-    let mut returned = [None; 3];
+        client.subscribe(topic_consumed, QoS::ExactlyOnce).unwrap();
+        client.subscribe(topic_returned, QoS::ExactlyOnce).unwrap();
+    }
+
+    let mut consumed = vec![None; phases];
+    let mut returned = vec![None; phases];
 
     for msg in connection.iter() {
-        if let ([Some(_), Some(_), Some(_)], [Some(_), Some(_), Some(_)]) = (consumed, returned) {
+        if consumed.iter().chain(returned.iter()).all(|x| x.is_some()) {
             client.disconnect().unwrap();
             break;
         }
@@ -673,12 +1218,159 @@ fn get_switchboard_data(&self) -> ([f64; 3], [f64; 3]) {
         }
     }
 
-    let consumed = [consumed[0].unwrap(), consumed[1].unwrap(), consumed[2].unwrap()];
-    let returned = [returned[0].unwrap(), returned[1].unwrap(), returned[2].unwrap()];
+    let consumed = consumed.into_iter().map(|x| x.unwrap()).collect();
+    let returned = returned.into_iter().map(|x| x.unwrap()).collect();
 
     return (consumed, returned);
 }
 
+/* Totals of mapped meter are known after its first complete reading */
+fn get_switchboard_mapped_data(&self, mut client: Client, mut connection: Connection) -> (Vec<f64>, Vec<f64>) {
+    let mut meter = self.get_meter_source().unwrap();
+    for topic in meter.get_topics() {
+        client.subscribe(topic, QoS::ExactlyOnce).unwrap();
+    }
+
+    for msg in connection.iter() {
+        match msg {
+            Ok(Event::Incoming(Packet::Publish(data))) => {
+                meter.parse(&data.topic, std::str::from_utf8(&data.payload).unwrap());
+                if let Some((consumed, returned)) = meter.get_totals() {
+                    client.disconnect().unwrap();
+                    return (
+                        consumed.into_iter().map(|x| x.ceil()).collect(),
+                        returned.into_iter().map(|x| x.ceil()).collect(),
+                    );
+                }
+            },
+            Ok(_) => (), 
+            Err(_) => (),
+        }
+    }
+
+    eprintln!("Switchboard {} did not publish energy data", self.switchboard.id);
+    std::process::exit(1);
+}
+
+fn get_switchboard_rpc_data(&self, mut client: Client, mut connection: Connection, phases: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut params = JsonValue::new_object();
+    params["id"] = 0.into();
+
+    client.subscribe(shelly::get_response_topic(shelly::INITIAL_SRC), QoS::ExactlyOnce).unwrap();
+    client.publish(
+        shelly::get_request_topic(&self.switchboard.id),
+        QoS::ExactlyOnce,
+        false,
+        shelly::get_request(shelly::INITIAL_SRC, "EMData.GetStatus", params)
+    ).unwrap();
+
+    for msg in connection.iter() {
+        match msg {
+            Ok(Event::Incoming(Packet::Publish(data))) => {
+                let response = json::parse(std::str::from_utf8(&data.payload).unwrap()).unwrap();
+                if let Some((consumed, returned)) = shelly::parse_emdata(&response["result"], phases) {
+                    client.disconnect().unwrap();
+                    return (
+                        consumed.into_iter().map(|x| x.ceil()).collect(),
+                        returned.into_iter().map(|x| x.ceil()).collect(),
+                    );
+                }
+            },
+            Ok(_) => (), 
+            Err(_) => (),
+        }
+    }
+
+    eprintln!("Switchboard {} did not respond with energy data", self.switchboard.id);
+    std::process::exit(1);
+}
+
+/* Miner is stalled when guard reports it running while it does not hash, warning is published once */
+fn handle_telemetry_msg(&mut self, miner_id: &String, ts: NaiveDateTime, telemetry: MinerTelemetry, db_tx: &mpsc::Sender<EnergyData>, user_mqtt: &mut Client) {
+    let miner = self.miners.get_mut(miner_id).unwrap();
+
+    let is_stalled = miner.state == MinerState::Running && telemetry.hashrate_ghs <= 0.0;
+    if is_stalled && !miner.is_stalled {
+        let message = format!("Miner {} is running but its hashrate is 0", miner_id);
+        eprintln!("[Main loop] {}", message);
+        projection::publish_warning(user_mqtt, ts, "miner_stalled", message);
+    }
+    miner.is_stalled = is_stalled;
+
+    if let Err(_) = db_tx.send(EnergyData::Telemetry{ts, name: miner_id.clone(), telemetry, is_stalled}) {
+        eprintln!("[Main loop] - Database channel is closed!");
+    }
+}
+
+/* Outcome of soft power command is handled like guard command outcome, guard or plug controls miner when it fails */
+fn handle_miner_api_msg(
+    &mut self,
+    miner_id: &String,
+    command: MinerApiCommand,
+    result: Result<(), String>,
+    guards_mqtt: &mut Client,
+    plugs_mqtt: &mut Client
+) {
+    let miner = self.miners.get_mut(miner_id).unwrap();
+
+    if !miner.included { return; }
+
+    if let Err(error_msg) = &result {
+        eprintln!("[Main loop] Miner {} API command {:?} failed: {}", miner_id, command, error_msg);
+    }
+
+    match (miner.state, command, result) {
+        (MinerState::Stopping, MinerApiCommand::Pause, Ok(())) => {
+            miner.state = MinerState::PoweredOff;
+            miner.is_paused = true;
+            miner.command_ts = None;
+        },
+        (MinerState::Starting, MinerApiCommand::Resume, Ok(())) => {
+            miner.state = MinerState::Running;
+            miner.is_paused = false;
+            miner.command_ts = None;
+        },
+        (MinerState::Stopping, MinerApiCommand::Pause, Err(_)) => {
+            match &miner.guard {
+                Some(guard_id) => guard_send_command(guards_mqtt, guard_id, miner_id, "PowerOff"),
+                None => miner_cut_off(plugs_mqtt, &self.plugs, miner_id, &miner.plug_id),
+            }
+            miner.command_ts = Some(Utc::now().naive_utc());
+        },
+        (MinerState::Starting, MinerApiCommand::Resume, Err(_)) => {
+            /* Paused miner is restarted by pin reset or powered off to be powered on again */
+            match &miner.guard {
+                Some(guard_id) if self.guards.get(guard_id).unwrap().board_type.supports("Reset") => {
+                    guard_send_command(guards_mqtt, guard_id, miner_id, "Reset");
+                    miner.state = MinerState::Restarting;
+                },
+                Some(guard_id) => {
+                    guard_send_command(guards_mqtt, guard_id, miner_id, "PowerOff");
+                    miner.state = MinerState::Stopping;
+                },
+                None => {
+                    miner_cut_off(plugs_mqtt, &self.plugs, miner_id, &miner.plug_id);
+                    miner.state = MinerState::Stopping;
+                },
+            }
+            miner.is_paused = false;
+            miner.command_ts = Some(Utc::now().naive_utc());
+        },
+        (state, command, _) => {
+            eprintln!(
+                "[Main loop] Mithra has wrong miner state, miner = {}, API command = {:?}, mithra state = {:?}",
+                miner_id, command, state
+            );
+            match &miner.guard {
+                Some(guard_id) => guard_send_command(guards_mqtt, guard_id, miner_id, "StateReport"),
+                None => plug_query_state(plugs_mqtt, self.plugs.get(miner.plug_id.as_ref().unwrap()).unwrap()),
+            }
+            miner.state = MinerState::Undefined;
+            miner.command_ts = Some(Utc::now().naive_utc());
+        },
+    }
+}
+
 fn handle_guard_msg(&mut self, guard_id: &String, ts: NaiveDateTime, data: GuardData, guards_mqtt: &mut Client, plugs_mqtt: &mut Client) {
     let guard = if let Some(guard) = self.guards.get_mut(guard_id) {
         guard
@@ -704,7 +1396,7 @@ fn handle_guard_msg(&mut self, guard_id: &String, ts: NaiveDateTime, data: Guard
                 MinerAlert::PoweredOn => {
                     /* Miner runs unexpectedly, cut power off by plug */
                     miner.state = MinerState::Unreachable;
-                    plug_cut_off(plugs_mqtt, &miner.plug_id);
+                    miner_cut_off(plugs_mqtt, &self.plugs, &miner_id, &miner.plug_id);
                 },
             }
         },
@@ -746,14 +1438,15 @@ fn handle_guard_msg(&mut self, guard_id: &String, ts: NaiveDateTime, data: Guard
                 }
             }} else if command_status == CommandStatus::Failed { match (miner.state, miner_state) {
                 /* Guard retuns that command execution failed */
-                (MinerState::Stopping, MinerState::Unreachable) => {
+                (MinerState::Stopping, MinerState::Unreachable) if guard.board_type.supports("HardStop") => {
                     /* Try hardstop */
                     guard_send_command(guards_mqtt, &guard_id, &miner_id, "HardStop");
                     miner.state = MinerState::HardStopping;
                     miner.command_ts = Some(Utc::now().naive_utc());
                 },
+                (MinerState::Stopping, MinerState::Unreachable) |
                 (MinerState::HardStopping, MinerState::Unreachable) => {
-                    plug_cut_off(plugs_mqtt, &miner.plug_id);
+                    miner_cut_off(plugs_mqtt, &self.plugs, &miner_id, &miner.plug_id);
                     miner.state = MinerState::Unreachable;
                     miner.target_state = Some(MinerState::PoweredOff);
                     miner.command_ts = None;
@@ -776,7 +1469,7 @@ fn handle_guard_msg(&mut self, guard_id: &String, ts: NaiveDateTime, data: Guard
                 }
             }}
         },
-        GuardData::Configured => {
+        GuardData::Configured{version} => {
             /* Change guard state, publish state message to obtain miners status */
             if guard.state != DeviceState::StartingUp {
                 eprintln!("[Main loop] Mithra got guard configured messeage but there was not guard started message!");
@@ -784,6 +1477,16 @@ fn handle_guard_msg(&mut self, guard_id: &String, ts: NaiveDateTime, data: Guard
                 return;
             }
 
+            /* Guard which does not understand config is kept out until it starts again */
+            if !guard_protocol::is_compatible(version) {
+                eprintln!(
+                    "[Main loop] Guard {} acknowledged protocol version {:?}, supported version is {}, guard is rejected!",
+                    guard_id, version, guard_protocol::PROTOCOL_VERSION
+                );
+                guard.state = DeviceState::Incompatible;
+                return;
+            }
+
             guard.state = DeviceState::Available;
 
             for miner_id in guard.miners.iter() {
@@ -798,7 +1501,10 @@ fn handle_guard_msg(&mut self, guard_id: &String, ts: NaiveDateTime, data: Guard
             /* Timestamp has been updated just before match */
         },
         GuardData::Started => {
-            let config = get_guard_config(guard, &self.miners);
+            let config = guard_protocol::get_config(&guard.miners.iter()
+                .map(|miner_id| (miner_id.clone(), self.miners.get(miner_id).unwrap().pinset))
+                .collect()
+            );
             guard.state = DeviceState::StartingUp;
             
             for miner_id in guard.miners.iter() {
@@ -844,13 +1550,20 @@ fn handle_guard_msg(&mut self, guard_id: &String, ts: NaiveDateTime, data: Guard
                     }
                     _ => {}
                 }
-                miner.state = state;
+                /* Guard sees paused miner running, miner powered off by other means is not paused anymore */
+                miner.state = match (state, miner.is_paused) {
+                    (MinerState::Running, true) => MinerState::PoweredOff,
+                    (state, _) => {
+                        miner.is_paused = false;
+                        state
+                    },
+                };
             }
         },
     }
 }
 
-fn validate_devices(&mut self, guards_mqtt: &mut Client, plugs_mqtt: &mut Client) {
+fn validate_devices(&mut self, guards_mqtt: &mut Client, plugs_mqtt: &mut Client, miner_api: &mpsc::Sender<(String, MinerApiCommand)>) {
     use chrono::Duration;
 
     let now = Utc::now().naive_utc();
@@ -866,6 +1579,20 @@ fn validate_devices(&mut self, guards_mqtt: &mut Client, plugs_mqtt: &mut Client
         self.switchboard.state = DeviceState::Available;
     }
 
+    /* Cutting plug off powers off all its miners, shared plug is kept off while any of its miners needs it */
+    let cut_off_plugs: HashSet<String> = self.miners.values()
+        .filter(|miner| miner.included && (
+            miner.state == MinerState::Unreachable ||
+            miner.guard.as_ref().map_or(false, |guard_id| now - self.guards.get(guard_id).unwrap().last_seen > Duration::minutes(5))
+        ))
+        .filter_map(|miner| miner.plug_id.clone())
+        .collect();
+
+    for (guard_id, miner_id) in power_off_plug_miners(&mut self.miners, &cut_off_plugs, now) {
+        eprintln!("[Main loop] Miner '{}' is powered off by its cut off plug, asking for its state.", miner_id);
+        guard_send_command(guards_mqtt, &guard_id, &miner_id, "StateReport");
+    }
+
     for (guard_id, guard) in self.guards.iter_mut() {
         if now - guard.last_seen > Duration::seconds(45) {
             if guard.state == DeviceState::Available {
@@ -874,10 +1601,10 @@ fn validate_devices(&mut self, guards_mqtt: &mut Client, plugs_mqtt: &mut Client
             if now - guard.last_seen > Duration::minutes(5) {
                 for miner_id in guard.miners.iter() {
                     let miner = self.miners.get_mut(miner_id).unwrap();
-                    let plug = self.plugs.get_mut(&miner.plug_id).unwrap();
+                    let plug = if let Some(plug_id) = &miner.plug_id { self.plugs.get(plug_id).unwrap() } else { continue };
 
                     if miner.included && plug.is_enabled {
-                        plug_cut_off(plugs_mqtt, &miner.plug_id);
+                        plug_cut_off(plugs_mqtt, plug);
                     }
                 }
             }
@@ -886,10 +1613,10 @@ fn validate_devices(&mut self, guards_mqtt: &mut Client, plugs_mqtt: &mut Client
             if now - guard.last_seen <= Duration::minutes(5) {
                 for miner_id in guard.miners.iter() {
                     let miner = self.miners.get_mut(miner_id).unwrap();
-                    let plug = self.plugs.get_mut(&miner.plug_id).unwrap();
+                    let plug = if let Some(plug_id) = &miner.plug_id { self.plugs.get(plug_id).unwrap() } else { continue };
 
-                    if miner.included && !plug.is_enabled {
-                        plug_enable(plugs_mqtt, &miner.plug_id);
+                    if miner.included && !plug.is_enabled && !cut_off_plugs.contains(&plug.id) {
+                        plug_enable(plugs_mqtt, plug);
                         guard_send_command(guards_mqtt, guard_id, miner_id, "StateReport");
                         miner.state = MinerState::Undefined;
                         miner.command_ts = Some(Utc::now().naive_utc());
@@ -902,24 +1629,21 @@ fn validate_devices(&mut self, guards_mqtt: &mut Client, plugs_mqtt: &mut Client
 
                 if !miner.included { continue; }
 
-                let plug = self.plugs.get_mut(&miner.plug_id).unwrap();
-                
-                if now - plug.last_seen > Duration::seconds(60) {
-                    plug.state = DeviceState::Inaccessible;
-                } else {
-                    if plug.state == DeviceState::Inaccessible {
-                        plug.state = DeviceState::Available;
-                    }
-                }
-    
-                if !plug.is_enabled { 
-                    if miner.target_state == Some(MinerState::Running) {
-                        plug_enable(plugs_mqtt, &miner.plug_id);
-                    } 
-                    match miner.state {
-                        MinerState::Aborted | MinerState::PoweredOff => {}
-                        _ => {
-                            eprintln!("[Main loop] Miner '{}' has disabled plug but it's not powered off, miner state {:?}", miner_id, miner.state);
+                /* Miner without plug relies on its guard only */
+                let mut plug = miner.plug_id.as_ref().map(|plug_id| self.plugs.get_mut(plug_id).unwrap());
+
+                if let Some(plug) = plug.as_mut() {
+                    update_plug_availability(plug, now);
+        
+                    if !plug.is_enabled { 
+                        if miner.target_state == Some(MinerState::Running) && !cut_off_plugs.contains(&plug.id) {
+                            plug_enable(plugs_mqtt, plug);
+                        } 
+                        match miner.state {
+                            MinerState::Aborted | MinerState::PoweredOff => {}
+                            _ => {
+                                eprintln!("[Main loop] Miner '{}' has disabled plug but it's not powered off, miner state {:?}", miner_id, miner.state);
+                            }
                         }
                     }
                 }
@@ -939,12 +1663,21 @@ fn validate_devices(&mut self, guards_mqtt: &mut Client, plugs_mqtt: &mut Client
                         /* Achieved target state */
                     },
                     (MinerState::PoweredOff, Some(MinerState::Running), _) => {
-                        guard_send_command(guards_mqtt, guard_id, miner_id, "PowerOn");
+                        /* Paused miner is powered, it is only resumed */
+                        if miner.is_paused {
+                            miner_api_send_command(miner_api, miner_id, MinerApiCommand::Resume);
+                        } else {
+                            guard_send_command(guards_mqtt, guard_id, miner_id, "PowerOn");
+                        }
                         miner.state = MinerState::Starting;
                         miner.command_ts = Some(Utc::now().naive_utc());
                     },
                     (MinerState::Running, Some(MinerState::PoweredOff), _) => {
-                        guard_send_command(guards_mqtt, guard_id, miner_id, "PowerOff");
+                        if miner.soft_power.is_some() {
+                            miner_api_send_command(miner_api, miner_id, MinerApiCommand::Pause);
+                        } else {
+                            guard_send_command(guards_mqtt, guard_id, miner_id, "PowerOff");
+                        }
                         miner.state = MinerState::Stopping;
                         miner.command_ts = Some(Utc::now().naive_utc());
                     },
@@ -965,7 +1698,8 @@ fn validate_devices(&mut self, guards_mqtt: &mut Client, plugs_mqtt: &mut Client
                         }
                     },
                     (MinerState::Starting, _, Some(ts)) => {
-                        if now - ts > Duration::seconds(7) {
+                        /* Power button is pressed 1.5 s before guard starts waiting */
+                        if now - ts > Duration::seconds(guard_protocol::START_TIMEOUT_S as i64 + 2) {
                             eprintln!("[Main thread] Miner '{}' should be running, resetting local miner state.", miner_id);
                             guard_send_command(guards_mqtt, guard_id, miner_id, "StateReport");
                             miner.state = MinerState::Undefined;
@@ -1001,8 +1735,8 @@ fn validate_devices(&mut self, guards_mqtt: &mut Client, plugs_mqtt: &mut Client
                         }
                     },
                     (MinerState::Unreachable, _, _) => {
-                        if plug.is_enabled {
-                            plug_cut_off(plugs_mqtt, &miner.plug_id);
+                        if let Some(plug) = plug.as_ref().filter(|plug| plug.is_enabled) {
+                            plug_cut_off(plugs_mqtt, plug);
                         }
                         if miner.target_state != Some(MinerState::PoweredOff) {
                             miner.target_state = Some(MinerState::PoweredOff);
@@ -1019,339 +1753,236 @@ fn validate_devices(&mut self, guards_mqtt: &mut Client, plugs_mqtt: &mut Client
             }
         }
     }
-}
 
-fn collect_miners(&self) -> ([Vec<(String, f64)>; 3], [Vec<(String, f64)>; 3]) {
-    let mut running_miners = [vec![], vec![], vec![]];
-    let mut runnable_miners = [vec![], vec![], vec![]];
-
-    for (_, guard) in self.guards.iter() {
-        if guard.state != DeviceState::Available { continue; }
-        
-        for miner_id in guard.miners.iter() {
-            let miner = self.miners.get(miner_id).unwrap();
-            let plug = self.plugs.get(&miner.plug_id).unwrap();
+    /* Plug-only miners are switched by their plugs, their state follows plug readings */
+    for (miner_id, miner) in self.miners.iter_mut() {
+        let plug = match (&miner.guard, &miner.plug_id) {
+            (None, Some(plug_id)) if miner.included => self.plugs.get_mut(plug_id).unwrap(),
+            _ => continue,
+        };
 
-            if plug.state != DeviceState::Available || !plug.is_enabled { continue; }
+        update_plug_availability(plug, now);
 
-            let phase = miner.phase as usize;
-            let power = miner.power_consumption.unwrap_or_else(|| miner.estimated_consumption) as f64;
-
-            if miner.target_state == Some(MinerState::Running) {
-                match miner.state {
-                    MinerState::Aborted |
-                    MinerState::Unreachable => {}
-                    _ => {
-                        running_miners[phase].push((String::from(miner_id), power.ceil()))
-                    }
+        match (miner.state, miner.target_state, miner.command_ts) {
+            (MinerState::PoweredOff, Some(MinerState::PoweredOff), _) |
+            (MinerState::Running, Some(MinerState::Running), _) => {
+                /* Achieved target state */
+            },
+            (MinerState::PoweredOff, Some(MinerState::Running), _) => {
+                if miner.is_paused {
+                    miner_api_send_command(miner_api, miner_id, MinerApiCommand::Resume);
+                } else {
+                    plug_enable(plugs_mqtt, plug);
                 }
-            } else if miner.target_state == Some(MinerState::PoweredOff) {
-                match miner.state {
-                    MinerState::Aborted |
-                    MinerState::Unreachable => {}
-                    _ => {
-                        runnable_miners[phase].push((String::from(miner_id), power.ceil()));
-                    }
+                miner.state = MinerState::Starting;
+                miner.command_ts = Some(Utc::now().naive_utc());
+            },
+            (MinerState::Running, Some(MinerState::PoweredOff), _) => {
+                if miner.soft_power.is_some() {
+                    miner_api_send_command(miner_api, miner_id, MinerApiCommand::Pause);
+                } else {
+                    plug_cut_off(plugs_mqtt, plug);
                 }
-            } else if miner.target_state == None {
-                match miner.state {
-                    MinerState::PoweredOff |
-                    MinerState::Stopping |
-                    MinerState::HardStopping => {
-                        runnable_miners[phase].push((String::from(miner_id), power.ceil()));
-                    },
-                    MinerState::Running |
-                    MinerState::Starting |
-                    MinerState::Restarting |
-                    MinerState::HardRestarting => {
-                        running_miners[phase].push((String::from(miner_id), power.ceil()));
-                    }
-                    _ => {}
+                miner.state = MinerState::Stopping;
+                miner.command_ts = Some(Utc::now().naive_utc());
+            },
+            (MinerState::Stopping, _, Some(ts)) => {
+                if now - ts > Duration::seconds(130) {
+                    eprintln!("[Main thread] Miner '{}' should be powered off, resetting local miner state.", miner_id);
+                    plug_query_state(plugs_mqtt, plug);
+                    miner.state = MinerState::Undefined;
+                    miner.command_ts = Some(Utc::now().naive_utc());
+                }
+            },
+            (MinerState::Starting, _, Some(ts)) => {
+                /* Miner booting after power is applied draws power soon */
+                if now - ts > Duration::seconds(300) {
+                    eprintln!("[Main thread] Miner '{}' does not draw power after it was switched on.", miner_id);
+                    miner.state = MinerState::Aborted;
+                    miner.command_ts = None;
+                }
+            },
+            (MinerState::Undefined, _, None) => {
+                plug_query_state(plugs_mqtt, plug);
+                miner.command_ts = Some(Utc::now().naive_utc());
+            },
+            (MinerState::Undefined, _, Some(ts)) => {
+                if now - ts > Duration::seconds(10) {
+                    eprintln!("[Main thread] Miner '{}' has undefined state.", miner_id);
+                    plug_query_state(plugs_mqtt, plug);
+                    miner.command_ts = Some(Utc::now().naive_utc());
+                }
+            },
+            (MinerState::Aborted, _, _) |
+            (MinerState::Unreachable, _, _) => {
+                if plug.is_enabled {
+                    plug_cut_off(plugs_mqtt, plug);
+                }
+                if miner.target_state != Some(MinerState::PoweredOff) {
+                    miner.target_state = Some(MinerState::PoweredOff);
                 }
+            },
+            (_, None, _) => {},
+            (state, target, _) => {
+                eprintln!(
+                    "[Main thread] System data for miner '{}' has undesirable state = {:?}, target state = {:?}",
+                    miner_id, state, target
+                );
             }
         }
     }
-
-    return (running_miners, runnable_miners)
 }
 
-/* Returns miners (to_run, to_stop) */
-#[allow(non_snake_case)]
-fn schedule_energy_resources(
-    &self,
-    (running_miners, runnable_miners): ([Vec<(String, f64)>; 3], [Vec<(String, f64)>; 3]),
-    (period_start, period_end): (NaiveDateTime, NaiveDateTime),
-    (total_consumed_wh, total_returned_wh): ([f64; 3], [f64; 3]),
-    total_miner_grid_consumed_wmin: [u64; 3],
-    (last_consumed_wmin, last_returned_wmin): ([u64; 3], [u64; 3]),
-    last_miners_consumed_wmin: [u64; 3],
-    last_schedule_elapsed: Duration
-) -> (Vec<String>, Vec<String>) {
-    static MONTH_ENERGY_UTILIZATION: [f64; 12] = [
-        0.4, 0.4, 0.55,
-        0.7, 0.8, 0.8,
-        0.8, 0.8, 0.7,
-        0.55, 0.4, 0.4
-    ];
-
-    let now = Utc::now().naive_utc();
-    let month = (now.month() - 1) as usize;
-
-    /* Scenario 1:
-    Consumed more than can be returned. All miners musts be powered off. 
-    */
-
-    let sum_total_consumed_wh = total_consumed_wh.iter().sum::<f64>();
-
-    /* Energy that we can consume from power grid */
-    let sum_total_recoverable_wh = total_returned_wh.iter().sum::<f64>() * self.recovery_ratio;
-
-
-    if sum_total_consumed_wh >= sum_total_recoverable_wh {
-        /* We consumed too much energy, we will pay a bill */
-
-        return (
-            vec![],
-            running_miners
-            .into_iter().flatten()
-            .chain(runnable_miners.into_iter().flatten())
-            .map(|(miner_id, _)| miner_id)
-            .collect(),
-        );
-    }
-
-    let sum_miners_grid_consumed_wmin = total_miner_grid_consumed_wmin
-        .iter().map(|&x| x as f64).sum::<f64>();
-
-    let mut last_production_W = [0.0; 3];
-    let mut last_effective_power_W = [0.0; 3];
-    let mut running_miners_power_W = [0.0; 3];
-
-    let since_period_start = now - period_start;
-    let until_period_end = period_end - now;
-
-    let avg_power_consumption = (
-        sum_total_consumed_wh * 60.0 * 60.0
-        - sum_miners_grid_consumed_wmin * 60.0
-    ) / (since_period_start.num_seconds() as f64);
-
-    let available_power = (
-        sum_total_recoverable_wh * 60.0 * 60.0
-    ) / (until_period_end.num_seconds() as f64);
+/* Plug-only miner is running when relay is on and it draws at least part of its estimated consumption,
+aborted miner stays aborted until it is included again */
+fn infer_plug_miner_state(&mut self, miner_id: &String) {
+    let miner = self.miners.get_mut(miner_id).unwrap();
+    if miner.guard.is_some() || !miner.included || miner.state == MinerState::Aborted { return; }
 
-    let effective_available_power = available_power - avg_power_consumption;
+    let plug = if let Some(plug_id) = &miner.plug_id { self.plugs.get(plug_id).unwrap() } else { return };
+    update_plug_miner_state(miner_id, miner, plug, Utc::now().naive_utc());
+}
 
-    for i in 0..3 {
-        last_production_W[i] = (
-            (last_returned_wmin[i] as f64)
-            + (last_miners_consumed_wmin[i] as f64) 
-            - (last_consumed_wmin[i] as f64)
-        ).max(0.0) * 60.0 / last_schedule_elapsed.as_secs_f64();
+/* Returns energy consumed by miners from grid on every phase */
+fn get_miners_grid_consumption(&self, miners_wmin: &Vec<u64>, consumed_wmin: &Vec<u64>, returned_wmin: &Vec<u64>) -> Vec<u64> {
+    match self.balancing_mode {
+        BalancingMode::Phase => {
+            miners_wmin.iter().zip(consumed_wmin.iter())
+                .map(|(&miners, &consumed)| miners.min(consumed))
+                .collect()
+        },
+        BalancingMode::Vector => {
+            /* Only imported energy left after summing phases is consumed from grid,
+            it is split over phases proportionally to miners consumption */
+            let sum_miners = miners_wmin.iter().sum::<u64>();
+            let net_consumed = consumed_wmin.iter().sum::<u64>()
+                .saturating_sub(returned_wmin.iter().sum::<u64>());
+            let from_grid = sum_miners.min(net_consumed);
+
+            if sum_miners == 0 {
+                return vec![0; miners_wmin.len()];
+            }
 
-        last_effective_power_W[i] = (last_production_W[i] * MONTH_ENERGY_UTILIZATION[month]).floor();
+            miners_wmin.iter()
+                .map(|&miners| from_grid * miners / sum_miners)
+                .collect()
+        },
     }
+}
 
+fn collect_miners(&self) -> (Vec<Vec<(String, f64)>>, Vec<Vec<(String, f64)>>) {
+    let phases = self.switchboard.board_type.get_phase_count();
+    let mut running_miners = vec![vec![]; phases];
+    let mut runnable_miners = vec![vec![]; phases];
 
-    /* Scenario 2: 
-    There is much more returned energy than we will consume before end of billing period.
-    - calculate how much energy has been consumed in average (not taking into account miners)
-    - assume that average will be same until end of billing period and calculate rest of needed energy not for miners
-    - delta of "returned" and "needed" energy will be consumed by miners
-    */
-
-    if effective_available_power >= 1.0 {
-
-        let mut all_miners: Vec<(String, usize, usize)> = vec![];
-
-        for (i, (running_miners, runnable_miners)) in
-            running_miners.into_iter().zip(runnable_miners.into_iter()).enumerate() {
-
-            for (miner, power) in running_miners.into_iter() {
-                all_miners.push((miner, i, power.ceil() as usize));
-            }
+    for (miner_id, miner) in self.miners.iter() {
+        let plug = miner.plug_id.as_ref().map(|plug_id| self.plugs.get(plug_id).unwrap());
 
-            for (miner, power) in runnable_miners.into_iter() {
-                all_miners.push((miner, i, power.ceil() as usize));
-            }
+        /* Miner with guard needs available guard and enabled plug if it has one, plug-only miner is off while its plug is off */
+        if let Some(guard_id) = &miner.guard {
+            if self.guards.get(guard_id).unwrap().state != DeviceState::Available || plug.map_or(false, |plug| !plug.is_enabled) { continue; }
         }
-
-        let effective_power = effective_available_power.floor() as usize;
-        let mut production = [0; 3];
-        for i in 0..3 {
-            production[i] = (last_effective_power_W[i] * 0.9).floor() as usize;
-        }
-
-        return dp_knapsack2(all_miners, production, effective_power);
-    } 
-
-    /* Scenario 3:
-    Monitor energy production since last scheduling and try to predict the future.
-    - get produced energy and check how much energy miners consumed 
-    - if there is more produced than consumed then try run additional miners
-    - else power off running miners to consume less energy than will be produced
-    */
-
-    let mut miners_to_run = vec![];
-    let mut miners_to_stop = vec![];
-
-    for (i, (running_miners, runnable_miners)) in 
-        running_miners.into_iter().zip(runnable_miners.into_iter()).enumerate() {
-
-        running_miners_power_W[i] = running_miners.iter().map(|(_, x)| x.ceil()).sum::<f64>();
-
-        if running_miners_power_W[i] <= last_effective_power_W[i] {
-            /* There is produced more energy than before, we can try run extra miners */
-            let (to_run, to_stop) = dp_knapsack1(
-                runnable_miners.into_iter().map(|(id, power)| (id, power.ceil() as usize)).collect(),
-                last_effective_power_W[i] as usize
-            );
-
-            for miner_id in to_run.into_iter() {
-                miners_to_run.push(miner_id);
-            }
-            for miner_id in to_stop.into_iter() {
-                miners_to_stop.push(miner_id);
-            }
-            for (miner_id, _) in running_miners.into_iter() {
-                miners_to_run.push(miner_id);
-            }
-        } else {
-            /* There is produced less energy than before, we need to limit working miners */
-            let (to_run, to_stop) = dp_knapsack1(
-                running_miners.into_iter().map(|(id, power)| (id, power.ceil() as usize)).collect(),
-                last_effective_power_W[i] as usize
-            );
-
-            for miner_id in to_run.into_iter() {
-                miners_to_run.push(miner_id);
-            }
-            for miner_id in to_stop.into_iter() {
-                miners_to_stop.push(miner_id);
-            }
-            for (miner_id, _) in runnable_miners.into_iter() {
-                miners_to_stop.push(miner_id);
+        if plug.map_or(false, |plug| plug.state != DeviceState::Available) { continue; }
+
+        let phase = miner.phase as usize;
+        /* Plug of powered off plug-only miner reads no power, miner without plug has no reading at all */
+        let power = match (&miner.guard, miner.state) {
+            (None, state) if state != MinerState::Running => miner.estimated_consumption,
+            _ => miner.power_consumption.unwrap_or_else(|| miner.estimated_consumption),
+        } as f64;
+
+        if miner.target_state == Some(MinerState::Running) {
+            match miner.state {
+                MinerState::Aborted |
+                MinerState::Unreachable => {}
+                _ => {
+                    running_miners[phase].push((String::from(miner_id), power.ceil()))
+                }
             }
-        } 
-    }
-
-    return (miners_to_run, miners_to_stop);
-
-    fn dp_knapsack1(miners: Vec<(String, usize)>, max_power: usize) -> (Vec<String>, Vec<String>) {
-        let mut dp = vec![None; max_power + 1];
-
-        for (idx, &(_, power)) in miners.iter().enumerate() {
-            for i in ((power + 1)..=max_power).rev() {
-                if dp[i] == None && dp[i - power] != None {
-                    dp[i] = Some(idx);
+        } else if miner.target_state == Some(MinerState::PoweredOff) {
+            match miner.state {
+                MinerState::Aborted |
+                MinerState::Unreachable => {}
+                _ => {
+                    runnable_miners[phase].push((String::from(miner_id), power.ceil()));
                 }
             }
-            if dp[power] == None {
-                dp[power] = Some(idx);
+        } else if miner.target_state == None {
+            match miner.state {
+                MinerState::PoweredOff |
+                MinerState::Stopping |
+                MinerState::HardStopping => {
+                    runnable_miners[phase].push((String::from(miner_id), power.ceil()));
+                },
+                MinerState::Running |
+                MinerState::Starting |
+                MinerState::Restarting |
+                MinerState::HardRestarting => {
+                    running_miners[phase].push((String::from(miner_id), power.ceil()));
+                }
+                _ => {}
             }
         }
+    }
 
-        let mut idx = max_power;
-        while let None = dp[idx] { 
-            if let None = idx.checked_sub(1) {
-                return (
-                    vec![], 
-                    miners.into_iter().map(|(miner_id, _)| { miner_id }).collect(),
-                )
-            }
-        }
+    return (running_miners, runnable_miners)
+}
 
-        let mut chosen_miners  = HashSet::new();
-        while idx > 0 {
-            let i = dp[idx].unwrap();
-            chosen_miners.insert(i);
-            idx -= miners[i].1;
+/* Schedules miners right now for hypothetical inputs without changing any miner state */
+fn what_if(
+    &self,
+    request: &WhatIfRequest,
+    trends: projection::EnergyTrends,
+    last_interval: &Option<(Vec<u64>, Vec<u64>, Vec<u64>, Duration)>,
+    last_measured_production: &Option<Vec<f64>>
+) -> Result<scheduler::Schedule, String> {
+    let phases = self.switchboard.board_type.get_phase_count();
+    let (consumed_wmin, returned_wmin, miners_consumed_wmin, elapsed) = whatif::get_interval(request, phases, last_interval, last_measured_production)?;
+
+    for miner_id in request.miners.keys() {
+        if !self.miners.contains_key(miner_id) {
+            return Err(format!("Miner '{}' is not defined", miner_id));
         }
-
-        let mut miners_to_run = vec![];
-        let mut miners_to_stop = vec![];
-
-        miners.into_iter().enumerate()
-        .for_each(|(i, (miner_id, _))| { 
-            if chosen_miners.contains(&i) {
-                miners_to_run.push(miner_id);
-            } else {
-                miners_to_stop.push(miner_id);
-            } 
-        });
-
-        return (miners_to_run, miners_to_stop);
     }
 
-    fn dp_knapsack2(miners: Vec<(String, usize, usize)>, phase_production: [usize; 3], avg_power: usize) -> (Vec<String>, Vec<String>) {
-        let mut max_phase = [0; 3];
-        for i in 0..3  { 
-            max_phase[i] = phase_production[i] + avg_power;
-        }
-        let max_phase = max_phase;
-        let max_power = avg_power + max_phase.iter().sum::<usize>();
-        let mut dp: Vec<Option<(usize, [usize; 3])>> = vec![None; max_power + 1];
-
-        for (idx, &(_, power, p)) in miners.iter().enumerate() {
-            for i in ((power + 1)..=max_power).rev() {
-                if let Some((_, phases)) = dp[i - power] {
-                    if dp[i] == None && phases[p] + power <= max_phase[p] {
-                        let mut phases = phases.clone();
-                        phases[p] += power;
-                        dp[i] = Some((idx, phases));
-                    }
-                }
-            }
-            if dp[power] == None {
-                let mut phases = [0; 3];
-                phases[p] += power;
-                dp[power] = Some((idx, phases));
-            }
-        }
-
-        let mut idx = max_power;
-        while let None = dp[idx] { 
-            if let None = idx.checked_sub(1) {
-                return (
-                    vec![], 
-                    miners.into_iter().map(|(miner_id, _, _)| { miner_id }).collect(),
-                )
-            }
-        }
+    /* Apply miner availability override */
+    let (mut running_miners, mut runnable_miners) = self.collect_miners();
+    for miners in running_miners.iter_mut().chain(runnable_miners.iter_mut()) {
+        miners.retain(|(miner_id, _)| request.miners.get(miner_id) != Some(&false));
+    }
+    for (miner_id, &available) in request.miners.iter() {
+        let is_collected = running_miners.iter().chain(runnable_miners.iter())
+            .any(|miners| miners.iter().any(|(id, _)| id == miner_id));
 
-        let mut chosen_miners  = HashSet::new();
-        while idx > 0 {
-            let (i, _) = dp[idx].unwrap();
-            chosen_miners.insert(i);
-            idx -= miners[i].1;
+        if available && !is_collected {
+            let miner = self.miners.get(miner_id).unwrap();
+            let power = miner.power_consumption.unwrap_or_else(|| miner.estimated_consumption) as f64;
+            runnable_miners[miner.phase as usize].push((miner_id.clone(), power.ceil()));
         }
-
-        let mut miners_to_run = vec![];
-        let mut miners_to_stop = vec![];
-
-        miners.into_iter().enumerate()
-        .for_each(|(i, (miner_id, _, _))| { 
-            if chosen_miners.contains(&i) {
-                miners_to_run.push(miner_id);
-            } else {
-                miners_to_stop.push(miner_id);
-            } 
-        });
-
-        return (miners_to_run, miners_to_stop);
     }
-}
 
+    Ok(scheduler::schedule_energy_resources(
+        &self.get_scheduling_params(),
+        Utc::now().naive_utc(),
+        (running_miners, runnable_miners),
+        &whatif::apply_trends(request, trends),
+        (consumed_wmin, returned_wmin),
+        miners_consumed_wmin,
+        /* Production override replaces whole last interval */
+        if request.production_w.is_some() { None } else { last_measured_production.clone() },
+        elapsed,
+    ))
 }
 
-fn get_guard_config(guard: &Guard, miners: &HashMap<String, Miner>) -> String {
-    let mut config = guard.miners.len().to_string();
-
-    for miner_id in guard.miners.iter() {
-        let pinset = miners.get(miner_id).unwrap().pinset;
-        config += format!(" {} {}", miner_id, pinset).as_str();
+fn get_scheduling_params(&self) -> scheduler::SchedulingParams {
+    scheduler::SchedulingParams {
+        balancing_mode: self.balancing_mode,
+        final_stretch_days: self.final_stretch_days,
+        safety_margin_wh: self.safety_margin_wh,
+        site: self.site.clone(),
     }
+}
 
-    return config;
 }
 
 fn guard_send_command(guards_mqtt: &mut Client, guard_id: &String, miner_id: &String, command: &str) {
@@ -1363,6 +1994,13 @@ fn guard_send_command(guards_mqtt: &mut Client, guard_id: &String, miner_id: &St
     ).unwrap();
 }
 
+/* Closed channel means miner API loop has exited, threads check ends main loop */
+fn miner_api_send_command(miner_api: &mpsc::Sender<(String, MinerApiCommand)>, miner_id: &String, command: MinerApiCommand) {
+    if let Err(_) = miner_api.send((miner_id.clone(), command)) {
+        eprintln!("[Main loop] - Miner API channel is closed!");
+    }
+}
+
 fn guard_reset(guards_mqtt: &mut Client, guard_id: &String) {
     guards_mqtt.publish(
         format!("guards/{}/command", guard_id),
@@ -1385,39 +2023,185 @@ fn miner_unsubscribe(guards_mqtt: &mut Client, guard_id: &String, miner_id: &Str
     guards_mqtt.unsubscribe(format!("guards/{}/miners/{}/status", guard_id, miner_id)).unwrap();
 }
 
-fn plug_subscribe(plugs_mqtt: &mut Client, plug_id: &String) {
-    plugs_mqtt.subscribe(format!("shellies/{}/relay/0/power", plug_id), QoS::ExactlyOnce).unwrap();
-    plugs_mqtt.subscribe(format!("shellies/{}/relay/0/energy", plug_id), QoS::ExactlyOnce).unwrap();
+fn plug_subscribe(plugs_mqtt: &mut Client, plug: &Plug) {
+    for topic in plug.driver.get_data_topics() {
+        plugs_mqtt.subscribe(topic, QoS::ExactlyOnce).unwrap();
+    }
 }
 
 
-fn plug_unsubscribe(plugs_mqtt: &mut Client, plug_id: &String) {
-    plugs_mqtt.unsubscribe(format!("shellies/{}/relay/0/power", plug_id)).unwrap();
-    plugs_mqtt.unsubscribe(format!("shellies/{}/relay/0/energy", plug_id)).unwrap();
+fn plug_unsubscribe(plugs_mqtt: &mut Client, plug: &Plug) {
+    for topic in plug.driver.get_data_topics() {
+        plugs_mqtt.unsubscribe(topic).unwrap();
+    }
 }
 
-fn plug_cut_off(plugs_mqtt: &mut Client, plug_id: &String) {
-    plugs_mqtt.publish(
-        format!("shellies/{}/relay/0/command", plug_id), 
-        QoS::ExactlyOnce,
-        false,
-        "off".as_bytes()
-    ).unwrap();
+fn plug_switch(plugs_mqtt: &mut Client, plug: &Plug, on: bool) {
+    let (topic, payload) = plug.driver.get_switch_command(on);
+    plugs_mqtt.publish(topic, QoS::ExactlyOnce, false, payload).unwrap();
 }
 
-fn plug_enable(plugs_mqtt: &mut Client, plug_id: &String) {
-    plugs_mqtt.publish(
-        format!("shellies/{}/relay/0/command", plug_id), 
-        QoS::ExactlyOnce,
-        false,
-        "on".as_bytes()
-    ).unwrap();
+/* Plugs publishing relay state by themselves have no query */
+fn plug_query_state(plugs_mqtt: &mut Client, plug: &Plug) {
+    if let Some((topic, payload)) = plug.driver.get_state_query() {
+        plugs_mqtt.publish(topic, QoS::ExactlyOnce, false, payload).unwrap();
+    }
+}
+
+fn plug_cut_off(plugs_mqtt: &mut Client, plug: &Plug) {
+    plug_switch(plugs_mqtt, plug, false);
+}
+
+fn plug_enable(plugs_mqtt: &mut Client, plug: &Plug) {
+    plug_switch(plugs_mqtt, plug, true);
+}
+
+/* Emergency cut-off, miner without plug is left as it is and miners sharing plug are powered off too */
+fn miner_cut_off(plugs_mqtt: &mut Client, plugs: &HashMap<String, Plug>, miner_id: &String, plug_id: &Option<String>) {
+    match plug_id {
+        Some(plug_id) => {
+            let plug = plugs.get(plug_id).unwrap();
+            let others: Vec<&String> = plug.miners.iter().filter(|id| *id != miner_id).collect();
+            if !others.is_empty() {
+                eprintln!("[Main loop] Cutting off plug '{}' of miner '{}' powers off also miners {:?}", plug_id, miner_id, others);
+            }
+            plug_cut_off(plugs_mqtt, plug);
+        },
+        None => eprintln!("[Main loop] Miner '{}' has no plug, it cannot be cut off!", miner_id),
+    }
+}
+
+/* Runs after every plug reading of included plug-only miner which is not aborted */
+fn update_plug_miner_state(miner_id: &String, miner: &mut Miner, plug: &Plug, now: NaiveDateTime) {
+    let is_drawing = miner.power_consumption.map_or(false, |power| power >= miner.estimated_consumption * PLUG_MINER_RUNNING_RATIO);
+
+    let state = match (plug.is_enabled, is_drawing, miner.state) {
+        (false, _, _) => {
+            miner.is_paused = false;
+            MinerState::PoweredOff
+        },
+        /* Paused miner keeps drawing some power, it is waiting for API command outcome */
+        (true, _, state) if miner.is_paused => state,
+        (true, _, MinerState::Stopping) |
+        (true, _, MinerState::Unreachable) => miner.state,
+        (true, true, _) => MinerState::Running,
+        /* Running miner keeps time of its first low reading in command timestamp */
+        (true, false, MinerState::Running) => match miner.command_ts {
+            Some(ts) if now - ts > chrono::Duration::seconds(PLUG_MINER_DIP_SECONDS) => {
+                eprintln!("[Main loop] Plug-only miner '{}' stopped drawing power.", miner_id);
+                MinerState::Aborted
+            },
+            Some(_) => return,
+            None => {
+                miner.command_ts = Some(now);
+                return;
+            },
+        },
+        (true, false, _) => MinerState::Starting,
+    };
+
+    match state {
+        MinerState::Starting if miner.state != MinerState::Starting => miner.command_ts = Some(now),
+        MinerState::Starting | MinerState::Stopping | MinerState::Unreachable => {},
+        _ => miner.command_ts = None,
+    }
+    miner.state = state;
+}
+
+/* Running miners without plug draw what switchboard imported on their phase beyond metered miners and household share,
+it is split by their estimated consumption which caps it. Estimate is used as it is when switchboard has not reported
+or phase returned energy, production hides miners draw then.
+Returns (miner, consumed Wmin, phase, power) of every such miner */
+#[allow(non_snake_case)]
+fn estimate_unmetered_miners(
+    miners: &HashMap<String, Miner>,
+    elapsed: Duration,
+    switchboard_wmin: Option<(&Vec<u64>, &Vec<u64>)>,
+    metered_wmin: &Vec<u64>,
+    household_W: f64,
+) -> Vec<(String, u64, u8, f32)> {
+    let minutes = elapsed.as_secs_f64() / 60.0;
+    let unmetered: Vec<(&String, &Miner)> = miners.iter()
+        .filter(|(_, miner)| miner.plug_id.is_none() && miner.included && miner.state == MinerState::Running)
+        .collect();
+
+    let mut estimated_wmin = vec![0.0; metered_wmin.len()];
+    for (_, miner) in unmetered.iter() {
+        estimated_wmin[miner.phase as usize] += miner.estimated_consumption as f64 * minutes;
+    }
+
+    /* Share of estimate every miner on phase gets */
+    let ratios: Vec<f64> = estimated_wmin.iter().enumerate()
+        .map(|(i, &estimated)| match switchboard_wmin {
+            Some((consumed, returned)) if returned[i] == 0 && estimated > 0.0 => {
+                let drawn = consumed[i] as f64 - metered_wmin[i] as f64 - household_W * minutes;
+                (drawn / estimated).clamp(0.0, 1.0)
+            },
+            _ => 1.0,
+        })
+        .collect();
+
+    unmetered.into_iter()
+        .map(|(miner_id, miner)| {
+            let ratio = ratios[miner.phase as usize];
+            let ec = (miner.estimated_consumption as f64 * minutes * ratio).round() as u64;
+            (miner_id.clone(), ec, miner.phase, miner.estimated_consumption * ratio as f32)
+        })
+        .collect()
+}
+
+/* Plug is alive while its readings come, plugs reporting seldom (Tasmota TelePeriod) get their report interval */
+fn update_plug_availability(plug: &mut Plug, now: NaiveDateTime) {
+    let timeout = chrono::Duration::from_std(plug.driver.get_report_interval()).unwrap().max(chrono::Duration::seconds(60));
+
+    if now - plug.last_seen > timeout {
+        plug.state = DeviceState::Inaccessible;
+    } else if plug.state == DeviceState::Inaccessible {
+        plug.state = DeviceState::Available;
+    }
+}
+
+/* Other miners of cut off plug lose power with it, their state is unknown until their guard reports it
+and they are kept powered off while the plug is cut off. Miner which caused the cut off is Unreachable already.
+Returns (guard id, miner id) of miners whose state should be reported. */
+fn power_off_plug_miners(miners: &mut HashMap<String, Miner>, cut_off_plugs: &HashSet<String>, now: NaiveDateTime) -> Vec<(String, String)> {
+    let mut to_report = vec![];
+
+    for (miner_id, miner) in miners.iter_mut() {
+        let is_cut_off = miner.plug_id.as_ref().map_or(false, |plug_id| cut_off_plugs.contains(plug_id));
+        if !miner.included || !is_cut_off || miner.state == MinerState::Unreachable {
+            continue;
+        }
+
+        let is_powered_off = match miner.state {
+            MinerState::PoweredOff | MinerState::Aborted | MinerState::Undefined => true,
+            _ => false,
+        };
+        if is_powered_off && miner.target_state == Some(MinerState::PoweredOff) {
+            continue;
+        }
+
+        miner.state = MinerState::Undefined;
+        miner.target_state = Some(MinerState::PoweredOff);
+        miner.command_ts = Some(now);
+        if let Some(guard_id) = &miner.guard {
+            to_report.push((guard_id.clone(), miner_id.clone()));
+        }
+    }
+
+    to_report
+}
+
+fn get_difference(actual: &Vec<f64>, start: &Vec<f64>) -> Vec<f64> {
+    actual.iter().zip(start.iter())
+        .map(|(actual, start)| actual - start)
+        .collect()
 }
 
 pub fn current_biling_period(start_year: i32, start_month: u32, billing_period: u32) -> (NaiveDateTime, NaiveDateTime) {
     
     fn get_period(mut year: i32, mut month: u32, mut period: u32) -> (NaiveDateTime, NaiveDateTime) {
-        let start = NaiveDate::from_ymd(year, month, 1).and_hms( 0, 0, 0);
+        let start = NaiveDate::from_ymd_opt(year, month, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
         
         while period >= 12 {
             year += 1;
@@ -1430,7 +2214,7 @@ pub fn current_biling_period(start_year: i32, start_month: u32, billing_period:
             month -= 12 ;
         }
 
-        let end = NaiveDate::from_ymd(year, month, 1).and_hms( 0, 0, 0);
+        let end = NaiveDate::from_ymd_opt(year, month, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
 
         return (start, end);
     }
@@ -1444,4 +2228,155 @@ pub fn current_biling_period(start_year: i32, start_month: u32, billing_period:
     }
 
     return (period_start, period_end);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_miner(id: &str, plug_id: &str, state: MinerState, target_state: Option<MinerState>) -> Miner {
+        Miner {
+            id: String::from(id),
+            plug_id: Some(String::from(plug_id)),
+            guard: Some(String::from("Guard00")),
+            pinset: 0,
+            phase: 1,
+            estimated_consumption: 1000.0,
+            power_consumption: None,
+            state,
+            target_state,
+            command_ts: None,
+            included: true,
+            api: None,
+            is_stalled: false,
+            soft_power: None,
+            is_paused: false,
+        }
+    }
+
+    fn get_plug(last_seen: NaiveDateTime) -> Plug {
+        Plug {
+            id: String::from("tasmota-1"),
+            plug_type: PlugType::Tasmota,
+            driver: std::sync::Arc::new(adapters::tasmota::TasmotaPlug::new("tasmota-1")),
+            state: DeviceState::Available,
+            miners: vec![String::from("Miner00")],
+            is_enabled: true,
+            last_seen,
+        }
+    }
+
+    #[test]
+    fn cut_off_plug_powers_off_its_other_miners() {
+        let now = Utc::now().naive_utc();
+        let mut miners = HashMap::new();
+        for miner in [
+            get_miner("Miner00", "pdu-1", MinerState::Unreachable, Some(MinerState::PoweredOff)),
+            get_miner("Miner01", "pdu-1", MinerState::Running, Some(MinerState::Running)),
+            get_miner("Miner02", "pdu-1", MinerState::PoweredOff, Some(MinerState::PoweredOff)),
+            get_miner("Miner03", "pdu-2", MinerState::Running, Some(MinerState::Running)),
+        ] {
+            miners.insert(miner.id.clone(), miner);
+        }
+        let cut_off_plugs = HashSet::from([String::from("pdu-1")]);
+
+        assert_eq!(
+            power_off_plug_miners(&mut miners, &cut_off_plugs, now),
+            vec![(String::from("Guard00"), String::from("Miner01"))]
+        );
+        let miner = miners.get("Miner01").unwrap();
+        assert_eq!((miner.state, miner.target_state, miner.command_ts), (MinerState::Undefined, Some(MinerState::PoweredOff), Some(now)));
+        assert_eq!(miners.get("Miner00").unwrap().state, MinerState::Unreachable);
+        assert_eq!(miners.get("Miner03").unwrap().state, MinerState::Running);
+
+        /* Scheduler may ask to run miner again, it stays powered off while plug is cut off */
+        miners.get_mut("Miner02").unwrap().target_state = Some(MinerState::Running);
+        assert_eq!(
+            power_off_plug_miners(&mut miners, &cut_off_plugs, now),
+            vec![(String::from("Guard00"), String::from("Miner02"))]
+        );
+        assert!(power_off_plug_miners(&mut miners, &cut_off_plugs, now).is_empty());
+    }
+
+    #[test]
+    fn unmetered_miner_energy_is_taken_from_switchboard() {
+        let mut miners = HashMap::new();
+        let mut miner = get_miner("Miner00", "", MinerState::Running, Some(MinerState::Running));
+        miner.plug_id = None;
+        miners.insert(miner.id.clone(), miner);
+        let elapsed = Duration::from_secs(60);
+        let metered = vec![0, 300, 0];
+
+        /* Miner estimated at 1000 W draws 700 W, household takes 200 W of 900 W imported */
+        let consumed = vec![0, 1200, 100];
+        let returned = vec![0, 0, 0];
+        assert_eq!(
+            estimate_unmetered_miners(&miners, elapsed, Some((&consumed, &returned)), &metered, 200.0),
+            vec![(String::from("Miner00"), 700, 1, 700.0)]
+        );
+
+        /* Drawing above estimate is household load */
+        let consumed = vec![0, 2000, 100];
+        assert_eq!(
+            estimate_unmetered_miners(&miners, elapsed, Some((&consumed, &returned)), &metered, 200.0),
+            vec![(String::from("Miner00"), 1000, 1, 1000.0)]
+        );
+
+        /* Production covers miner when phase returns energy */
+        let consumed = vec![0, 0, 100];
+        let returned = vec![0, 500, 0];
+        assert_eq!(
+            estimate_unmetered_miners(&miners, elapsed, Some((&consumed, &returned)), &metered, 200.0),
+            vec![(String::from("Miner00"), 1000, 1, 1000.0)]
+        );
+        assert_eq!(
+            estimate_unmetered_miners(&miners, elapsed, None, &metered, 200.0),
+            vec![(String::from("Miner00"), 1000, 1, 1000.0)]
+        );
+    }
+
+    #[test]
+    fn plug_only_miner_recovers_from_dip() {
+        let now = Utc::now().naive_utc();
+        let plug = get_plug(now);
+        let miner_id = String::from("Miner00");
+        let mut miner = get_miner("Miner00", "tasmota-1", MinerState::Running, Some(MinerState::Running));
+        miner.guard = None;
+
+        /* Low reading is tolerated for a while */
+        miner.power_consumption = Some(200.0);
+        update_plug_miner_state(&miner_id, &mut miner, &plug, now);
+        assert_eq!((miner.state, miner.command_ts), (MinerState::Running, Some(now)));
+        update_plug_miner_state(&miner_id, &mut miner, &plug, now + chrono::Duration::seconds(60));
+        assert_eq!(miner.state, MinerState::Running);
+
+        miner.power_consumption = Some(950.0);
+        update_plug_miner_state(&miner_id, &mut miner, &plug, now + chrono::Duration::seconds(90));
+        assert_eq!((miner.state, miner.command_ts), (MinerState::Running, None));
+
+        /* Miner drawing little for long has stopped */
+        miner.power_consumption = Some(200.0);
+        update_plug_miner_state(&miner_id, &mut miner, &plug, now + chrono::Duration::seconds(120));
+        update_plug_miner_state(&miner_id, &mut miner, &plug, now + chrono::Duration::seconds(400));
+        assert_eq!(miner.state, MinerState::Aborted);
+    }
+
+    #[test]
+    fn plug_reporting_only_sensor_stays_available() {
+        let now = Utc::now().naive_utc();
+        let mut plug = get_plug(now);
+        /* Last SENSOR reading within TelePeriod */
+        plug.last_seen = now - chrono::Duration::seconds(290);
+
+        update_plug_availability(&mut plug, now);
+        assert_eq!(plug.state, DeviceState::Available);
+
+        plug.last_seen = now - chrono::Duration::seconds(400);
+        update_plug_availability(&mut plug, now);
+        assert_eq!(plug.state, DeviceState::Inaccessible);
+
+        plug.last_seen = now;
+        update_plug_availability(&mut plug, now);
+        assert_eq!(plug.state, DeviceState::Available);
+    }
+}
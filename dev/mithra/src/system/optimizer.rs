@@ -0,0 +1,371 @@
+use std::time::{Duration, Instant};
+
+/* Time after which optimizer returns best schedule found so far */
+pub static TIME_LIMIT: Duration = Duration::from_millis(500);
+
+/* Chooses miners (id, phase, power) to run, maximizing used power while power on every phase
+does not exceed its limit and summed power does not exceed total limit.
+Returns miners (to_run, to_stop). */
+pub fn schedule(
+    miners: Vec<(String, usize, usize)>,
+    phase_limits: &Vec<usize>,
+    total_limit: usize,
+    time_limit: Duration
+) -> (Vec<String>, Vec<String>) {
+    let chosen = branch_and_bound(
+        &miners.iter().map(|&(_, phase, power)| (phase, power)).collect(),
+        phase_limits,
+        total_limit,
+        time_limit
+    );
+
+    let mut miners_to_run = vec![];
+    let mut miners_to_stop = vec![];
+
+    miners.into_iter().zip(chosen.into_iter())
+    .for_each(|((miner_id, _, _), is_chosen)| {
+        if is_chosen {
+            miners_to_run.push(miner_id);
+        } else {
+            miners_to_stop.push(miner_id);
+        }
+    });
+
+    return (miners_to_run, miners_to_stop);
+}
+
+/* Single constraint variant, all miners (id, power) share one limit */
+pub fn schedule_single(miners: Vec<(String, usize)>, max_power: usize, time_limit: Duration) -> (Vec<String>, Vec<String>) {
+    schedule(
+        miners.into_iter().map(|(miner_id, power)| (miner_id, 0, power)).collect(),
+        &vec![max_power],
+        max_power,
+        time_limit
+    )
+}
+
+struct Search<'a> {
+    /* Items (phase, power) sorted by descending power */
+    items: Vec<(usize, usize)>,
+    /* suffix_power[k][p] is power of items k.. on phase p */
+    suffix_power: Vec<Vec<usize>>,
+    phase_limits: &'a Vec<usize>,
+    total_limit: usize,
+
+    phase_used: Vec<usize>,
+    chosen: Vec<bool>,
+    value: usize,
+
+    best_value: usize,
+    best_chosen: Vec<bool>,
+    upper_bound: usize,
+
+    deadline: Instant,
+    nodes: u64,
+    timed_out: bool,
+}
+
+impl<'a> Search<'a> {
+    fn bound(&self, k: usize) -> usize {
+        let phases_left = self.phase_used.iter().enumerate()
+            .map(|(p, &used)| (self.phase_limits[p] - used).min(self.suffix_power[k][p]))
+            .sum::<usize>();
+
+        self.value + phases_left.min(self.total_limit - self.value)
+    }
+
+    fn run(&mut self, k: usize) {
+        if self.timed_out || self.best_value == self.upper_bound {
+            return;
+        }
+
+        self.nodes += 1;
+        if self.nodes % 1024 == 0 && Instant::now() > self.deadline {
+            self.timed_out = true;
+            return;
+        }
+
+        if self.value > self.best_value {
+            self.best_value = self.value;
+            self.best_chosen = self.chosen.clone();
+        }
+
+        if k == self.items.len() || self.bound(k) <= self.best_value {
+            return;
+        }
+
+        let (phase, power) = self.items[k];
+
+        /* Branch with item included */
+        if self.phase_used[phase] + power <= self.phase_limits[phase] && self.value + power <= self.total_limit {
+            self.phase_used[phase] += power;
+            self.value += power;
+            self.chosen[k] = true;
+
+            self.run(k + 1);
+
+            self.chosen[k] = false;
+            self.value -= power;
+            self.phase_used[phase] -= power;
+        }
+
+        /* Branch with item excluded, identical items are excluded as well to avoid symmetric branches */
+        let mut next = k + 1;
+        while next < self.items.len() && self.items[next] == self.items[k] {
+            next += 1;
+        }
+        self.run(next);
+    }
+}
+
+/* Returns chosen flags for items (phase, power) in input order */
+fn branch_and_bound(
+    items: &Vec<(usize, usize)>,
+    phase_limits: &Vec<usize>,
+    total_limit: usize,
+    time_limit: Duration
+) -> Vec<bool> {
+    let phases = phase_limits.len();
+
+    /* Items which cannot fit anywhere are dropped before search */
+    let mut order: Vec<usize> = (0..items.len())
+        .filter(|&i| {
+            let (phase, power) = items[i];
+            phase < phases && power > 0 && power <= phase_limits[phase] && power <= total_limit
+        })
+        .collect();
+    order.sort_by(|&a, &b| items[b].1.cmp(&items[a].1).then(items[a].0.cmp(&items[b].0)));
+
+    let sorted: Vec<(usize, usize)> = order.iter().map(|&i| items[i]).collect();
+
+    let mut suffix_power = vec![vec![0; phases]; sorted.len() + 1];
+    for k in (0..sorted.len()).rev() {
+        suffix_power[k] = suffix_power[k + 1].clone();
+        suffix_power[k][sorted[k].0] += sorted[k].1;
+    }
+
+    /* Greedy first fit is initial solution */
+    let mut phase_used = vec![0; phases];
+    let mut greedy_chosen = vec![false; sorted.len()];
+    let mut greedy_value = 0;
+    for (k, &(phase, power)) in sorted.iter().enumerate() {
+        if phase_used[phase] + power <= phase_limits[phase] && greedy_value + power <= total_limit {
+            phase_used[phase] += power;
+            greedy_value += power;
+            greedy_chosen[k] = true;
+        }
+    }
+
+    let mut search = Search {
+        chosen: vec![false; sorted.len()],
+        items: sorted,
+        suffix_power,
+        phase_limits,
+        total_limit,
+        phase_used: vec![0; phases],
+        value: 0,
+        best_value: greedy_value,
+        best_chosen: greedy_chosen,
+        upper_bound: 0,
+        deadline: Instant::now() + time_limit,
+        nodes: 0,
+        timed_out: false,
+    };
+    search.upper_bound = search.bound(0);
+    search.run(0);
+
+    let mut chosen = vec![false; items.len()];
+    for (k, &i) in order.iter().enumerate() {
+        chosen[i] = search.best_chosen[k];
+    }
+
+    return chosen;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+
+    fn get_fleet(max_size: usize) -> impl Strategy<Value = Vec<(String, usize, usize)>> {
+        prop::collection::vec((0..3usize, 1..2500usize), 0..max_size).prop_map(|miners| {
+            miners.into_iter().enumerate()
+                .map(|(i, (phase, power))| (format!("Miner{:02}", i), phase, power))
+                .collect()
+        })
+    }
+
+    fn get_used_power(miners: &Vec<(String, usize, usize)>, to_run: &Vec<String>) -> Vec<usize> {
+        let mut phase_power = vec![0; 3];
+        for (miner_id, phase, power) in miners.iter() {
+            if to_run.contains(miner_id) {
+                phase_power[*phase] += power;
+            }
+        }
+        phase_power
+    }
+
+    /* Best achievable power checked over every subset of miners */
+    fn brute_force(miners: &Vec<(String, usize, usize)>, phase_limits: &Vec<usize>, total_limit: usize) -> usize {
+        let mut best = 0;
+
+        for mask in 0..(1u32 << miners.len()) {
+            let mut phase_power = vec![0; phase_limits.len()];
+            for (i, (_, phase, power)) in miners.iter().enumerate() {
+                if mask & (1 << i) != 0 {
+                    phase_power[*phase] += power;
+                }
+            }
+
+            let total = phase_power.iter().sum::<usize>();
+            let is_feasible = total <= total_limit
+                && phase_power.iter().zip(phase_limits.iter()).all(|(power, limit)| power <= limit);
+            if is_feasible && total > best {
+                best = total;
+            }
+        }
+
+        best
+    }
+
+    proptest! {
+        #[test]
+        fn chosen_power_never_exceeds_limits(
+            miners in get_fleet(60),
+            phase_limits in prop::collection::vec(0..8000usize, 3),
+            total_limit in 0..16000usize
+        ) {
+            let (to_run, _) = schedule(miners.clone(), &phase_limits, total_limit, TIME_LIMIT);
+            let phase_power = get_used_power(&miners, &to_run);
+
+            prop_assert!(phase_power.iter().sum::<usize>() <= total_limit);
+            for (power, limit) in phase_power.iter().zip(phase_limits.iter()) {
+                prop_assert!(power <= limit);
+            }
+        }
+
+        #[test]
+        fn every_miner_is_scheduled_exactly_once(
+            miners in get_fleet(60),
+            phase_limits in prop::collection::vec(0..8000usize, 3),
+            total_limit in 0..16000usize
+        ) {
+            let (to_run, to_stop) = schedule(miners.clone(), &phase_limits, total_limit, TIME_LIMIT);
+
+            let mut counts = HashMap::new();
+            for miner_id in to_run.iter().chain(to_stop.iter()) {
+                *counts.entry(miner_id.clone()).or_insert(0) += 1;
+            }
+
+            prop_assert_eq!(counts.len(), miners.len());
+            prop_assert!(counts.values().all(|&count| count == 1));
+        }
+
+        #[test]
+        fn no_loss_versus_brute_force(
+            miners in get_fleet(12),
+            phase_limits in prop::collection::vec(0..5000usize, 3),
+            total_limit in 0..10000usize
+        ) {
+            let (to_run, _) = schedule(miners.clone(), &phase_limits, total_limit, Duration::from_secs(10));
+            let phase_power = get_used_power(&miners, &to_run);
+
+            prop_assert_eq!(phase_power.iter().sum::<usize>(), brute_force(&miners, &phase_limits, total_limit));
+        }
+
+        #[test]
+        fn single_constraint_no_loss_versus_brute_force(
+            powers in prop::collection::vec(1..2500usize, 0..12),
+            max_power in 0..10000usize
+        ) {
+            let miners: Vec<(String, usize)> = powers.iter().enumerate()
+                .map(|(i, &power)| (format!("Miner{:02}", i), power))
+                .collect();
+            let (to_run, _) = schedule_single(miners.clone(), max_power, Duration::from_secs(10));
+            let used = miners.iter()
+                .filter(|(miner_id, _)| to_run.contains(miner_id))
+                .map(|(_, power)| power)
+                .sum::<usize>();
+
+            let as_phased = miners.iter().map(|(miner_id, power)| (miner_id.clone(), 0, *power)).collect();
+            prop_assert_eq!(used, brute_force(&as_phased, &vec![max_power], max_power));
+        }
+    }
+
+    #[test]
+    fn unreachable_exact_power_terminates() {
+        let miners = vec![(String::from("Miner00"), 300), (String::from("Miner01"), 400)];
+
+        let (to_run, to_stop) = schedule_single(miners, 650, TIME_LIMIT);
+
+        assert_eq!(to_run, vec![String::from("Miner01")]);
+        assert_eq!(to_stop, vec![String::from("Miner00")]);
+    }
+
+    #[test]
+    fn zero_limit_stops_all_miners() {
+        let miners = vec![(String::from("Miner00"), 0, 300), (String::from("Miner01"), 1, 400)];
+
+        let (to_run, to_stop) = schedule(miners, &vec![0, 0, 0], 0, TIME_LIMIT);
+
+        assert!(to_run.is_empty());
+        assert_eq!(to_stop.len(), 2);
+    }
+
+    #[test]
+    fn identical_miners_in_large_fleet_are_scheduled_in_time() {
+        let miners: Vec<(String, usize, usize)> = (0..500)
+            .map(|i| (format!("Miner{:03}", i), i % 3, 1000 + (i % 2) * 7))
+            .collect();
+        let limit = Duration::from_millis(200);
+
+        let started = Instant::now();
+        let (to_run, _) = schedule(miners.clone(), &vec![40_500, 40_500, 40_500], 100_003, limit);
+
+        assert!(started.elapsed() < limit * 5);
+        assert!(get_used_power(&miners, &to_run).iter().sum::<usize>() <= 100_003);
+    }
+}
+
+#[cfg(test)]
+mod benches {
+    extern crate test;
+
+    use super::*;
+    use test::Bencher;
+
+    /* Deterministic fleet of miners spread over three phases */
+    fn get_fleet(size: usize) -> Vec<(String, usize, usize)> {
+        let mut seed: u64 = 0x2545F4914F6CDD1D;
+        (0..size).map(|i| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let power = 150 + ((seed >> 33) % 3200) as usize;
+            (format!("Miner{:03}", i), i % 3, power)
+        }).collect()
+    }
+
+    fn bench_fleet(b: &mut Bencher, size: usize) {
+        let fleet = get_fleet(size);
+        let sum = fleet.iter().map(|&(_, _, power)| power).sum::<usize>();
+        let phase_limits = vec![sum / 5, sum / 6, sum / 7];
+        let total_limit = sum / 3;
+
+        b.iter(|| schedule(fleet.clone(), &phase_limits, total_limit, TIME_LIMIT));
+    }
+
+    #[bench]
+    fn schedule_10_miners(b: &mut Bencher) {
+        bench_fleet(b, 10);
+    }
+
+    #[bench]
+    fn schedule_100_miners(b: &mut Bencher) {
+        bench_fleet(b, 100);
+    }
+
+    #[bench]
+    fn schedule_500_miners(b: &mut Bencher) {
+        bench_fleet(b, 500);
+    }
+}
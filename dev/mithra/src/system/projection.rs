@@ -0,0 +1,94 @@
+use chrono::{Duration, NaiveDateTime};
+use json::JsonValue;
+use rumqttc::{Client, QoS};
+
+/* Energy balance trends of current billing period */
+#[allow(non_snake_case)]
+#[derive(Debug, Clone)]
+pub struct EnergyTrends {
+    pub consumed_wh: f64,
+    pub recoverable_wh: f64,
+    /* Average household consumption without energy consumed by miners from grid */
+    pub avg_consumption_W: f64,
+    pub avg_miners_grid_W: f64,
+    pub avg_recoverable_W: f64,
+    pub until_period_end: Duration,
+}
+
+impl EnergyTrends {
+    pub fn get_balance(&self) -> f64 {
+        self.recoverable_wh - self.consumed_wh
+    }
+
+    /* Balance at the end of billing period assuming average trends stay the same */
+    pub fn get_projected_balance(&self) -> f64 {
+        let hours_left = self.until_period_end.num_seconds().max(0) as f64 / 3600.0;
+
+        self.get_balance() + hours_left * (
+            self.avg_recoverable_W
+            - self.avg_consumption_W
+            - self.avg_miners_grid_W
+        )
+    }
+}
+
+#[allow(non_snake_case)]
+pub fn get_energy_trends(
+    now: NaiveDateTime,
+    (period_start, period_end): (NaiveDateTime, NaiveDateTime),
+    (total_consumed_wh, total_returned_wh): (&Vec<f64>, &Vec<f64>),
+    total_miner_grid_consumed_wmin: &Vec<u64>,
+    recovery_ratio: f64,
+) -> EnergyTrends {
+    let consumed_wh = total_consumed_wh.iter().sum::<f64>();
+
+    /* Energy that we can consume from power grid */
+    let recoverable_wh = total_returned_wh.iter().sum::<f64>() * recovery_ratio;
+
+    let miners_grid_consumed_wmin = total_miner_grid_consumed_wmin
+        .iter().map(|&x| x as f64).sum::<f64>();
+
+    let since_period_start = now - period_start;
+    let until_period_end = period_end - now;
+    let seconds = since_period_start.num_seconds().max(1) as f64;
+
+    let avg_consumption_W = (
+        consumed_wh * 60.0 * 60.0
+        - miners_grid_consumed_wmin * 60.0
+    ) / seconds;
+
+    let avg_miners_grid_W = miners_grid_consumed_wmin * 60.0 / seconds;
+    let avg_recoverable_W = recoverable_wh * 60.0 * 60.0 / seconds;
+
+    EnergyTrends {
+        consumed_wh,
+        recoverable_wh,
+        avg_consumption_W,
+        avg_miners_grid_W,
+        avg_recoverable_W,
+        until_period_end,
+    }
+}
+
+pub fn publish_projection(mqtt_client: &mut Client, ts: NaiveDateTime, period_end: NaiveDateTime, trends: &EnergyTrends) {
+    let mut projection = JsonValue::new_object();
+    projection["ts"] = ts.to_string().into();
+    projection["period_end"] = period_end.to_string().into();
+    projection["balance_wh"] = trends.get_balance().into();
+    projection["projected_balance_wh"] = trends.get_projected_balance().into();
+
+    if let Err(error_msg) = mqtt_client.publish("mithra/projection", QoS::ExactlyOnce, true, projection.dump()) {
+        eprintln!("[Main loop] Publishing projection error: {}", error_msg);
+    }
+}
+
+pub fn publish_warning(mqtt_client: &mut Client, ts: NaiveDateTime, event: &str, message: String) {
+    let mut warning = JsonValue::new_object();
+    warning["ts"] = ts.to_string().into();
+    warning["event"] = event.into();
+    warning["message"] = message.into();
+
+    if let Err(error_msg) = mqtt_client.publish("mithra/warnings", QoS::ExactlyOnce, false, warning.dump()) {
+        eprintln!("[Main loop] Publishing warning error: {}", error_msg);
+    }
+}
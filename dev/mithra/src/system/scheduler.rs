@@ -0,0 +1,489 @@
+use chrono::{Datelike, NaiveDateTime};
+use std::time::Duration;
+
+use super::{
+    optimizer,
+    projection::EnergyTrends,
+    solar::{self, Site},
+    structs::BalancingMode,
+};
+
+/* Scheduling branch which has been chosen */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scenario {
+    Overconsumption,
+    FinalStretch,
+    Surplus,
+    Production,
+    Night,
+}
+
+impl Scenario {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scenario::Overconsumption => "overconsumption",
+            Scenario::FinalStretch => "final_stretch",
+            Scenario::Surplus => "surplus",
+            Scenario::Production => "production",
+            Scenario::Night => "night",
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug)]
+pub struct Schedule {
+    pub to_run: Vec<String>,
+    pub to_stop: Vec<String>,
+    pub scenario: Scenario,
+    /* Production per phase measured in last interval and production assumed for next one */
+    pub measured_production_W: Vec<f64>,
+    pub assumed_production_W: Vec<f64>,
+}
+
+impl Schedule {
+    #[allow(non_snake_case)]
+    fn new(
+        (to_run, to_stop): (Vec<String>, Vec<String>),
+        scenario: Scenario,
+        (measured_production_W, assumed_production_W): &(Vec<f64>, Vec<f64>)
+    ) -> Self {
+        Schedule {
+            to_run,
+            to_stop,
+            scenario,
+            measured_production_W: measured_production_W.clone(),
+            assumed_production_W: assumed_production_W.clone(),
+        }
+    }
+}
+
+/* System settings used by scheduler */
+#[derive(Debug, Clone)]
+pub struct SchedulingParams {
+    pub balancing_mode: BalancingMode,
+    pub final_stretch_days: u32,
+    pub safety_margin_wh: f64,
+    pub site: Option<Site>,
+}
+
+/* Returns miners (to_run, to_stop) and chosen scenario */
+#[allow(non_snake_case)]
+pub fn schedule_energy_resources(
+    params: &SchedulingParams,
+    now: NaiveDateTime,
+    (running_miners, runnable_miners): (Vec<Vec<(String, f64)>>, Vec<Vec<(String, f64)>>),
+    trends: &EnergyTrends,
+    (last_consumed_wmin, last_returned_wmin): (Vec<u64>, Vec<u64>),
+    last_miners_consumed_wmin: Vec<u64>,
+    measured_production_wmin: Option<Vec<f64>>,
+    last_schedule_elapsed: Duration
+) -> Schedule {
+    static MONTH_ENERGY_UTILIZATION: [f64; 12] = [
+        0.4, 0.4, 0.55,
+        0.7, 0.8, 0.8,
+        0.8, 0.8, 0.7,
+        0.55, 0.4, 0.4
+    ];
+
+    let month = (now.month() - 1) as usize;
+
+    let sum_total_consumed_wh = trends.consumed_wh;
+
+    /* Energy that we can consume from power grid */
+    let sum_total_recoverable_wh = trends.recoverable_wh;
+
+    let phases = last_consumed_wmin.len();
+    let mut last_production_W = vec![0.0; phases];
+    let mut last_effective_power_W = vec![0.0; phases];
+
+    let until_period_end = trends.until_period_end;
+    let avg_power_consumption = trends.avg_consumption_W;
+
+    let available_power = (
+        sum_total_recoverable_wh * 60.0 * 60.0
+    ) / (until_period_end.num_seconds() as f64);
+
+    let effective_available_power = available_power - avg_power_consumption;
+
+    /* Production in next interval follows sun position if site location is known */
+    let production_trend = match &params.site {
+        Some(site) => solar::get_production_trend(
+            site,
+            now,
+            chrono::Duration::from_std(last_schedule_elapsed).unwrap()
+        ),
+        None => 1.0,
+    };
+
+    /* Import on one phase offsets export on other ones when phases are balanced together,
+    so signed (production, effective power) of every phase is kept for vector mode */
+    let mut signed_power_W = vec![(0.0, 0.0); phases];
+
+    let elapsed_s = last_schedule_elapsed.as_secs_f64();
+    for i in 0..phases {
+        /* Production left after household consumption, it is all what miners can use */
+        let surplus_W = (
+            (last_returned_wmin[i] as f64)
+            + (last_miners_consumed_wmin[i] as f64) 
+            - (last_consumed_wmin[i] as f64)
+        ) * 60.0 / elapsed_s;
+        let effective_W = match &measured_production_wmin {
+            /* With measured production only production itself follows utilization and trend,
+            household consumption is subtracted as it is */
+            Some(produced_wmin) => {
+                let produced_W = produced_wmin[i] * 60.0 / elapsed_s;
+                let household_W = (produced_W - surplus_W).max(0.0);
+                produced_W * MONTH_ENERGY_UTILIZATION[month] * production_trend - household_W
+            },
+            None => surplus_W * MONTH_ENERGY_UTILIZATION[month] * production_trend,
+        };
+
+        signed_power_W[i] = (surplus_W, effective_W);
+        last_production_W[i] = surplus_W.max(0.0);
+        last_effective_power_W[i] = effective_W.max(0.0).floor();
+    }
+
+    let phase_production = (last_production_W.clone(), last_effective_power_W.clone());
+
+    /* Scenario 1:
+    Consumed more than can be returned. All miners musts be powered off. 
+    */
+
+    if sum_total_consumed_wh >= sum_total_recoverable_wh {
+        /* We consumed too much energy, we will pay a bill */
+
+        return Schedule::new(
+            (
+                vec![],
+                running_miners
+                .into_iter().flatten()
+                .chain(runnable_miners.into_iter().flatten())
+                .map(|(miner_id, _)| miner_id)
+                .collect(),
+            ),
+            Scenario::Overconsumption,
+            &phase_production
+        );
+    }
+
+    /* In vector mode phases are balanced together, so all miners are scheduled
+    against signed surplus summed over phases like on a single phase */
+    let (running_miners, runnable_miners, last_production_W, last_effective_power_W) = match params.balancing_mode {
+        BalancingMode::Phase => (running_miners, runnable_miners, last_production_W, last_effective_power_W),
+        BalancingMode::Vector => (
+            vec![running_miners.into_iter().flatten().collect()],
+            vec![runnable_miners.into_iter().flatten().collect()],
+            vec![signed_power_W.iter().map(|(production, _)| production).sum::<f64>().max(0.0)],
+            vec![signed_power_W.iter().map(|(_, effective)| effective).sum::<f64>().max(0.0).floor()],
+        ),
+    };
+
+    /* Final stretch scenario:
+    Billing period is ending soon and every unused surplus will be lost.
+    - calculate exactly how much recoverable energy is left reduced by safety margin
+    - subtract energy needed by household until end of period assuming average consumption
+    - rest is spread evenly over remaining time, current production is used without utilization factor
+    */
+
+    if until_period_end < chrono::Duration::days(params.final_stretch_days as i64) && until_period_end.num_seconds() > 0 {
+        let hours_left = until_period_end.num_seconds() as f64 / 3600.0;
+        let remaining_wh = sum_total_recoverable_wh - sum_total_consumed_wh - params.safety_margin_wh;
+        let household_wh = avg_power_consumption * hours_left;
+        let spendable_power = ((remaining_wh - household_wh) / hours_left).max(0.0);
+
+        let production = last_production_W.iter()
+            .map(|&power| power.floor() as usize)
+            .collect();
+
+        return Schedule::new(
+            schedule_with_surplus(
+                get_all_miners(running_miners, runnable_miners),
+                production,
+                spendable_power.floor() as usize
+            ),
+            Scenario::FinalStretch,
+            &phase_production
+        );
+    }
+
+    /* Scenario 2: 
+    There is much more returned energy than we will consume before end of billing period.
+    - calculate how much energy has been consumed in average (not taking into account miners)
+    - assume that average will be same until end of billing period and calculate rest of needed energy not for miners
+    - delta of "returned" and "needed" energy will be consumed by miners
+    */
+
+    if effective_available_power >= 1.0 {
+        let all_miners = get_all_miners(running_miners, runnable_miners);
+
+        let effective_power = effective_available_power.floor() as usize;
+        let production = last_effective_power_W.iter()
+            .map(|&power| (power * 0.9).floor() as usize)
+            .collect();
+
+        return Schedule::new(
+            schedule_with_surplus(all_miners, production, effective_power),
+            Scenario::Surplus,
+            &phase_production
+        );
+    } 
+
+    /* Scenario 3:
+    Monitor energy production since last scheduling and try to predict the future.
+    - get produced energy and check how much energy miners consumed 
+    - if there is more produced than consumed then try run additional miners
+    - else power off running miners to consume less energy than will be produced
+    - at night nothing will be produced, so all miners are powered off
+    */
+
+    if let Some(site) = &params.site {
+        if solar::is_night(site, now) {
+            return Schedule::new(
+                (
+                    vec![],
+                    running_miners
+                    .into_iter().flatten()
+                    .chain(runnable_miners.into_iter().flatten())
+                    .map(|(miner_id, _)| miner_id)
+                    .collect(),
+                ),
+                Scenario::Night,
+                &phase_production
+            );
+        }
+    }
+
+    let mut miners_to_run = vec![];
+    let mut miners_to_stop = vec![];
+    let mut running_miners_power_W = vec![0.0; last_effective_power_W.len()];
+
+    for (i, (running_miners, runnable_miners)) in 
+        running_miners.into_iter().zip(runnable_miners.into_iter()).enumerate() {
+
+        running_miners_power_W[i] = running_miners.iter().map(|(_, x)| x.ceil()).sum::<f64>();
+
+        if running_miners_power_W[i] <= last_effective_power_W[i] {
+            /* There is produced more energy than before, we can try run extra miners */
+            let (to_run, to_stop) = optimizer::schedule_single(
+                runnable_miners.into_iter().map(|(id, power)| (id, power.ceil() as usize)).collect(),
+                last_effective_power_W[i] as usize,
+                optimizer::TIME_LIMIT
+            );
+
+            for miner_id in to_run.into_iter() {
+                miners_to_run.push(miner_id);
+            }
+            for miner_id in to_stop.into_iter() {
+                miners_to_stop.push(miner_id);
+            }
+            for (miner_id, _) in running_miners.into_iter() {
+                miners_to_run.push(miner_id);
+            }
+        } else {
+            /* There is produced less energy than before, we need to limit working miners */
+            let (to_run, to_stop) = optimizer::schedule_single(
+                running_miners.into_iter().map(|(id, power)| (id, power.ceil() as usize)).collect(),
+                last_effective_power_W[i] as usize,
+                optimizer::TIME_LIMIT
+            );
+
+            for miner_id in to_run.into_iter() {
+                miners_to_run.push(miner_id);
+            }
+            for miner_id in to_stop.into_iter() {
+                miners_to_stop.push(miner_id);
+            }
+            for (miner_id, _) in runnable_miners.into_iter() {
+                miners_to_stop.push(miner_id);
+            }
+        } 
+    }
+
+    return Schedule::new((miners_to_run, miners_to_stop), Scenario::Production, &phase_production);
+
+    /* Returns miners from all phases as (miner_id, phase, power) */
+    fn get_all_miners(running_miners: Vec<Vec<(String, f64)>>, runnable_miners: Vec<Vec<(String, f64)>>) -> Vec<(String, usize, usize)> {
+        let mut all_miners = vec![];
+
+        for (i, (running_miners, runnable_miners)) in
+            running_miners.into_iter().zip(runnable_miners.into_iter()).enumerate() {
+
+            for (miner, power) in running_miners.into_iter() {
+                all_miners.push((miner, i, power.ceil() as usize));
+            }
+
+            for (miner, power) in runnable_miners.into_iter() {
+                all_miners.push((miner, i, power.ceil() as usize));
+            }
+        }
+
+        return all_miners;
+    }
+
+    /* Surplus power can be used on any phase, production only on phase where it is measured */
+    fn schedule_with_surplus(miners: Vec<(String, usize, usize)>, phase_production: Vec<usize>, surplus_power: usize) -> (Vec<String>, Vec<String>) {
+        let phase_limits = phase_production.iter()
+            .map(|production| production + surplus_power)
+            .collect();
+        let total_limit = surplus_power + phase_production.iter().sum::<usize>();
+
+        optimizer::schedule(miners, &phase_limits, total_limit, optimizer::TIME_LIMIT)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::projection;
+    use json::JsonValue;
+    use proptest::prelude::*;
+    use std::{collections::HashSet, fs, str::FromStr};
+
+    fn parse_ts(value: &JsonValue) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value.as_str().unwrap(), "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn parse_miners(value: &JsonValue) -> Vec<Vec<(String, f64)>> {
+        value.members()
+            .map(|phase| phase.members()
+                .map(|miner| (String::from(miner[0].as_str().unwrap()), miner[1].as_f64().unwrap()))
+                .collect()
+            )
+            .collect()
+    }
+
+    fn parse_ids(value: &JsonValue) -> HashSet<String> {
+        value.members().map(|id| String::from(id.as_str().unwrap())).collect()
+    }
+
+    fn run_fixture(fixture: &JsonValue) -> (HashSet<String>, HashSet<String>) {
+        let now = parse_ts(&fixture["now"]);
+        let period = (parse_ts(&fixture["period"][0]), parse_ts(&fixture["period"][1]));
+
+        let site = &fixture["params"]["site"];
+        let params = SchedulingParams {
+            balancing_mode: BalancingMode::from_str(fixture["params"]["balancing_mode"].as_str().unwrap()).unwrap(),
+            final_stretch_days: fixture["params"]["final_stretch_days"].as_u32().unwrap(),
+            safety_margin_wh: fixture["params"]["safety_margin_wh"].as_f64().unwrap(),
+            site: if site.is_null() {
+                None
+            } else {
+                Some(Site {
+                    latitude: site["latitude"].as_f64().unwrap(),
+                    longitude: site["longitude"].as_f64().unwrap(),
+                })
+            },
+        };
+
+        let floats = |key: &str| fixture[key].members().map(|x| x.as_f64().unwrap()).collect::<Vec<f64>>();
+        let integers = |key: &str| fixture[key].members().map(|x| x.as_u64().unwrap()).collect::<Vec<u64>>();
+
+        let trends = projection::get_energy_trends(
+            now,
+            period,
+            (&floats("total_consumed_wh"), &floats("total_returned_wh")),
+            &integers("total_miners_grid_wmin"),
+            fixture["recovery_ratio"].as_f64().unwrap(),
+        );
+
+        let schedule = schedule_energy_resources(
+            &params,
+            now,
+            (parse_miners(&fixture["running"]), parse_miners(&fixture["runnable"])),
+            &trends,
+            (integers("last_consumed_wmin"), integers("last_returned_wmin")),
+            integers("last_miners_consumed_wmin"),
+            if fixture["last_produced_wmin"].is_null() { None } else { Some(floats("last_produced_wmin")) },
+            Duration::from_secs(fixture["last_elapsed_s"].as_u64().unwrap()),
+        );
+
+        assert_eq!(schedule.scenario.as_str(), fixture["scenario"].as_str().unwrap());
+
+        (schedule.to_run.into_iter().collect(), schedule.to_stop.into_iter().collect())
+    }
+
+    #[test]
+    fn scheduling_fixtures() {
+        let directory = format!("{}/fixtures/scheduler", env!("CARGO_MANIFEST_DIR"));
+        let mut checked = 0;
+
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let fixture = json::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+
+            let (to_run, to_stop) = run_fixture(&fixture);
+
+            assert_eq!(to_run, parse_ids(&fixture["to_run"]), "to_run of {:?}: {}", path, fixture["description"]);
+            assert_eq!(to_stop, parse_ids(&fixture["to_stop"]), "to_stop of {:?}: {}", path, fixture["description"]);
+            checked += 1;
+        }
+
+        assert!(checked > 0);
+    }
+
+    proptest! {
+        #[test]
+        fn every_miner_ends_in_exactly_one_set(
+            powers in prop::collection::vec((0..3usize, 1..2500u32, any::<bool>()), 0..40),
+            consumed_wh in prop::collection::vec(0..2_000_000u32, 3),
+            returned_wh in prop::collection::vec(0..3_000_000u32, 3),
+            last_wmin in prop::collection::vec((0..5000u64, 0..5000u64, 0..5000u64), 3),
+            days in 1..360i64,
+            vector in any::<bool>()
+        ) {
+            let mut running = vec![vec![]; 3];
+            let mut runnable = vec![vec![]; 3];
+            for (i, &(phase, power, is_running)) in powers.iter().enumerate() {
+                let miner = (format!("Miner{:02}", i), power as f64);
+                if is_running {
+                    running[phase].push(miner);
+                } else {
+                    runnable[phase].push(miner);
+                }
+            }
+
+            let period = (
+                NaiveDateTime::parse_from_str("2022-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap(),
+                NaiveDateTime::parse_from_str("2023-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap(),
+            );
+            let now = period.0 + chrono::Duration::days(days);
+            let trends = projection::get_energy_trends(
+                now,
+                period,
+                (
+                    &consumed_wh.iter().map(|&x| x as f64).collect(),
+                    &returned_wh.iter().map(|&x| x as f64).collect(),
+                ),
+                &vec![0, 0, 0],
+                0.8,
+            );
+            let params = SchedulingParams {
+                balancing_mode: if vector { BalancingMode::Vector } else { BalancingMode::Phase },
+                final_stretch_days: 7,
+                safety_margin_wh: 1000.0,
+                site: None,
+            };
+
+            let Schedule { to_run, to_stop, .. } = schedule_energy_resources(
+                &params,
+                now,
+                (running, runnable),
+                &trends,
+                (
+                    last_wmin.iter().map(|x| x.0).collect(),
+                    last_wmin.iter().map(|x| x.1).collect(),
+                ),
+                last_wmin.iter().map(|x| x.2).collect(),
+                None,
+                Duration::from_secs(180),
+            );
+
+            let scheduled: Vec<&String> = to_run.iter().chain(to_stop.iter()).collect();
+            let unique: HashSet<&String> = scheduled.iter().cloned().collect();
+            prop_assert_eq!(scheduled.len(), powers.len());
+            prop_assert_eq!(unique.len(), powers.len());
+        }
+    }
+}
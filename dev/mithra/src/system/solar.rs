@@ -0,0 +1,153 @@
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+use std::f64::consts::PI;
+
+/* Location of photovoltaic installation */
+#[derive(Debug, Clone)]
+pub struct Site {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/* Solar elevation in degrees for UTC time, computed with NOAA general solar position equations */
+pub fn get_solar_elevation(site: &Site, ts: NaiveDateTime) -> f64 {
+    let hour = ts.hour() as f64 + ts.minute() as f64 / 60.0 + ts.second() as f64 / 3600.0;
+    let days_in_year = NaiveDate::from_ymd_opt(ts.year(), 12, 31).map_or(365.0, |date| date.ordinal() as f64);
+
+    /* Fractional year in radians */
+    let gamma = 2.0 * PI / days_in_year * (ts.ordinal0() as f64 + (hour - 12.0) / 24.0);
+
+    /* Equation of time in minutes and solar declination in radians */
+    let eqtime = 229.18 * (
+        0.000075
+        + 0.001868 * gamma.cos()
+        - 0.032077 * gamma.sin()
+        - 0.014615 * (2.0 * gamma).cos()
+        - 0.040849 * (2.0 * gamma).sin()
+    );
+    let declination = 0.006918
+        - 0.399912 * gamma.cos()
+        + 0.070257 * gamma.sin()
+        - 0.006758 * (2.0 * gamma).cos()
+        + 0.000907 * (2.0 * gamma).sin()
+        - 0.002697 * (3.0 * gamma).cos()
+        + 0.00148 * (3.0 * gamma).sin();
+
+    /* True solar time in minutes and hour angle in radians */
+    let solar_time = hour * 60.0 + eqtime + 4.0 * site.longitude;
+    let hour_angle = (solar_time / 4.0 - 180.0).to_radians();
+
+    let latitude = site.latitude.to_radians();
+    let cos_zenith = latitude.sin() * declination.sin()
+        + latitude.cos() * declination.cos() * hour_angle.cos();
+
+    90.0 - cos_zenith.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+pub fn is_night(site: &Site, ts: NaiveDateTime) -> bool {
+    get_solar_elevation(site, ts) <= 0.0
+}
+
+/* First moment after ts when sun rises above horizon, None during polar night */
+pub fn get_next_sunrise(site: &Site, ts: NaiveDateTime) -> Option<NaiveDateTime> {
+    let step = Duration::minutes(5);
+    let mut previous = ts;
+    let mut time = ts + step;
+
+    while time - ts <= Duration::hours(48) {
+        if is_night(site, previous) && !is_night(site, time) {
+            /* Refine to minute precision */
+            let mut minute = previous;
+            while is_night(site, minute) {
+                minute = minute + Duration::minutes(1);
+            }
+            return Some(minute);
+        }
+        previous = time;
+        time = time + step;
+    }
+
+    None
+}
+
+/* Ratio of expected production in next interval to production measured in last interval.
+Clear sky production is approximated as proportional to sine of solar elevation. */
+pub fn get_production_trend(site: &Site, now: NaiveDateTime, interval: Duration) -> f64 {
+    static MAX_TREND: f64 = 2.0;
+
+    let past = get_solar_elevation(site, now - interval / 2).to_radians().sin();
+    let future = get_solar_elevation(site, now + interval / 2).to_radians().sin();
+
+    if future <= 0.0 {
+        0.0
+    } else if past <= 0.0 {
+        /* Morning, nothing to compare with */
+        1.0
+    } else {
+        (future / past).min(MAX_TREND)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Royal Observatory Greenwich and Tromsø above polar circle */
+    const GREENWICH: Site = Site { latitude: 51.4779, longitude: 0.0 };
+    const TROMSO: Site = Site { latitude: 69.65, longitude: 18.96 };
+
+    fn get_ts(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap().and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn elevation_peaks_at_solar_noon() {
+        /* At summer solstice sun culminates at 12:02 UTC, 90 - 51.48 + 23.44 degrees high */
+        let noon = get_solar_elevation(&GREENWICH, get_ts(2022, 6, 21, 12, 2));
+        assert!((noon - 61.96).abs() < 0.1, "noon elevation {}", noon);
+        assert!(get_solar_elevation(&GREENWICH, get_ts(2022, 6, 21, 11, 30)) < noon);
+        assert!(get_solar_elevation(&GREENWICH, get_ts(2022, 6, 21, 12, 30)) < noon);
+
+        /* At winter solstice 90 - 51.48 - 23.44 degrees */
+        let noon = get_solar_elevation(&GREENWICH, get_ts(2022, 12, 21, 11, 58));
+        assert!((noon - 15.08).abs() < 0.1, "noon elevation {}", noon);
+    }
+
+    #[test]
+    fn night_follows_horizon() {
+        assert!(is_night(&GREENWICH, get_ts(2022, 6, 21, 0, 0)));
+        assert!(!is_night(&GREENWICH, get_ts(2022, 6, 21, 12, 0)));
+
+        /* Polar night and midnight sun */
+        assert!(is_night(&TROMSO, get_ts(2022, 12, 21, 10, 45)));
+        assert!(!is_night(&TROMSO, get_ts(2022, 6, 21, 23, 0)));
+    }
+
+    #[test]
+    fn sunrise_is_found_within_48_hours() {
+        /* Sun centre crosses horizon at 03:50 UTC, published sunrise 03:43 UTC counts with refraction */
+        let sunrise = get_next_sunrise(&GREENWICH, get_ts(2022, 6, 21, 0, 0)).unwrap();
+        assert!((sunrise - get_ts(2022, 6, 21, 3, 50)).num_minutes().abs() <= 2, "sunrise {}", sunrise);
+
+        /* Evening looks for sunrise of the next day */
+        let sunrise = get_next_sunrise(&GREENWICH, get_ts(2022, 6, 21, 21, 0)).unwrap();
+        assert_eq!(sunrise.date(), NaiveDate::from_ymd_opt(2022, 6, 22).unwrap());
+
+        /* Polar night has no sunrise in next 48 hours */
+        assert_eq!(get_next_sunrise(&TROMSO, get_ts(2022, 12, 20, 0, 0)), None);
+    }
+
+    #[test]
+    fn production_trend_follows_sun() {
+        let interval = Duration::minutes(10);
+
+        /* Sun has just risen, there is nothing to compare with */
+        assert_eq!(get_production_trend(&GREENWICH, get_ts(2022, 6, 21, 3, 50), interval), 1.0);
+        /* Morning production grows, limited by maximal trend */
+        let morning = get_production_trend(&GREENWICH, get_ts(2022, 6, 21, 4, 10), interval);
+        assert!(morning > 1.5 && morning <= 2.0, "morning trend {}", morning);
+        assert!((get_production_trend(&GREENWICH, get_ts(2022, 6, 21, 12, 2), interval) - 1.0).abs() < 0.001);
+        assert!(get_production_trend(&GREENWICH, get_ts(2022, 6, 21, 15, 0), interval) < 1.0);
+        /* After sunset nothing is produced */
+        assert_eq!(get_production_trend(&GREENWICH, get_ts(2022, 6, 21, 20, 30), interval), 0.0);
+    }
+}
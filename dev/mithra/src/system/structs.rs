@@ -1,66 +1,253 @@
 use chrono::NaiveDateTime;
 use std::{
+    collections::HashMap,
     str::FromStr,
+    sync::Arc,
     time::Instant,
 };
 
+use super::adapters::PlugDriver;
+use super::discovery::AnnouncedDevice;
+
 /* There is status enum for switchboard, guards and plugs */
 #[derive(Debug, PartialEq)]
 pub enum DeviceState {
     Available,
     ConfigExpired,
     Inaccessible,
+    /* Guard acknowledged other protocol version than the supported one */
+    Incompatible,
     StartingUp,
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ShellyType {
     /* List can be extended in future */
+    SHEM,
     SHEM_3,
     SHPLG_S,
+    /* Gen2 devices, Gen3 Plug S has the same RPC interface as Plus Plug S */
+    PRO_3EM,
+    PLUS_PLUG_S,
+}
+
+impl ShellyType {
+    /* Number of phases measured by energy meter, plugs do not measure any phase */
+    pub fn get_phase_count(&self) -> usize {
+        match self {
+            ShellyType::SHEM => 1,
+            ShellyType::SHEM_3 => 3,
+            ShellyType::SHPLG_S => 0,
+            ShellyType::PRO_3EM => 3,
+            ShellyType::PLUS_PLUG_S => 0,
+        }
+    }
+
+    /* Gen2 and newer devices are controlled by JSON-RPC instead of Gen1 topics */
+    pub fn uses_rpc(&self) -> bool {
+        match self {
+            ShellyType::SHEM | ShellyType::SHEM_3 | ShellyType::SHPLG_S => false,
+            ShellyType::PRO_3EM | ShellyType::PLUS_PLUG_S => true,
+        }
+    }
 }
 
 impl FromStr for ShellyType {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        /* Gen1 model or Gen2 application name */
         match s {
+            "SHEM" => Ok(Self::SHEM),
             "SHEM-3" => Ok(Self::SHEM_3),
             "SHPLG-S" => Ok(Self::SHPLG_S),
+            "Pro3EM" => Ok(Self::PRO_3EM),
+            "PlusPlugS" | "PlugSG3" => Ok(Self::PLUS_PLUG_S),
             _ => Err(String::from("Unimplemented shelly device")) 
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub enum GuardType {
-    /* List can be extended in future */
-    ESP32
+/* Register map of Modbus meter, SunSpec is any meter model 201 - 204 found by walking SunSpec models */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModbusModel {
+    SDM630,
+    SunSpec,
 }
 
-impl GuardType {
-    pub fn get_pinset_limit(&self) -> u32 {
+impl FromStr for ModbusModel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "SDM630" => Ok(Self::SDM630),
+            "SunSpec" => Ok(Self::SunSpec),
+            _ => Err(String::from("Unimplemented Modbus device"))
+        }
+    }
+}
+
+/* Modbus TCP meter polled every interval seconds */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModbusConfig {
+    pub model: ModbusModel,
+    pub address: String,
+    pub unit: u8,
+    pub phases: usize,
+    pub interval: u64,
+}
+
+/* DSMR meter P1 port read from serial device path or from TCP bridge address */
+#[derive(Clone, Debug, PartialEq)]
+pub struct P1Config {
+    pub source: String,
+    pub phases: usize,
+    pub interval: u64,
+}
+
+/* SMA Energy Meter or Home Manager multicasting its readings, serial picks one of them */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmaConfig {
+    pub address: String,
+    pub serial: Option<u32>,
+    pub phases: usize,
+    pub interval: u64,
+}
+
+/* Switchboard meter, mapped meter is described in devices config and has given number of phases.
+Utility meter with P1 port counts energy of all phases together, it is split over phases by their power. */
+#[derive(Clone, Debug, PartialEq)]
+pub enum MeterType {
+    Shelly(ShellyType),
+    Mapped(String, usize),
+    Modbus(ModbusConfig),
+    P1(P1Config),
+    Sma(SmaConfig),
+}
+
+impl MeterType {
+    pub fn get_phase_count(&self) -> usize {
         match self {
-            GuardType::ESP32 => 4
+            MeterType::Shelly(shelly_type) => shelly_type.get_phase_count(),
+            MeterType::Mapped(_, phases) => *phases,
+            MeterType::Modbus(config) => config.phases,
+            MeterType::P1(config) => config.phases,
+            MeterType::Sma(config) => config.phases,
+        }
+    }
+}
+
+/* Plug backend, Shelly plugs are identified by model, mapped plug by name of its mapping */
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlugType {
+    Shelly(ShellyType),
+    Tasmota,
+    Mapped(String),
+}
+
+impl FromStr for PlugType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Tasmota" => Ok(Self::Tasmota),
+            _ => match ShellyType::from_str(s) {
+                Ok(shelly_type) if shelly_type.get_phase_count() == 0 => Ok(Self::Shelly(shelly_type)),
+                _ => Err(String::from("Unimplemented plug type")),
+            }
+        }
+    }
+}
+
+/* How energy provider balances phases of the meter */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BalancingMode {
+    /* Each phase is billed separately */
+    Phase,
+    /* Energy is summed over all phases, export on one phase cancels import on another */
+    Vector,
+}
+
+impl FromStr for BalancingMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "phase" => Ok(Self::Phase),
+            "vector" => Ok(Self::Vector),
+            _ => Err(String::from("Unimplemented balancing mode")) 
         }
     }
 }
 
-impl FromStr for GuardType {
+/* Commands of guard specification, guard must support at least the required ones */
+pub const GUARD_COMMANDS: [&str; 6] = ["PowerOn", "PowerOff", "HardStop", "Reset", "HardReset", "StateReport"];
+pub const REQUIRED_GUARD_COMMANDS: [&str; 3] = ["PowerOn", "PowerOff", "StateReport"];
+
+/* Guard board described in devices config, ESP32 board is built in */
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuardType {
+    pub name: String,
+    pub pinsets: u32,
+    pub commands: Vec<String>,
+}
+
+impl GuardType {
+    pub fn esp32() -> Self {
+        GuardType {
+            name: String::from("ESP32"),
+            pinsets: 4,
+            commands: GUARD_COMMANDS.iter().map(|&command| String::from(command)).collect(),
+        }
+    }
+
+    pub fn get_pinset_limit(&self) -> u32 {
+        self.pinsets
+    }
+
+    pub fn supports(&self, command: &str) -> bool {
+        self.commands.iter().any(|supported| supported == command)
+    }
+}
+
+/* Firmware management API able to pause mining without powering miner off */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApiControl {
+    BraiinsOS,
+    LuxOS,
+    Cgminer,
+}
+
+impl FromStr for ApiControl {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "ESP32" => Ok(Self::ESP32),
-            _ => Err(String::from("Unimplemented guard type")) 
+            "BraiinsOS" => Ok(Self::BraiinsOS),
+            "LuxOS" => Ok(Self::LuxOS),
+            "cgminer" => Ok(Self::Cgminer),
+            _ => Err(String::from("Unimplemented miner API control"))
         }
     }
 }
 
+/* Miner is paused and resumed through its API, power target in W is set on resume */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoftPower {
+    pub control: ApiControl,
+    pub power_target: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MinerApiCommand {
+    Pause,
+    Resume,
+}
+
 #[derive(Debug)]
 pub struct Plug {
     pub id: String,
+    pub plug_type: PlugType,
+    pub driver: Arc<dyn PlugDriver>,
     pub state: DeviceState,
-    pub miner_id: String,
+    /* Metered PDU outlet may feed several miners on the same phase */
+    pub miners: Vec<String>,
     pub is_enabled: bool,
     pub last_seen: NaiveDateTime,
 }
@@ -68,8 +255,10 @@ pub struct Plug {
 #[derive(Debug)]
 pub struct Miner {
     pub id: String,
-    pub plug_id: String,
-    pub guard: String,
+    /* Miner without plug is not metered and cannot be cut off */
+    pub plug_id: Option<String>,
+    /* Plug-only miner has no guard, it is driven by its plug */
+    pub guard: Option<String>,
     pub pinset: u32,
     pub phase: u8,
     pub estimated_consumption: f32,
@@ -78,6 +267,14 @@ pub struct Miner {
     pub target_state: Option<MinerState>,
     pub command_ts: Option<NaiveDateTime>,
     pub included: bool,
+    /* Address of cgminer API, telemetry is polled only from miners having it */
+    pub api: Option<String>,
+    /* Miner reported as running does not hash */
+    pub is_stalled: bool,
+    /* Soft power management needs API address */
+    pub soft_power: Option<SoftPower>,
+    /* Paused miner is powered but it is PoweredOff for mithra */
+    pub is_paused: bool,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -148,21 +345,51 @@ pub struct Guard {
 #[derive(Debug)]
 pub struct Switchboard {
     pub id: String,
+    pub board_type: MeterType,
     pub state: DeviceState,
     pub last_seen: NaiveDateTime,
 }
 
+/* Fronius inverter measuring production, its power is spread evenly over phases it feeds */
+#[derive(Clone, Debug)]
+pub struct Inverter {
+    pub id: String,
+    pub address: String,
+    pub phases: Vec<u8>,
+    pub interval: u64,
+}
+
 /* Miner data for miners MQTT messages receiver loop */
 
+/* Readings of plug, miners are listed with their estimated consumption */
 #[derive(Debug)]
-pub struct MinerData {
+pub struct PlugData {
+    pub driver: Arc<dyn PlugDriver>,
     pub last_received: Option<Instant>,
-    pub name: String,
+    pub miners: Vec<(String, f32)>,
     pub energy_consumed: u64,
     pub phase: u8,
     pub power: f32,
 }
 
+/* Miner telemetry from cgminer API, chip temperature of the hottest chip and speed of the slowest fan */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MinerTelemetry {
+    pub hashrate_ghs: f64,
+    pub temperature: Option<f32>,
+    pub fan_rpm: Option<u32>,
+    pub accepted: u64,
+}
+
+/* Plug readings common for all backends, None when message does not carry the value */
+#[derive(Debug, Default, PartialEq)]
+pub struct PlugStatus {
+    pub output: Option<bool>,
+    pub power: Option<f32>,
+    /* Energy counter in Wmin like Gen1 Shelly plug reports */
+    pub energy_wmin: Option<u64>,
+}
+
 #[derive(Debug)]
 pub enum MinerAlert {
     PoweredOn,
@@ -184,9 +411,27 @@ impl FromStr for MinerAlert {
 
 #[derive(Debug, Clone)]
 pub enum EnergyData {
-    Switchboard {ts: NaiveDateTime, ec: [u64; 3], er: [u64; 3], tc: [f64; 3], tr: [f64; 3]},
+    Switchboard {ts: NaiveDateTime, ec: Vec<u64>, er: Vec<u64>, tc: Vec<f64>, tr: Vec<f64>},
     Miner {ts: NaiveDateTime, name: String, ec: u64, phase: u8, power: f32},
-    MinersGrid {ts: NaiveDateTime, ec: u64, phase: u8}
+    MinersGrid {ts: NaiveDateTime, ec: u64, phase: u8},
+    Production {ts: NaiveDateTime, ep: u64, power: f32},
+    Telemetry {ts: NaiveDateTime, name: String, telemetry: MinerTelemetry, is_stalled: bool},
+    Projection {ts: NaiveDateTime, balance_wh: f64, projected_balance_wh: f64},
+    PeriodSummary {ts: NaiveDateTime, period_start: NaiveDateTime, period_end: NaiveDateTime, balance_wh: f64},
+    Accuracy {month: u32, hour: u32, phase: u8, stats: AccuracyStats},
+}
+
+/* Rolling statistics of production assumed by scheduler compared with measured one */
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccuracyStats {
+    pub samples: u64,
+    /* Assumed minus measured production, positive when production was overestimated */
+    pub mean_error_W: f64,
+    pub mean_abs_error_W: f64,
+    pub mean_grid_import_W: f64,
+    /* Export large enough to run some miner which was not running */
+    pub mean_wasted_export_W: f64,
 }
 
 /* Data for main thread channel */
@@ -194,7 +439,8 @@ pub enum EnergyData {
 pub enum GuardData {
     Alert {miner_id: String, alert: MinerAlert},
     Command {miner_id: String, command_status: CommandStatus, miner_state: MinerState},
-    Configured,
+    /* Protocol version acknowledged by guard */
+    Configured {version: Option<u32>},
     Ping,
     Started,
     State {miner_id: String, state: MinerState},
@@ -218,10 +464,25 @@ impl FromStr for UserCommands {
     }
 }
 
+/* Hypothetical scheduling inputs, powers are in Watts per phase */
+#[derive(Debug)]
+pub struct WhatIfRequest {
+    pub id: Option<String>,
+    pub production_w: Option<Vec<f64>>,
+    pub extra_consumption_w: Option<Vec<f64>>,
+    /* Miner availability override */
+    pub miners: HashMap<String, bool>,
+}
+
 #[derive(Debug)]
 pub enum Message {
     Energy(EnergyData),
     Guard {guard_id: String, ts: NaiveDateTime, data: GuardData},
-    Plug {plug_id: String, ts: NaiveDateTime, is_on: bool},
+    /* Any plug reading keeps plug alive, relay state is known only from some of them */
+    Plug {plug_id: String, ts: NaiveDateTime, is_on: Option<bool>},
+    Announce {ts: NaiveDateTime, device: AnnouncedDevice},
+    Telemetry {miner_id: String, ts: NaiveDateTime, telemetry: MinerTelemetry},
+    MinerApi {miner_id: String, command: MinerApiCommand, result: Result<(), String>},
     User {miner_id: String, command: UserCommands},
+    WhatIf(Result<WhatIfRequest, String>),
 }
\ No newline at end of file
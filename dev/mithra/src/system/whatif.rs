@@ -0,0 +1,252 @@
+use json::JsonValue;
+use std::{collections::HashMap, time::Duration};
+
+use super::{
+    projection::EnergyTrends,
+    scheduler::Schedule,
+    structs::WhatIfRequest,
+};
+
+pub static REQUEST_TOPIC: &str = "mithra/whatif/request";
+pub static RESPONSE_TOPIC: &str = "mithra/whatif/response";
+
+/* Parses request like:
+{"id": "1", "production": [1200, 0, 300], "extra_consumption": [0, 500, 0], "miners": {"Miner00": false}}
+All fields are optional. */
+pub fn parse_request(payload: &str) -> Result<WhatIfRequest, String> {
+    let request = match json::parse(payload) {
+        Ok(request) => request,
+        Err(error_msg) => return Err(format!("Request is not valid json: {}", error_msg)),
+    };
+
+    fn parse_powers(value: &JsonValue, name: &str) -> Result<Option<Vec<f64>>, String> {
+        if value.is_null() {
+            return Ok(None);
+        }
+        if !value.is_array() {
+            return Err(format!("Field '{}' must be an array of powers", name));
+        }
+
+        let mut powers = vec![];
+        for power in value.members() {
+            match power.as_f64() {
+                Some(power) if power >= 0.0 => powers.push(power),
+                _ => return Err(format!("Field '{}' contains improper power", name)),
+            }
+        }
+        Ok(Some(powers))
+    }
+
+    let production_w = parse_powers(&request["production"], "production")?;
+    let extra_consumption_w = parse_powers(&request["extra_consumption"], "extra_consumption")?;
+
+    let mut miners = HashMap::new();
+    if !request["miners"].is_null() {
+        if !request["miners"].is_object() {
+            return Err(String::from("Field 'miners' must be an object of miner availability"));
+        }
+        for (miner_id, available) in request["miners"].entries() {
+            match available.as_bool() {
+                Some(available) => { miners.insert(String::from(miner_id), available); },
+                None => return Err(format!("Availability of miner '{}' must be boolean", miner_id)),
+            }
+        }
+    }
+
+    Ok(WhatIfRequest {
+        id: request["id"].as_str().map(String::from),
+        production_w,
+        extra_consumption_w,
+        miners,
+    })
+}
+
+/* Household load in W of last interval is energy taken from grid and production which was neither consumed
+by miners nor returned. Without measured production it is only lower bound, production of interval is unknown. */
+fn get_household_load(
+    phases: usize,
+    last_interval: &Option<(Vec<u64>, Vec<u64>, Vec<u64>, Duration)>,
+    last_production: &Option<Vec<f64>>
+) -> Vec<f64> {
+    match last_interval {
+        Some((consumed, returned, miners_consumed, elapsed)) if elapsed.as_secs_f64() > 0.0 => {
+            let minutes = elapsed.as_secs_f64() / 60.0;
+            (0..phases)
+                .map(|i| {
+                    let produced = last_production.as_ref().map_or(0.0, |production| production[i]);
+                    let load = produced + consumed[i] as f64 - miners_consumed[i] as f64 - returned[i] as f64;
+                    (load / minutes).max(0.0)
+                })
+                .collect()
+        },
+        _ => vec![0.0; phases],
+    }
+}
+
+/* Builds last interval energies from hypothetical inputs, returns (consumed, returned, miners consumed, elapsed).
+Production override keeps household load and miners draw of last real interval,
+without it last real interval is used with extra consumption added. */
+pub fn get_interval(
+    request: &WhatIfRequest,
+    phases: usize,
+    last_interval: &Option<(Vec<u64>, Vec<u64>, Vec<u64>, Duration)>,
+    last_production: &Option<Vec<f64>>
+) -> Result<(Vec<u64>, Vec<u64>, Vec<u64>, Duration), String> {
+    let extra = request.extra_consumption_w.clone().unwrap_or_else(|| vec![0.0; phases]);
+    if extra.len() != phases {
+        return Err(format!("Field 'extra_consumption' must have {} phases", phases));
+    }
+
+    if let Some(production) = &request.production_w {
+        if production.len() != phases {
+            return Err(format!("Field 'production' must have {} phases", phases));
+        }
+
+        /* One minute interval, so Wmin are equal to W */
+        let household = get_household_load(phases, last_interval, last_production);
+        let miners: Vec<u64> = match last_interval {
+            Some((_, _, miners_consumed, elapsed)) if elapsed.as_secs_f64() > 0.0 => miners_consumed.iter()
+                .map(|&miners| (miners as f64 * 60.0 / elapsed.as_secs_f64()).round() as u64)
+                .collect(),
+            _ => vec![0; phases],
+        };
+
+        let balance: Vec<f64> = (0..phases)
+            .map(|i| production[i] - extra[i] - household[i] - miners[i] as f64)
+            .collect();
+        let returned = balance.iter().map(|balance| balance.max(0.0).round() as u64).collect();
+        let consumed = balance.iter().map(|balance| (-balance).max(0.0).round() as u64).collect();
+
+        return Ok((consumed, returned, miners, Duration::from_secs(60)));
+    }
+
+    if let Some((consumed, returned, miners_consumed, elapsed)) = last_interval {
+        let minutes = elapsed.as_secs_f64() / 60.0;
+        let consumed = consumed.iter().zip(extra.iter())
+            .map(|(&consumed, extra)| consumed + (extra * minutes).round() as u64)
+            .collect();
+
+        return Ok((consumed, returned.clone(), miners_consumed.clone(), *elapsed));
+    }
+
+    Err(String::from("There was no scheduling yet, production must be given"))
+}
+
+/* Extra consumption is assumed to last until end of billing period */
+pub fn apply_trends(request: &WhatIfRequest, mut trends: EnergyTrends) -> EnergyTrends {
+    if let Some(extra) = &request.extra_consumption_w {
+        trends.avg_consumption_W += extra.iter().sum::<f64>();
+    }
+    trends
+}
+
+pub fn get_response(id: &Option<String>, schedule: &Schedule) -> String {
+    let mut response = JsonValue::new_object();
+    response["id"] = id.clone().into();
+    response["scenario"] = schedule.scenario.as_str().into();
+    response["to_run"] = schedule.to_run.clone().into();
+    response["to_stop"] = schedule.to_stop.clone().into();
+    response.dump()
+}
+
+pub fn get_error_response(id: &Option<String>, error_msg: String) -> String {
+    let mut response = JsonValue::new_object();
+    response["id"] = id.clone().into();
+    response["error"] = error_msg.into();
+    response.dump()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::{projection, scheduler::{self, SchedulingParams}, structs::BalancingMode};
+    use chrono::NaiveDateTime;
+
+    #[test]
+    fn parses_full_request() {
+        let request = parse_request(
+            r#"{"id": "7", "production": [1200, 0, 300], "extra_consumption": [0, 500, 0], "miners": {"Miner00": false}}"#
+        ).unwrap();
+
+        assert_eq!(request.id, Some(String::from("7")));
+        assert_eq!(request.production_w, Some(vec![1200.0, 0.0, 300.0]));
+        assert_eq!(request.extra_consumption_w, Some(vec![0.0, 500.0, 0.0]));
+        assert_eq!(request.miners.get("Miner00"), Some(&false));
+    }
+
+    #[test]
+    fn rejects_improper_fields() {
+        assert!(parse_request("not json").is_err());
+        assert!(parse_request(r#"{"production": [-1, 0, 0]}"#).is_err());
+        assert!(parse_request(r#"{"miners": {"Miner00": "yes"}}"#).is_err());
+    }
+
+    #[test]
+    fn production_override_builds_one_minute_interval() {
+        let request = parse_request(r#"{"production": [1200, 0, 300], "extra_consumption": [200, 500, 0]}"#).unwrap();
+
+        let (consumed, returned, miners, elapsed) = get_interval(&request, 3, &None, &None).unwrap();
+
+        assert_eq!(consumed, vec![0, 500, 0]);
+        assert_eq!(returned, vec![1000, 0, 300]);
+        assert_eq!(miners, vec![0, 0, 0]);
+        assert_eq!(elapsed, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn extra_consumption_is_added_to_last_interval() {
+        let request = parse_request(r#"{"extra_consumption": [0, 100, 0]}"#).unwrap();
+        let last_interval = Some((vec![10, 20, 30], vec![40, 50, 60], vec![1, 2, 3], Duration::from_secs(180)));
+
+        let (consumed, returned, miners, _) = get_interval(&request, 3, &last_interval, &None).unwrap();
+
+        assert_eq!(consumed, vec![10, 320, 30]);
+        assert_eq!(returned, vec![40, 50, 60]);
+        assert_eq!(miners, vec![1, 2, 3]);
+        assert!(get_interval(&request, 3, &None, &None).is_err());
+    }
+
+    #[test]
+    fn household_load_reduces_overridden_production() {
+        let request = parse_request(r#"{"production": [1200, 0, 0]}"#).unwrap();
+        /* Household took 300 W and miner 200 W of 1000 W produced on first phase in last two minutes */
+        let last_interval = Some((vec![0, 0, 0], vec![1000, 0, 0], vec![400, 0, 0], Duration::from_secs(120)));
+        let last_production = Some(vec![2000.0, 0.0, 0.0]);
+
+        let (consumed, returned, miners, elapsed) = get_interval(&request, 3, &last_interval, &last_production).unwrap();
+        assert_eq!((consumed, returned.clone(), miners.clone()), (vec![0, 0, 0], vec![700, 0, 0], vec![200, 0, 0]));
+
+        let now = NaiveDateTime::parse_from_str("2022-06-21 11:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let period = (
+            NaiveDateTime::parse_from_str("2022-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap(),
+            NaiveDateTime::parse_from_str("2023-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap(),
+        );
+        let trends = projection::get_energy_trends(
+            now,
+            period,
+            (&vec![1000000.0; 3], &vec![1400000.0; 3]),
+            &vec![0, 0, 0],
+            0.8,
+        );
+        let params = SchedulingParams {
+            balancing_mode: BalancingMode::Phase,
+            final_stretch_days: 0,
+            safety_margin_wh: 0.0,
+            site: None,
+        };
+        let schedule = |(consumed, returned, miners): (Vec<u64>, Vec<u64>, Vec<u64>)| scheduler::schedule_energy_resources(
+            &params,
+            now,
+            (vec![vec![]; 3], vec![vec![(String::from("Miner00"), 800.0)], vec![], vec![]]),
+            &trends,
+            (consumed, returned),
+            miners,
+            None,
+            elapsed,
+        ).to_run;
+
+        /* 1200 W would run miner, 900 W left by household does not */
+        assert_eq!(schedule((vec![0, 0, 0], vec![1000, 0, 0], vec![200, 0, 0])), vec![String::from("Miner00")]);
+        assert!(schedule((vec![0, 0, 0], returned, miners)).is_empty());
+    }
+}